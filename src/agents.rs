@@ -0,0 +1,519 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rand::random;
+
+use crate::input::read_manifest_file;
+use crate::input_parsers::{build_simulation, ParsedManifest};
+use crate::rate_inference::simulate_history;
+use crate::reactions::{ReactionDescription, ReactionEvent};
+use crate::state::{Settings, SimulatorComponents};
+use crate::trace::parse_trace;
+
+/// Where one rule moves agents, written in a manifest as
+/// `!AGENT_RULE A + B -> B + A: 1->2, 2->1`. Each (reactant, product) pair,
+/// numbered from 0 here, carries the agent in that reactant's cell to that
+/// product's cell.
+#[derive(Debug, Clone)]
+pub struct AgentRule {
+    pub rule: ReactionDescription, // Its rate doesn't matter.
+    pub carries: Vec<(usize, usize)>,
+}
+
+impl AgentRule {
+    /// Checks a rule's carries, numbered from 1 the way a manifest writes
+    /// them, and renumbers them from 0.
+    pub fn new(rule: ReactionDescription, carries: Vec<(usize, usize)>) -> Result<AgentRule, &'static str> {
+        let n_slots = if rule.r2.is_some() { 2 } else { 1 };
+        if rule.r2.is_some() != rule.p2.is_some() {
+            return Err("an agent rule with as many products as reactants");
+        }
+        if carries.iter().any(|(from, to)| !(1..=n_slots).contains(from) || !(1..=n_slots).contains(to)) {
+            return Err("agent moves between the rule's own reactants and products");
+        }
+        let carries: Vec<(usize, usize)> = carries.into_iter().map(|(from, to)| (from - 1, to - 1)).collect();
+        if (0..n_slots).any(|slot| carries.iter().filter(|(from, _)| *from == slot).count() > 1
+            || carries.iter().filter(|(_, to)| *to == slot).count() > 1) {
+            return Err("at most one agent move from each reactant and to each product");
+        }
+        Ok(AgentRule { rule, carries })
+    }
+}
+
+/// One place an agent was, from the event that put it there on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub n_events: usize,       // How many events of the history had happened.
+    pub t: f32,
+    pub loc: usize,
+    pub unwrapped: (i64, i64), // Row and column, counting trips across a wrapped edge.
+}
+
+/// One tracked agent: where it went, and when it stopped being an agent, if
+/// it did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Agent {
+    pub path: Vec<Step>, // Starts where the agent first appeared.
+    pub retired: Option<(usize, f32)>, // (n_events, t)
+}
+
+impl Agent {
+    /// Where the agent was once `n_events` events had happened, or None if
+    /// it wasn't around then.
+    pub fn step_at(&self, n_events: usize) -> Option<&Step> {
+        if self.retired.is_some_and(|(retired, _)| retired <= n_events) {
+            return None;
+        }
+        let n_steps = self.path.partition_point(|step| step.n_events <= n_events);
+        n_steps.checked_sub(1).map(|idx| &self.path[idx])
+    }
+
+    /// Where the agent was at time `t`, or None if it wasn't around then.
+    pub fn step_at_time(&self, t: f64) -> Option<&Step> {
+        if self.retired.is_some_and(|(_, retired)| retired as f64 <= t) {
+            return None;
+        }
+        let n_steps = self.path.partition_point(|step| step.t as f64 <= t);
+        n_steps.checked_sub(1).map(|idx| &self.path[idx])
+    }
+
+    /// The last `length` places the agent went, up to `n_events` events in.
+    pub fn trail(&self, n_events: usize, length: usize) -> &[Step] {
+        if self.step_at(n_events).is_none() {
+            return &[];
+        }
+        let end = self.path.partition_point(|step| step.n_events <= n_events);
+        &self.path[end.saturating_sub(length)..end]
+    }
+}
+
+/// Follows agents, the cells in chosen states, through a reaction history,
+/// giving each one a persistent identity. A rule carries agents the way its
+/// `!AGENT_RULE` says or, without one, the way its states suggest: an agent
+/// stays put if its cell's product is an agent state too, and otherwise moves
+/// to the other cell if that cell's product is an agent state and it wasn't
+/// holding an agent already (like `Ant + O -> O + Ant`). An agent that isn't
+/// carried anywhere retires, and a product in an agent state that no agent
+/// was carried to is a new agent.
+pub struct AgentTracker {
+    carries: Vec<Vec<(usize, usize)>>, // By rule.
+    is_agent: Vec<bool>,               // By state id.
+    at_cell: Vec<Option<usize>>,       // The agent in each cell of the latest board.
+    pub agents: Vec<Agent>,
+    n_events: usize,                   // How much of the history has been followed.
+    n_rows: usize,
+    n_cols: usize,
+    wrap: bool,
+}
+
+impl AgentTracker {
+    /// Sets up tracking for a manifest (with its scenario already applied),
+    /// starting from the board on screen, or returns None if it names no
+    /// agent states.
+    pub fn new(manifest: &ParsedManifest, components: &SimulatorComponents, settings: &Settings) -> Result<Option<AgentTracker>, String> {
+        if manifest.agent_states.is_empty() {
+            return Ok(None);
+        }
+        let n_states = components.state_names.keys().max().map_or(0, |id| id + 1);
+        let mut is_agent = vec![false; n_states];
+        for name in manifest.agent_states.iter() {
+            let mut found = false;
+            for (state, state_name) in components.state_names.iter() {
+                if state_name == name {
+                    is_agent[*state] = true;
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(format!("there's no state named {name:?} to track as an agent"));
+            }
+        }
+
+        let mut carries: Vec<Vec<(usize, usize)>> = components.all_reactions
+            .iter()
+            .map(|rxn| {
+                let reactants = [Some(rxn.r1_num), rxn.r2_num];
+                let products = [Some(rxn.p1_num), rxn.p2_num];
+                let agent = |state: Option<usize>| state.is_some_and(|state| is_agent[state]);
+                (0..2)
+                    .filter(|slot| agent(reactants[*slot]))
+                    .filter_map(|slot| {
+                        let other = 1 - slot;
+                        if agent(products[slot]) {
+                            Some((slot, slot))
+                        } else if agent(products[other]) && !agent(reactants[other]) {
+                            Some((slot, other))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        for agent_rule in manifest.agent_rules.iter() {
+            let mut matched = false;
+            for (rule_idx, rule) in manifest.transition_rules.iter().enumerate() {
+                if rule.same_reaction(&agent_rule.rule) {
+                    carries[rule_idx] = agent_rule.carries.clone();
+                    matched = true;
+                }
+            }
+            if !matched {
+                let rule = &agent_rule.rule;
+                return Err(match (&rule.r2, &rule.p2) {
+                    (Some(r2), Some(p2)) => format!("there's no rule {} + {r2} -> {} + {p2} to move agents with", rule.r1, rule.p1),
+                    _ => format!("there's no rule {} -> {} to move agents with", rule.r1, rule.p1),
+                });
+            }
+        }
+
+        let mut tracker = AgentTracker {
+            carries,
+            is_agent,
+            at_cell: vec![None; components.current_states.len()],
+            agents: Vec::new(),
+            n_events: 0,
+            n_rows: settings.n_rows,
+            n_cols: settings.n_cols,
+            wrap: settings.wrap,
+        };
+        for (loc, state) in components.current_states.iter().enumerate() {
+            if tracker.is_agent[*state] {
+                tracker.add_agent(loc, 0.0);
+            }
+        }
+        Ok(Some(tracker))
+    }
+
+    fn add_agent(&mut self, loc: usize, t: f32) {
+        let unwrapped = ((loc / self.n_cols) as i64, (loc % self.n_cols) as i64);
+        self.at_cell[loc] = Some(self.agents.len());
+        self.agents.push(Agent { path: vec![Step { n_events: self.n_events, t, loc, unwrapped }], retired: None });
+    }
+
+    /// Moves agents for the next event of the history.
+    pub fn apply(&mut self, event: &ReactionEvent, components: &SimulatorComponents) {
+        let rxn = &components.all_reactions[event.rxn_idx];
+        let locs = [Some(event.r1_loc), event.r2_loc];
+        let products = [Some(rxn.p1_num), rxn.p2_num];
+        let agents = locs.map(|loc| loc.and_then(|loc| self.at_cell[loc].take()));
+        self.n_events += 1;
+
+        let mut filled = [false; 2];
+        let mut carried = [false; 2];
+        for (from, to) in self.carries[event.rxn_idx].clone() {
+            let (Some(agent), Some(loc)) = (agents[from], locs[to]) else {
+                continue;
+            };
+            let last = *self.agents[agent].path.last().unwrap();
+            if loc != last.loc {
+                let (row, col) = ((loc / self.n_cols) as i64, (loc % self.n_cols) as i64);
+                let (last_row, last_col) = ((last.loc / self.n_cols) as i64, (last.loc % self.n_cols) as i64);
+                // A move to a neighbor across a wrapped edge is one step, not a jump across the board.
+                let shortest = |delta: i64, size: usize| match self.wrap {
+                    true => (delta + size as i64 / 2).rem_euclid(size as i64) - size as i64 / 2,
+                    false => delta,
+                };
+                let unwrapped = (
+                    last.unwrapped.0 + shortest(row - last_row, self.n_rows),
+                    last.unwrapped.1 + shortest(col - last_col, self.n_cols),
+                );
+                self.agents[agent].path.push(Step { n_events: self.n_events, t: event.t, loc, unwrapped });
+            }
+            self.at_cell[loc] = Some(agent);
+            filled[to] = true;
+            carried[from] = true;
+        }
+        for slot in 0..2 {
+            if let (Some(agent), false) = (agents[slot], carried[slot]) {
+                self.agents[agent].retired = Some((self.n_events, event.t));
+            }
+        }
+        for slot in 0..2 {
+            if let (Some(loc), Some(state), false) = (locs[slot], products[slot], filled[slot]) {
+                if self.is_agent[state] {
+                    self.add_agent(loc, event.t);
+                }
+            }
+        }
+    }
+
+    /// Follows whatever the history has gained since the last call.
+    pub fn catch_up(&mut self, components: &SimulatorComponents) {
+        while self.n_events < components.reaction_history.len() {
+            let event = components.reaction_history[self.n_events];
+            self.apply(&event, components);
+        }
+    }
+}
+
+/// The mean squared displacement of agents over each multiple of `dt`,
+/// averaged over every agent and every starting time (from 0, in steps of
+/// `dt`, up to `t_end`) at which the agent was around at both ends. Returns
+/// (lag, mean squared displacement in cells², how many pairs it averages).
+pub fn mean_squared_displacement(agents: &[Agent], dt: f64, t_end: f64) -> Vec<(f64, f64, usize)> {
+    let n_samples = (t_end / dt).floor() as usize + 1;
+    let positions: Vec<Vec<Option<(i64, i64)>>> = agents
+        .iter()
+        .map(|agent| (0..n_samples).map(|idx| agent.step_at_time(idx as f64 * dt).map(|step| step.unwrapped)).collect())
+        .collect();
+    (1..n_samples)
+        .map(|lag| {
+            let (mut total, mut n_pairs) = (0.0, 0);
+            for samples in positions.iter() {
+                for (start, end) in samples.iter().zip(samples[lag..].iter()) {
+                    if let (Some(start), Some(end)) = (start, end) {
+                        total += ((end.0 - start.0).pow(2) + (end.1 - start.1).pow(2)) as f64;
+                        n_pairs += 1;
+                    }
+                }
+            }
+            let msd = if n_pairs > 0 { total / n_pairs as f64 } else { f64::NAN };
+            (lag as f64 * dt, msd, n_pairs)
+        })
+        .collect()
+}
+
+/// The settings for `chitin agents`, as given on the command line.
+pub struct AgentOptions<'a> {
+    pub trace: Option<PathBuf>,
+    pub time: Option<&'a str>,
+    pub samples: Option<&'a str>,
+    pub seed: Option<&'a str>,
+    pub msd: Option<&'a str>,
+}
+
+/// Runs `chitin agents`: follows a manifest's agents through a trace file (or
+/// a seeded run) and writes every agent's trajectory to a CSV file (or
+/// stdout), and optionally their mean squared displacement to another one,
+/// with a diffusion coefficient fitted to it. Returns the process exit code.
+pub fn agents_manifest_file(
+    input_file: PathBuf,
+    output_file: Option<PathBuf>,
+    options: AgentOptions,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path).and_then(|manifest| manifest.with_scenario(scenario)) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (mut components, settings) = match build_simulation(&manifest) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let mut tracker = match AgentTracker::new(&manifest, &components, &settings) {
+        Ok(Some(tracker)) => tracker,
+        Ok(None) => {
+            eprintln!("error: {input_file:?} doesn't name any agent states; add a line like !AGENTS Ant");
+            return 1;
+        },
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 1;
+        }
+    };
+    let parsed = (|| -> Result<(usize, u64), String> {
+        let n_samples = match options.samples {
+            Some(samples) => samples.parse().ok().filter(|n| *n > 0).ok_or(format!("--samples can't be {samples:?}"))?,
+            None => 100,
+        };
+        let seed = match options.seed {
+            Some(seed) => seed.parse().map_err(|_| format!("--seed can't be {seed:?}"))?,
+            None => settings.rng_seed.map_or_else(random::<u32>, |seed| seed as u32) as u64,
+        };
+        Ok((n_samples, seed))
+    })();
+    let (n_samples, seed) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 2;
+        }
+    };
+    let (events, t_end) = match options.trace {
+        Some(path) => match fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| parse_trace(&text, &components, &settings))
+        {
+            Ok(events) => {
+                let t_end = events.last().map_or(0.0, |event| event.t);
+                (events, t_end)
+            },
+            Err(err) => {
+                eprintln!("error: couldn't read {path:?}: {err}");
+                return 1;
+            }
+        },
+        None => {
+            let t_end = match options.time.map(|time| time.parse::<f32>()) {
+                None => settings.max_duration,
+                Some(Ok(time)) if time > 0.0 => time,
+                Some(_) => {
+                    eprintln!("error: --time should be a positive time");
+                    return 2;
+                }
+            };
+            (simulate_history(&components, &settings, seed, t_end), t_end)
+        }
+    };
+    components.reaction_history = events;
+    tracker.catch_up(&components);
+
+    let mut text = String::from("agent,t,row,col,unwrapped_row,unwrapped_col\n");
+    for (agent_idx, agent) in tracker.agents.iter().enumerate() {
+        for step in agent.path.iter() {
+            text.push_str(&format!(
+                "{agent_idx},{},{},{},{},{}\n",
+                step.t, step.loc / settings.n_cols, step.loc % settings.n_cols, step.unwrapped.0, step.unwrapped.1
+            ));
+        }
+    }
+    let n_retired = tracker.agents.iter().filter(|agent| agent.retired.is_some()).count();
+    let mut summary = format!(
+        "Followed {} agent(s) ({n_retired} retired) through {} event(s) up to t={t_end}.",
+        tracker.agents.len(), components.reaction_history.len()
+    );
+
+    if let Some(path) = options.msd {
+        let msd = match t_end > 0.0 {
+            true => mean_squared_displacement(&tracker.agents, t_end as f64 / n_samples as f64, t_end as f64),
+            false => Vec::new(),
+        };
+        let mut msd_text = String::from("lag,msd,pairs\n");
+        for (lag, value, n_pairs) in msd.iter() {
+            msd_text.push_str(&format!("{lag},{value},{n_pairs}\n"));
+        }
+        if let Err(err) = fs::write(path, msd_text) {
+            eprintln!("Couldn't write {path:?}: {err}");
+            return 1;
+        }
+        // A random walk in the plane has MSD = 4 D lag; fit D through the origin.
+        let (numerator, denominator) = msd
+            .iter()
+            .filter(|(_, value, _)| value.is_finite())
+            .fold((0.0, 0.0), |(numerator, denominator), (lag, value, _)| (numerator + lag * value, denominator + lag * lag));
+        if denominator > 0.0 {
+            summary.push_str(&format!(
+                "\nThe diffusion coefficient fitted to their mean squared displacement is {:.4} cells²/time.",
+                numerator / (4.0 * denominator)
+            ));
+        }
+    }
+    match output_file {
+        Some(path) => match fs::write(&path, text) {
+            Ok(()) => {
+                println!("{summary}\nWrote their trajectories to {path:?}.");
+                0
+            },
+            Err(err) => {
+                eprintln!("Couldn't write {path:?}: {err}");
+                1
+            }
+        },
+        None => {
+            print!("{text}");
+            0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::{build_simulation, settings_input};
+    use crate::manifest_lexer::normalize_manifest_text;
+    use crate::trace::parse_trace;
+
+    use super::{mean_squared_displacement, AgentTracker};
+
+    #[test]
+    fn test_ant_walks_around_wrapped_board() {
+        let manifest = "\
+wrap = true
+!START_TRANSITION_RULES
+A + O -> O + A (1)
+A + F -> H + O (1)
+H -> O (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A O F O
+!END_INIT_STATE
+!AGENTS A, H";
+        let manifest = settings_input::manifest(&normalize_manifest_text(manifest).unwrap()).unwrap();
+        let (mut components, settings) = build_simulation(&manifest).unwrap();
+        let mut tracker = AgentTracker::new(&manifest, &components, &settings).unwrap().unwrap();
+        // Left across the edge and back, right, then eat the food and vanish.
+        let trace = "1 0 0,0 0,3\n2 0 0,3 0,0\n3 0 0,0 0,1\n4 1 0,1 0,2\n5 2 0,1\n";
+        components.reaction_history = parse_trace(trace, &components, &settings).unwrap();
+        tracker.catch_up(&components);
+        assert_eq!(tracker.agents.len(), 1);
+        let ant = &tracker.agents[0];
+        let unwrapped: Vec<(i64, i64)> = ant.path.iter().map(|step| step.unwrapped).collect();
+        assert_eq!(unwrapped, [(0, 0), (0, -1), (0, 0), (0, 1)]);
+        // Eating the food turns the ant into H where it stands.
+        assert_eq!(ant.step_at(4).map(|step| step.loc), Some(1));
+        assert_eq!(ant.retired, Some((5, 5.0)));
+        assert_eq!(ant.step_at(5), None);
+        assert_eq!(ant.trail(3, 2).iter().map(|step| step.loc).collect::<Vec<usize>>(), [0, 1]);
+    }
+
+    #[test]
+    fn test_agent_rules() {
+        let manifest = "\
+!START_TRANSITION_RULES
+A + B -> B + A (1)
+A + O -> A + A (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A B O
+!END_INIT_STATE
+!AGENTS A B
+!AGENT_RULE A + B -> B + A: 1->2, 2->1";
+        let manifest = settings_input::manifest(&normalize_manifest_text(manifest).unwrap()).unwrap();
+        let (mut components, settings) = build_simulation(&manifest).unwrap();
+        let mut tracker = AgentTracker::new(&manifest, &components, &settings).unwrap().unwrap();
+        // The two agents swap places, then the A that ends up next to the O splits.
+        components.reaction_history = parse_trace("1 0 0,0 0,1\n2 1 0,1 0,2\n", &components, &settings).unwrap();
+        tracker.catch_up(&components);
+        let locs: Vec<Option<usize>> = tracker.agents.iter().map(|agent| agent.step_at(2).map(|step| step.loc)).collect();
+        assert_eq!(locs, [Some(1), Some(0), Some(2)]);
+        assert_eq!(tracker.agents[2].path[0].n_events, 2);
+
+        for bad in ["!AGENTS Z", "!AGENTS A\n!AGENT_RULE A + O -> O + A: 1->2"] {
+            let text = format!("!START_TRANSITION_RULES\nA + O -> A + A (1)\n!END_TRANSITION_RULES\n!START_INIT_STATE\nA O\n!END_INIT_STATE\n{bad}");
+            let manifest = settings_input::manifest(&normalize_manifest_text(&text).unwrap()).unwrap();
+            let (components, settings) = build_simulation(&manifest).unwrap();
+            assert!(AgentTracker::new(&manifest, &components, &settings).is_err(), "{bad}");
+        }
+        assert!(settings_input::manifest("!AGENT_RULE A + B -> B + A: 1->3").is_err());
+        assert!(settings_input::manifest("!AGENT_RULE A + B -> B + A: 1->2, 1->1").is_err());
+    }
+
+    #[test]
+    fn test_mean_squared_displacement() {
+        let manifest = "\
+!START_TRANSITION_RULES
+A + O -> O + A (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A O O
+!END_INIT_STATE
+!AGENTS A";
+        let manifest = settings_input::manifest(&normalize_manifest_text(manifest).unwrap()).unwrap();
+        let (mut components, settings) = build_simulation(&manifest).unwrap();
+        let mut tracker = AgentTracker::new(&manifest, &components, &settings).unwrap().unwrap();
+        // One step right in each unit of time.
+        components.reaction_history = parse_trace("0.5 0 0,0 0,1\n1.5 0 0,1 0,2\n", &components, &settings).unwrap();
+        tracker.catch_up(&components);
+        // Positions at t = 0, 1, 2, 3 are 0, 1, 2, 2.
+        let msd = mean_squared_displacement(&tracker.agents, 1.0, 3.0);
+        assert_eq!(msd, [(1.0, 2.0 / 3.0, 3), (2.0, 2.5, 2), (3.0, 4.0, 1)]);
+    }
+}
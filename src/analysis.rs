@@ -0,0 +1,401 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::input::read_manifest_file;
+use crate::input_parsers::ParsedManifest;
+use crate::lint::describe_rule;
+use crate::manifest_lexer::quote_name;
+use crate::reactions::ReactionDescription;
+use crate::state::SimulatorComponents;
+
+// Enumerating conservation laws can blow up on big rule sets; give up on rule
+// sets with more than this many (state, distinct rule effect) pairs, past this
+// many candidate laws, or after roughly this many row operations.
+const MAX_INVARIANT_MATRIX_SIZE: usize = 4_000_000;
+const MAX_INVARIANT_CANDIDATES: usize = 2_000;
+const MAX_INVARIANT_WORK: usize = 50_000_000;
+
+/// A weighted count of states, like `Ant + 2 AntCarrying`, that a manifest
+/// expects its rules to keep constant. Written in a manifest as
+/// `!INVARIANT Ant + 2 AntCarrying`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invariant {
+    pub terms: Vec<(String, i64)>, // (state, weight)
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let terms: Vec<String> = self.terms
+            .iter()
+            .map(|(state, weight)| {
+                // Quote names that start with a digit, so `2 "1"` can't be misread.
+                let name = if state.starts_with(|c: char| c.is_ascii_digit()) { format!("\"{state}\"") } else { quote_name(state) };
+                if *weight == 1 { name } else { format!("{weight} {name}") }
+            })
+            .collect();
+        write!(f, "{}", terms.join(" + "))
+    }
+}
+
+impl Invariant {
+    /// How much firing a rule changes the weighted count.
+    pub fn change_under(&self, rule: &ReactionDescription) -> i64 {
+        let weight = |state: &str| -> i64 {
+            self.terms.iter().filter(|(term, _)| term == state).map(|(_, weight)| weight).sum()
+        };
+        let reactants = std::iter::once(&rule.r1).chain(rule.r2.iter());
+        let products = std::iter::once(&rule.p1).chain(rule.p2.iter());
+        products.map(|state| weight(state)).sum::<i64>() - reactants.map(|state| weight(state)).sum::<i64>()
+    }
+}
+
+/// Finds every state that can ever appear on the surface, starting from the
+/// states in `initial` and firing any rule whose reactants have all appeared.
+/// This ignores geometry, so it over-approximates what can actually happen.
+pub fn reachable_states<'a>(initial: impl IntoIterator<Item = &'a str>, rules: &'a [ReactionDescription]) -> HashSet<&'a str> {
+    let mut reachable: HashSet<&str> = initial.into_iter().collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for rule in rules {
+            let can_fire = reachable.contains(&rule.r1[..])
+                && rule.r2.as_ref().is_none_or(|r2| reachable.contains(&r2[..]));
+            if !can_fire {
+                continue;
+            }
+            for product in std::iter::once(&rule.p1).chain(rule.p2.iter()) {
+                changed |= reachable.insert(&product[..]);
+            }
+        }
+    }
+    reachable
+}
+
+/// reachable_states for a built simulation, by state id, starting from the
+/// board on screen.
+pub fn reachable_state_ids(components: &SimulatorComponents) -> HashSet<usize> {
+    let mut reachable: HashSet<usize> = components.current_states.iter().copied().collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for rxn in components.all_reactions.iter() {
+            if reachable.contains(&rxn.r1_num) && rxn.r2_num.is_none_or(|r2| reachable.contains(&r2)) {
+                changed |= reachable.insert(rxn.p1_num);
+                if let Some(p2) = rxn.p2_num {
+                    changed |= reachable.insert(p2);
+                }
+            }
+        }
+    }
+    reachable
+}
+
+/// Finds the minimal conservation laws of a rule set: weighted counts of
+/// states, with no negative weights, that no rule changes. Every rule turns one
+/// cell into one cell, so the total count of all states is always conserved;
+/// this finds the finer-grained laws (like "the number of ants") that make it
+/// up. Uses the Farkas algorithm for Petri net place invariants.
+pub fn conservation_laws(states: &[&str], rules: &[&ReactionDescription]) -> Result<Vec<Invariant>, String> {
+    let state_idx: HashMap<&str, usize> = states.iter().enumerate().map(|(idx, state)| (*state, idx)).collect();
+    let n_states = states.len();
+
+    // Only a rule's net effect matters, and big rule sets repeat the same few
+    // effects many times over, so work with each distinct effect once.
+    let mut effects: Vec<Vec<(usize, i64)>> = Vec::new(); // (state, net change), sorted by state
+    for rule in rules {
+        let mut effect: BTreeMap<usize, i64> = BTreeMap::new();
+        let reactants = std::iter::once(&rule.r1).chain(rule.r2.iter()).map(|state| (state, -1));
+        let products = std::iter::once(&rule.p1).chain(rule.p2.iter()).map(|state| (state, 1));
+        for (state, change) in reactants.chain(products) {
+            let Some(idx) = state_idx.get(&state[..]) else {
+                return Err(format!("rule {} uses state {state}, which isn't in the list", describe_rule(rule)));
+            };
+            *effect.entry(*idx).or_default() += change;
+        }
+        effects.push(effect.into_iter().filter(|(_, change)| *change != 0).collect());
+    }
+    effects.retain(|effect| !effect.is_empty());
+    effects.sort();
+    effects.dedup();
+    if effects.len() * n_states > MAX_INVARIANT_MATRIX_SIZE {
+        return Err(format!(
+            "too many states ({n_states}) and distinct rule effects ({}) to search", effects.len()
+        ));
+    }
+
+    // Each row is (net change under each effect, weights on each state).
+    let mut rows: Vec<(Vec<i64>, Vec<i64>)> = (0..n_states)
+        .map(|idx| {
+            let changes = effects
+                .iter()
+                .map(|effect| effect.iter().find(|(state, _)| *state == idx).map_or(0, |(_, change)| *change))
+                .collect();
+            let mut weights = vec![0; n_states];
+            weights[idx] = 1;
+            (changes, weights)
+        })
+        .collect();
+
+    // Eliminate one effect at a time, always picking the one that makes the
+    // fewest new combinations; the order doesn't change the answer, but a bad
+    // one can make the intermediate rows explode.
+    let mut remaining: Vec<usize> = (0..effects.len()).collect();
+    let mut work = 0;
+    while !remaining.is_empty() {
+        work += remaining.len() * rows.len();
+        let combinations = |col: usize| {
+            let positive = rows.iter().filter(|(changes, _)| changes[col] > 0).count();
+            let negative = rows.iter().filter(|(changes, _)| changes[col] < 0).count();
+            positive * negative
+        };
+        let (pick, _) = remaining.iter().enumerate().min_by_key(|(_, col)| combinations(**col)).unwrap();
+        let rule_idx = remaining.swap_remove(pick);
+        let (zero, nonzero): (Vec<_>, Vec<_>) = rows.into_iter().partition(|(changes, _)| changes[rule_idx] == 0);
+        let mut next_rows = zero;
+        if nonzero.is_empty() {
+            rows = next_rows;
+            continue;
+        }
+        let (positive, negative): (Vec<_>, Vec<_>) = nonzero.into_iter().partition(|(changes, _)| changes[rule_idx] > 0);
+        work += positive.len() * negative.len();
+        if work > MAX_INVARIANT_WORK {
+            return Err("gave up; the search was taking too long".to_string());
+        }
+        for (pos_changes, pos_weights) in positive.iter() {
+            for (neg_changes, neg_weights) in negative.iter() {
+                let (a, b) = (-neg_changes[rule_idx], pos_changes[rule_idx]);
+                let changes: Vec<i64> = pos_changes.iter().zip(neg_changes).map(|(p, n)| a * p + b * n).collect();
+                let weights: Vec<i64> = pos_weights.iter().zip(neg_weights).map(|(p, n)| a * p + b * n).collect();
+                let divisor = weights.iter().chain(changes.iter()).fold(0, |acc, x| gcd(acc, *x));
+                next_rows.push((
+                    changes.iter().map(|x| x / divisor).collect(),
+                    weights.iter().map(|x| x / divisor).collect()
+                ));
+            }
+        }
+        rows = minimal_supports(next_rows);
+        if rows.len() > MAX_INVARIANT_CANDIDATES {
+            return Err(format!("gave up after finding more than {MAX_INVARIANT_CANDIDATES} candidate laws"));
+        }
+    }
+
+    let mut laws: Vec<Invariant> = rows
+        .into_iter()
+        .map(|(_, weights)| Invariant {
+            terms: weights
+                .iter()
+                .enumerate()
+                .filter(|(_, weight)| **weight != 0)
+                .map(|(idx, weight)| (states[idx].to_string(), *weight))
+                .collect()
+        })
+        .collect();
+    laws.sort_by_key(|law| law.to_string());
+    Ok(laws)
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 { a.abs() } else { gcd(b, a % b) }
+}
+
+/// Drops rows whose set of weighted states contains another row's; those are
+/// sums of smaller laws.
+fn minimal_supports(rows: Vec<(Vec<i64>, Vec<i64>)>) -> Vec<(Vec<i64>, Vec<i64>)> {
+    let supports: Vec<Vec<usize>> = rows
+        .iter()
+        .map(|(_, weights)| (0..weights.len()).filter(|idx| weights[*idx] != 0).collect())
+        .collect();
+    let is_subset = |small: &Vec<usize>, big: &Vec<usize>| small.iter().all(|idx| big.binary_search(idx).is_ok());
+    rows.into_iter()
+        .enumerate()
+        .filter(|(idx, _)| {
+            !supports.iter().enumerate().any(|(other, support)| {
+                other != *idx
+                    && is_subset(support, &supports[*idx])
+                    && (support.len() < supports[*idx].len() || other < *idx)
+            })
+        })
+        .map(|(_, row)| row)
+        .collect()
+}
+
+/// What `chitin analyze` finds out about a manifest.
+pub struct Analysis {
+    pub reachable: Vec<String>,
+    pub unreachable: Vec<String>,
+    pub dead_ends: Vec<String>, // Reachable states that no rule ever changes back.
+    pub conservation_laws: Result<Vec<Invariant>, String>,
+    pub broken_invariants: Vec<(Invariant, Vec<(usize, i64)>)>, // (invariant, [(rule index, change)]), over rules that can fire
+}
+
+/// Works out which states can appear, which laws the rules conserve, and
+/// whether the manifest's declared invariants hold. The manifest should
+/// already have its scenario applied.
+pub fn analyze_manifest(manifest: &ParsedManifest) -> Result<Analysis, String> {
+    let rules = &manifest.transition_rules;
+    let mut initial: Vec<String> = Vec::new();
+    for source in manifest.init_states.iter() {
+        initial.extend(source.expand(manifest)?.states);
+    }
+
+    let reachable_set = reachable_states(initial.iter().map(|s| &s[..]), rules);
+    let mut all_states: Vec<&str> = rules
+        .iter()
+        .flat_map(|rule| rule.all_states())
+        .chain(manifest.color_classes.iter().flat_map(|(_, _, states)| states.iter().map(|s| &s[..])))
+        .chain(reachable_set.iter().copied())
+        .collect();
+    all_states.sort();
+    all_states.dedup();
+    let (reachable, unreachable): (Vec<&str>, Vec<&str>) = all_states
+        .into_iter()
+        .partition(|state| reachable_set.contains(state));
+
+    // Laws only need to hold for rules that can actually fire.
+    let is_live = |rule: &ReactionDescription| rule.all_states().iter().all(|state| reachable_set.contains(state));
+    let live_rules: Vec<&ReactionDescription> = rules.iter().filter(|rule| is_live(rule)).collect();
+    let consumed: HashSet<&str> = live_rules
+        .iter()
+        .flat_map(|rule| {
+            let mut changed = vec![];
+            if rule.r1 != rule.p1 {
+                changed.push(&rule.r1[..]);
+            }
+            if let (Some(r2), Some(p2)) = (&rule.r2, &rule.p2) {
+                if r2 != p2 {
+                    changed.push(&r2[..]);
+                }
+            }
+            changed
+        })
+        .collect();
+    let initial_set: HashSet<&str> = initial.iter().map(|s| &s[..]).collect();
+    let dead_ends: Vec<String> = reachable
+        .iter()
+        .filter(|state| !consumed.contains(*state) && !initial_set.contains(*state))
+        .map(|state| state.to_string())
+        .collect();
+
+    let mut broken_invariants = Vec::new();
+    for invariant in manifest.invariants.iter() {
+        let breaking_rules: Vec<(usize, i64)> = rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| is_live(rule))
+            .map(|(rule_idx, rule)| (rule_idx, invariant.change_under(rule)))
+            .filter(|(_, change)| *change != 0)
+            .collect();
+        if !breaking_rules.is_empty() {
+            broken_invariants.push((invariant.clone(), breaking_rules));
+        }
+    }
+
+    Ok(Analysis {
+        conservation_laws: conservation_laws(&reachable, &live_rules),
+        reachable: reachable.iter().map(|s| s.to_string()).collect(),
+        unreachable: unreachable.iter().map(|s| s.to_string()).collect(),
+        dead_ends,
+        broken_invariants,
+    })
+}
+
+/// Runs `chitin analyze` on a manifest file, printing what it finds. Returns
+/// the process exit code: 1 if a declared invariant is broken, 0 otherwise.
+pub fn analyze_manifest_file(input_file: PathBuf, scenario: Option<&str>, search_path: &[PathBuf]) -> i32 {
+    let analysis = match read_manifest_file(input_file.clone(), search_path)
+        .and_then(|manifest| manifest.with_scenario(scenario))
+        .and_then(|manifest| Ok((analyze_manifest(&manifest)?, manifest)))
+    {
+        Ok(analysis) => analysis,
+        Err(err) => {
+            eprintln!("error: couldn't analyze {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (analysis, manifest) = analysis;
+
+    println!("Reachable states ({}): {}", analysis.reachable.len(), analysis.reachable.join(", "));
+    if !analysis.unreachable.is_empty() {
+        println!("Unreachable states ({}): {}", analysis.unreachable.len(), analysis.unreachable.join(", "));
+    }
+    if !analysis.dead_ends.is_empty() {
+        println!("Created but never consumed: {}", analysis.dead_ends.join(", "));
+    }
+    match &analysis.conservation_laws {
+        Ok(laws) => {
+            println!("Conservation laws ({}):", laws.len());
+            for law in laws {
+                println!("    {law}");
+            }
+        },
+        Err(err) => println!("Conservation laws: {err}"),
+    }
+
+    let mut by_invariant: BTreeMap<String, &Vec<(usize, i64)>> = BTreeMap::new();
+    for (invariant, breaking_rules) in analysis.broken_invariants.iter() {
+        by_invariant.insert(invariant.to_string(), breaking_rules);
+    }
+    for invariant in manifest.invariants.iter() {
+        match by_invariant.get(&invariant.to_string()) {
+            None => println!("Invariant {invariant}: holds"),
+            Some(breaking_rules) => {
+                println!("Invariant {invariant}: broken by {} rule(s)", breaking_rules.len());
+                for (rule_idx, change) in breaking_rules.iter() {
+                    println!("    rule {rule_idx} ({}) changes it by {change:+}", describe_rule(&manifest.transition_rules[*rule_idx]));
+                }
+            }
+        }
+    }
+    if analysis.broken_invariants.is_empty() { 0 } else { 1 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{analyze_manifest, Invariant};
+
+    const ANTS: &str = "\
+!INVARIANT Ant + Carrying
+!INVARIANT Food + Carrying
+!INVARIANT Ant
+!START_TRANSITION_RULES
+Ant + Empty -> Empty + Ant (1)
+Carrying + Empty -> Empty + Carrying (1)
+Ant + Food -> Carrying + Empty (1)
+Carrying + Nest -> Ant + Nest (1)
+Ghost -> Ant (1)
+Empty -> Lost (0.1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+Ant Empty Food
+Empty Nest Empty
+!END_INIT_STATE";
+
+    #[test]
+    fn test_reachability_and_laws() {
+        let manifest = settings_input::manifest(ANTS).unwrap();
+        let analysis = analyze_manifest(&manifest).unwrap();
+        assert_eq!(analysis.unreachable, vec!["Ghost"]);
+        assert_eq!(analysis.dead_ends, vec!["Lost"]);
+
+        let laws: Vec<String> = analysis.conservation_laws.unwrap().iter().map(|law| law.to_string()).collect();
+        assert!(laws.contains(&"Ant + Carrying".to_string()), "{laws:?}");
+        assert!(laws.contains(&"Nest".to_string()), "{laws:?}");
+        assert!(!laws.contains(&"Food + Carrying".to_string()), "{laws:?}");
+    }
+
+    #[test]
+    fn test_broken_invariants() {
+        let manifest = settings_input::manifest(ANTS).unwrap();
+        assert_eq!(manifest.invariants[0], Invariant { terms: vec![("Ant".to_string(), 1), ("Carrying".to_string(), 1)] });
+        let analysis = analyze_manifest(&manifest).unwrap();
+
+        let broken: Vec<String> = analysis.broken_invariants.iter().map(|(invariant, _)| invariant.to_string()).collect();
+        // Ghost -> Ant would break Ant + Carrying, but it can never fire.
+        assert_eq!(broken, vec!["Food + Carrying", "Ant"]);
+        assert_eq!(analysis.broken_invariants[1].1, vec![(2, -1), (3, 1)]);
+    }
+}
@@ -0,0 +1,555 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::input::load_from_manifest;
+use crate::manifest_writer::{write_manifest, BoardSnapshot};
+use crate::structured_manifest::{ColorClassEntry, ManifestFormat, RuleEntry, SettingValue, StructuredManifest};
+
+// Each CA cell becomes a 3x3 block of surface cells. The center holds the
+// cell's state; the ring around it does the communication:
+//
+//     A B C
+//     D E F
+//     G H I
+//
+// Ring cells in the order the neighbor summary is gathered, which is also the
+// order of a Moore neighborhood (clockwise from the top left): after the swaps,
+// A holds the top-left neighbor's state, B the top neighbor's, and so on.
+const RING: [&str; 8] = ["A", "B", "C", "F", "I", "H", "G", "D"];
+const VON_NEUMANN_RING: [&str; 4] = ["B", "F", "H", "D"];
+const BOUNDARY: &str = "Z";
+const WIRE_COLOR: [u8; 3] = [255, 255, 255];
+const BOUNDARY_COLOR: [u8; 3] = [0, 255, 255];
+
+/// A synchronous cellular automaton, written as TOML or JSON, to compile into
+/// a surface CRN. The rule is either outer-totalistic (the new state depends
+/// on the old one and how many neighbors are in one of the `counted` states)
+/// or a table of neighborhood patterns; a cell that no rule matches keeps its
+/// state.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CellularAutomaton {
+    pub states: Vec<CaState>,
+    #[serde(default)]
+    pub neighborhood: Neighborhood,
+    #[serde(default)]
+    pub boundary: Option<String>, // The state off-board neighbors count as; without one, the board wraps around.
+    #[serde(default)]
+    pub counted: Vec<String>,
+    #[serde(default)]
+    pub outer_totalistic: Vec<TotalisticRule>,
+    #[serde(default)]
+    pub table: Vec<TableRule>,
+    #[serde(default)]
+    pub settings: BTreeMap<String, SettingValue>,
+    pub init_state: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaState {
+    pub name: String,
+    pub color: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Neighborhood {
+    #[default]
+    Moore,
+    VonNeumann,
+}
+
+/// A cell in state `from` with a number of counted neighbors listed in
+/// `counts` moves to state `to`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TotalisticRule {
+    pub from: String,
+    pub counts: Vec<usize>,
+    pub to: String,
+}
+
+/// A cell in state `from` whose neighbors match `neighbors` moves to state
+/// `to`. Neighbors are listed clockwise, starting from the top left for a
+/// Moore neighborhood and from the top for a von Neumann one; `*` matches
+/// any state. The first matching rule wins.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TableRule {
+    pub from: String,
+    pub neighbors: Vec<String>,
+    pub to: String,
+}
+
+impl Neighborhood {
+    fn ring(&self) -> &'static [&'static str] {
+        match self {
+            Neighborhood::Moore => &RING,
+            Neighborhood::VonNeumann => &VON_NEUMANN_RING,
+        }
+    }
+}
+
+impl CellularAutomaton {
+    pub fn from_toml(text: &str) -> Result<CellularAutomaton, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    pub fn from_json(text: &str) -> Result<CellularAutomaton, String> {
+        serde_json::from_str(text).map_err(|err| err.to_string())
+    }
+
+    fn state_idx(&self, name: &str) -> Result<usize, String> {
+        self.states
+            .iter()
+            .position(|state| state.name == name)
+            .ok_or_else(|| format!("there's no state named {name:?}"))
+    }
+
+    /// Checks that every name refers to a state and that the rule and initial
+    /// state fit together.
+    fn validate(&self) -> Result<(), String> {
+        if self.states.is_empty() {
+            return Err("there are no states".to_string());
+        }
+        let mut seen = BTreeSet::new();
+        for state in self.states.iter() {
+            // Generated names are built from state names with underscores
+            // between the parts, so they have to stay unambiguous.
+            if state.name.is_empty() || !state.name.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(format!("state names can only use letters and digits, not {:?}", state.name));
+            }
+            if !seen.insert(&state.name) {
+                return Err(format!("state {:?} is listed twice", state.name));
+            }
+        }
+        if !self.outer_totalistic.is_empty() && !self.table.is_empty() {
+            return Err("give either outer_totalistic or table rules, not both".to_string());
+        }
+        if let Some(boundary) = &self.boundary {
+            self.state_idx(boundary)?;
+        }
+        for name in self.counted.iter() {
+            self.state_idx(name)?;
+        }
+        let n_neighbors = self.neighborhood.ring().len();
+        for rule in self.outer_totalistic.iter() {
+            self.state_idx(&rule.from)?;
+            self.state_idx(&rule.to)?;
+            if let Some(count) = rule.counts.iter().find(|count| **count > n_neighbors) {
+                return Err(format!("a cell can't have {count} counted neighbors out of {n_neighbors}"));
+            }
+        }
+        for rule in self.table.iter() {
+            self.state_idx(&rule.from)?;
+            self.state_idx(&rule.to)?;
+            if rule.neighbors.len() != n_neighbors {
+                return Err(format!("table rules need {n_neighbors} neighbors, not {}", rule.neighbors.len()));
+            }
+            for name in rule.neighbors.iter().filter(|name| *name != "*") {
+                self.state_idx(name)?;
+            }
+        }
+        let n_cols = self.init_state.first().map_or(0, |row| row.len());
+        if n_cols == 0 {
+            return Err("the initial state is empty".to_string());
+        }
+        if let Some(row_idx) = self.init_state.iter().position(|row| row.len() != n_cols) {
+            return Err(format!("row {row_idx} of the initial state has a different length than row 0"));
+        }
+        for name in self.init_state.iter().flatten() {
+            self.state_idx(name)?;
+        }
+        Ok(())
+    }
+
+    /// Adds one neighbor's state to what's known about the neighborhood so
+    /// far: for an outer-totalistic rule, the number of counted neighbors; for
+    /// a table, the states themselves, in order.
+    fn fold_neighbor(&self, summary: &[usize], state: usize) -> Vec<usize> {
+        if self.table.is_empty() {
+            let counted = self.counted.contains(&self.states[state].name) as usize;
+            vec![summary.first().copied().unwrap_or(0) + counted]
+        } else {
+            summary.iter().copied().chain(std::iter::once(state)).collect()
+        }
+    }
+
+    fn summary_name(&self, summary: &[usize]) -> String {
+        if self.table.is_empty() {
+            summary.first().copied().unwrap_or(0).to_string()
+        } else {
+            summary.iter().map(|state| &self.states[*state].name[..]).collect::<Vec<&str>>().join("_")
+        }
+    }
+
+    /// The state a cell moves to, given its own state and the summary of its
+    /// neighborhood.
+    pub fn next_state(&self, state: usize, summary: &[usize]) -> usize {
+        let name = &self.states[state].name;
+        let to = if self.table.is_empty() {
+            let count = summary.first().copied().unwrap_or(0);
+            self.outer_totalistic
+                .iter()
+                .find(|rule| rule.from == *name && rule.counts.contains(&count))
+                .map(|rule| &rule.to)
+        } else {
+            self.table
+                .iter()
+                .find(|rule| {
+                    rule.from == *name && rule.neighbors
+                        .iter()
+                        .zip(summary)
+                        .all(|(pattern, state)| pattern == "*" || *pattern == self.states[*state].name)
+                })
+                .map(|rule| &rule.to)
+        };
+        to.map_or(state, |to| self.state_idx(to).unwrap())
+    }
+
+    /// Builds the surface CRN. Each generation, a block's center broadcasts
+    /// its state to the ring (B, F, H, D, each passing it on to a corner);
+    /// the ring swaps states with the neighboring blocks' rings (corners take
+    /// two swaps, across and then up or down, to reach the diagonal blocks);
+    /// a summary of the neighborhood is gathered around the ring from A to D,
+    /// putting the ring back to rest as it goes; and D hands the summary to
+    /// the center, which moves to its next state. A block can't start a new
+    /// generation until its neighbors have swapped with it, so neighboring
+    /// blocks are never more than one generation apart, and every block goes
+    /// through the same states the synchronous automaton would.
+    pub fn compile(&self) -> Result<StructuredManifest, String> {
+        self.validate()?;
+        let names: Vec<&str> = self.states.iter().map(|state| &state.name[..]).collect();
+        let ring = self.neighborhood.ring();
+        let mut rules: Vec<RuleEntry> = Vec::new();
+        let mut add_rule = |r1: String, r2: String, p1: String, p2: String| {
+            rules.push(RuleEntry { reactants: vec![r1, r2], products: vec![p1, p2], rate: 1.0 });
+        };
+
+        // Broadcast: the center visits B, F, H, and D in turn, and each passes
+        // the state on to the corner before it in the ring.
+        for s in names.iter() {
+            add_rule(format!("E_{s}"), "B".into(), format!("bE_{s}"), format!("wB_{s}"));
+            add_rule(format!("bE_{s}"), "F".into(), format!("fE_{s}"), format!("wF_{s}"));
+            add_rule(format!("fE_{s}"), "H".into(), format!("hE_{s}"), format!("wH_{s}"));
+            add_rule(format!("hE_{s}"), "D".into(), format!("dE_{s}"), format!("wD_{s}"));
+            for (edge, corner) in [("B", "A"), ("F", "C"), ("H", "I"), ("D", "G")] {
+                add_rule(format!("w{edge}_{s}"), corner.into(), format!("{edge}_{s}"), format!("{corner}_{s}"));
+            }
+        }
+
+        // Swap with the neighboring blocks: edges directly, corners across and
+        // then up or down.
+        for s in names.iter() {
+            for t in names.iter() {
+                for (x, y) in [("F", "D"), ("H", "B")] {
+                    add_rule(format!("{x}_{s}"), format!("{y}_{t}"), format!("n{x}_{t}"), format!("n{y}_{s}"));
+                }
+                for (x, y) in [("I", "G"), ("C", "A")] {
+                    add_rule(format!("{x}_{s}"), format!("{y}_{t}"), format!("t{x}_{t}"), format!("t{y}_{s}"));
+                }
+                for (x, y) in [("I", "C"), ("G", "A")] {
+                    add_rule(format!("t{x}_{s}"), format!("t{y}_{t}"), format!("n{x}_{t}"), format!("n{y}_{s}"));
+                }
+            }
+        }
+        if let Some(boundary) = &self.boundary {
+            // Ring cells next to the edge of the board have no block to swap
+            // with, so they hear the boundary state instead. A corner on the
+            // top or bottom row may already have swapped across.
+            for x in RING.iter() {
+                for s in names.iter() {
+                    add_rule(BOUNDARY.into(), format!("{x}_{s}"), BOUNDARY.into(), format!("n{x}_{boundary}"));
+                    if ["A", "C", "G", "I"].contains(x) {
+                        add_rule(BOUNDARY.into(), format!("t{x}_{s}"), BOUNDARY.into(), format!("n{x}_{boundary}"));
+                    }
+                }
+            }
+        }
+
+        // Gather the neighborhood around the ring, from A to D.
+        let fold = |summary: &[usize], position: &str, state: usize| {
+            if ring.contains(&position) { self.fold_neighbor(summary, state) } else { summary.to_vec() }
+        };
+        let mut summaries: BTreeSet<Vec<usize>> = BTreeSet::new();
+        for v in 0..names.len() {
+            for w in 0..names.len() {
+                let summary = fold(&fold(&[], RING[0], v), RING[1], w);
+                add_rule(
+                    format!("n{}_{}", RING[0], names[v]),
+                    format!("n{}_{}", RING[1], names[w]),
+                    RING[0].into(),
+                    format!("s{}_{}", RING[1], self.summary_name(&summary))
+                );
+                summaries.insert(summary);
+            }
+        }
+        for pair in RING.windows(2).skip(1) {
+            let (previous, position) = (pair[0], pair[1]);
+            let mut next_summaries = BTreeSet::new();
+            for summary in summaries.iter() {
+                for (w, name) in names.iter().enumerate() {
+                    let next = fold(summary, position, w);
+                    add_rule(
+                        format!("s{previous}_{}", self.summary_name(summary)),
+                        format!("n{position}_{name}"),
+                        previous.into(),
+                        format!("s{position}_{}", self.summary_name(&next))
+                    );
+                    next_summaries.insert(next);
+                }
+            }
+            summaries = next_summaries;
+        }
+
+        // D hands the whole neighborhood to the center.
+        let last = RING[RING.len() - 1];
+        for summary in summaries.iter() {
+            for (s, name) in names.iter().enumerate() {
+                add_rule(
+                    format!("s{last}_{}", self.summary_name(summary)),
+                    format!("dE_{name}"),
+                    last.into(),
+                    format!("E_{}", names[self.next_state(s, summary)])
+                );
+            }
+        }
+
+        // Colors: each CA state's center cells share its color, and all the
+        // plumbing is drawn as plain wire.
+        let mut colormap: Vec<ColorClassEntry> = self.states
+            .iter()
+            .map(|state| ColorClassEntry {
+                class: Some(state.name.clone()),
+                states: ["E", "bE", "fE", "hE", "dE"].iter().map(|prefix| format!("{prefix}_{}", state.name)).collect(),
+                color: state.color,
+            })
+            .collect();
+        let centers: BTreeSet<String> = colormap.iter().flat_map(|entry| entry.states.iter().cloned()).collect();
+        let wires: BTreeSet<String> = rules
+            .iter()
+            .flat_map(|rule| rule.reactants.iter().chain(rule.products.iter()).cloned())
+            .filter(|state| !centers.contains(state) && state != BOUNDARY)
+            .collect();
+        colormap.push(ColorClassEntry { class: Some("wires".into()), states: wires.into_iter().collect(), color: WIRE_COLOR });
+
+        // Each CA cell becomes a block, with a frame of boundary cells around
+        // the board if it doesn't wrap.
+        let mut init_state: Vec<Vec<String>> = Vec::new();
+        for row in self.init_state.iter() {
+            for block_row in 0..3 {
+                let mut cells = Vec::new();
+                for state in row.iter() {
+                    match block_row {
+                        0 => cells.extend(["A", "B", "C"].map(String::from)),
+                        1 => cells.extend(["D".to_string(), format!("E_{state}"), "F".to_string()]),
+                        _ => cells.extend(["G", "H", "I"].map(String::from)),
+                    }
+                }
+                init_state.push(cells);
+            }
+        }
+        let mut settings = self.settings.clone();
+        if self.boundary.is_some() {
+            let width = init_state[0].len() + 2;
+            let frame = vec![BOUNDARY.to_string(); width];
+            init_state = std::iter::once(frame.clone())
+                .chain(init_state.into_iter().map(|row| {
+                    std::iter::once(BOUNDARY.to_string()).chain(row).chain(std::iter::once(BOUNDARY.to_string())).collect()
+                }))
+                .chain(std::iter::once(frame))
+                .collect();
+            colormap.push(ColorClassEntry { class: None, states: vec![BOUNDARY.into()], color: BOUNDARY_COLOR });
+        } else {
+            settings.insert("wrap".into(), SettingValue::Bool(true));
+        }
+
+        Ok(StructuredManifest { settings, colormap, rules, init_state, ..Default::default() })
+    }
+}
+
+/// Runs `chitin compile-ca`: compiles a CA description (TOML or JSON) into a
+/// manifest, written in the format matching the output file's extension.
+pub fn compile_ca_file(input_file: PathBuf, output_file: PathBuf) -> i32 {
+    let automaton = fs::read_to_string(&input_file)
+        .map_err(|err| err.to_string())
+        .and_then(|text| match ManifestFormat::from_path(&input_file) {
+            ManifestFormat::Toml => CellularAutomaton::from_toml(&text),
+            ManifestFormat::Json => CellularAutomaton::from_json(&text),
+            ManifestFormat::Text => Err("CA descriptions have to be .toml or .json files".to_string()),
+        });
+    let compiled = automaton.and_then(|automaton| automaton.compile());
+    let text = compiled.and_then(|structured| match ManifestFormat::from_path(&output_file) {
+        ManifestFormat::Text => {
+            let parsed = structured.to_parsed()?;
+            let (components, settings, _) = load_from_manifest(&parsed, None)?;
+            Ok(write_manifest(&components, &settings, &parsed, BoardSnapshot::Current))
+        },
+        ManifestFormat::Toml => Ok(structured.to_toml()),
+        ManifestFormat::Json => Ok(structured.to_json()),
+    });
+    match text.and_then(|text| fs::write(&output_file, text).map_err(|err| err.to_string())) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("error: couldn't compile {input_file:?}: {err}");
+            1
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input::load_from_manifest;
+    use crate::simulator::{extend_reaction_history, initialize_queue};
+
+    use super::CellularAutomaton;
+
+    // Greenberg-Hastings excitable media: a resting cell next to an excited
+    // one gets excited, and excited cells go refractory, then back to rest.
+    const GREENBERG_HASTINGS: &str = r#"
+neighborhood = "von_neumann"
+boundary = "q"
+counted = ["a"]
+init_state = [["q", "q", "q"], ["q", "a", "q"], ["r", "q", "q"]]
+
+[[states]]
+name = "q"
+color = [230, 230, 230]
+[[states]]
+name = "a"
+color = [255, 0, 0]
+[[states]]
+name = "r"
+color = [0, 100, 255]
+
+[[outer_totalistic]]
+from = "q"
+counts = [1, 2, 3, 4]
+to = "a"
+[[outer_totalistic]]
+from = "a"
+counts = [0, 1, 2, 3, 4]
+to = "r"
+[[outer_totalistic]]
+from = "r"
+counts = [0, 1, 2, 3, 4]
+to = "q"
+"#;
+
+    /// One synchronous step of the automaton, for comparison.
+    fn step(automaton: &CellularAutomaton, board: &[Vec<usize>]) -> Vec<Vec<usize>> {
+        let (n_rows, n_cols) = (board.len() as i64, board[0].len() as i64);
+        let boundary = automaton.boundary.as_ref().map(|name| automaton.state_idx(name).unwrap());
+        let offsets: &[(i64, i64)] = match automaton.neighborhood {
+            super::Neighborhood::Moore => &[(-1, -1), (-1, 0), (-1, 1), (0, 1), (1, 1), (1, 0), (1, -1), (0, -1)],
+            super::Neighborhood::VonNeumann => &[(-1, 0), (0, 1), (1, 0), (0, -1)],
+        };
+        (0..n_rows)
+            .map(|row| {
+                (0..n_cols)
+                    .map(|col| {
+                        let summary = offsets.iter().fold(Vec::new(), |summary, (dr, dc)| {
+                            let (r, c) = (row + dr, col + dc);
+                            let neighbor = if (0..n_rows).contains(&r) && (0..n_cols).contains(&c) {
+                                board[r as usize][c as usize]
+                            } else {
+                                boundary.unwrap_or(board[r.rem_euclid(n_rows) as usize][c.rem_euclid(n_cols) as usize])
+                            };
+                            automaton.fold_neighbor(&summary, neighbor)
+                        });
+                        automaton.next_state(board[row as usize][col as usize], &summary)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Runs the compiled surface CRN and checks that each block's center goes
+    /// through the same states, generation by generation, as the synchronous
+    /// automaton's cell.
+    fn check_against_synchronous(automaton: &CellularAutomaton, n_generations: usize) {
+        let structured = automaton.compile().unwrap();
+        let (mut components, settings, mut global_state) = load_from_manifest(&structured.to_parsed().unwrap(), None).unwrap();
+        initialize_queue(&components, &mut global_state, &settings);
+
+        let n_rows = automaton.init_state.len();
+        let n_cols = automaton.init_state[0].len();
+        let frame = automaton.boundary.is_some() as usize;
+        let mut expected = vec![automaton.init_state
+            .iter()
+            .map(|row| row.iter().map(|name| automaton.state_idx(name).unwrap()).collect::<Vec<usize>>())
+            .collect::<Vec<_>>()];
+        for _ in 0..n_generations {
+            expected.push(step(automaton, expected.last().unwrap()));
+        }
+
+        let mut generations = vec![vec![0; n_cols]; n_rows];
+        let mut n_events = 0;
+        while generations.iter().flatten().any(|generation| *generation < n_generations) {
+            extend_reaction_history(&mut components, &mut global_state, &settings);
+            n_events += 1;
+            assert!(n_events < 1_000_000, "the surface CRN stalled");
+            let event = components.reaction_history.last().unwrap();
+            for loc in std::iter::once(event.r1_loc).chain(event.r2_loc) {
+                // Centers only go back to an E_ state when they move to their
+                // next state; the frame of boundary cells is skipped.
+                let (row, col) = (loc / settings.n_cols, loc % settings.n_cols);
+                let (Some(row), Some(col)) = (row.checked_sub(frame), col.checked_sub(frame)) else {
+                    continue;
+                };
+                let name = &components.state_names[&components.latest_states[loc]];
+                if let Some(state) = name.strip_prefix("E_").filter(|_| row % 3 == 1 && col % 3 == 1) {
+                    let (block_row, block_col) = (row / 3, col / 3);
+                    generations[block_row][block_col] += 1;
+                    let generation = generations[block_row][block_col];
+                    if generation <= n_generations {
+                        let expected_state = &automaton.states[expected[generation][block_row][block_col]].name;
+                        assert_eq!(state, expected_state, "block ({block_row}, {block_col}), generation {generation}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_greenberg_hastings() {
+        let automaton = CellularAutomaton::from_toml(GREENBERG_HASTINGS).unwrap();
+        let structured = automaton.compile().unwrap();
+        assert_eq!((structured.init_state.len(), structured.init_state[0].len()), (11, 11));
+        assert_eq!(structured.init_state[5][5], "E_a");
+        check_against_synchronous(&automaton, 4);
+    }
+
+    #[test]
+    fn test_life_table() {
+        // A blinker, with Life written out as a table rule, on a wrapping board.
+        let mut text = String::from(
+            "init_state = [[\"o\", \"x\", \"o\", \"o\"], [\"o\", \"x\", \"o\", \"o\"], [\"o\", \"x\", \"o\", \"o\"], [\"o\", \"o\", \"o\", \"o\"]]\n\
+             [[states]]\nname = \"o\"\ncolor = [0, 0, 0]\n[[states]]\nname = \"x\"\ncolor = [255, 255, 255]\n"
+        );
+        for pattern in 0..256u32 {
+            let alive = pattern.count_ones();
+            let neighbors: Vec<&str> = (0..8).map(|bit| if pattern & (1 << bit) != 0 { "\"x\"" } else { "\"o\"" }).collect();
+            for (from, survives) in [("o", alive == 3), ("x", alive == 2 || alive == 3)] {
+                let to = if survives { "x" } else { "o" };
+                text.push_str(&format!("[[table]]\nfrom = \"{from}\"\nneighbors = [{}]\nto = \"{to}\"\n", neighbors.join(", ")));
+            }
+        }
+        let automaton = CellularAutomaton::from_toml(&text).unwrap();
+        assert!(automaton.boundary.is_none());
+        check_against_synchronous(&automaton, 3);
+    }
+
+    #[test]
+    fn test_invalid_automata() {
+        let bad_state = GREENBERG_HASTINGS.replace("to = \"q\"", "to = \"x\"");
+        assert!(CellularAutomaton::from_toml(&bad_state).unwrap().compile().is_err());
+        let bad_count = GREENBERG_HASTINGS.replace("counts = [1, 2, 3, 4]", "counts = [5]");
+        assert!(CellularAutomaton::from_toml(&bad_count).unwrap().compile().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+
+use sdl2::pixels::Color;
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::state::SimulatorComponents;
+
+/// Writes the reaction network as a Graphviz (DOT) graph of which states turn
+/// into which. Each state is a node, filled with its color class's color and
+/// boxed in with the rest of its class. Each rule adds an edge from every
+/// reactant to the product that replaces it, labeled with the other reactant
+/// (if any) and the rule's rate; rules that leave a cell unchanged don't add an
+/// edge for it.
+pub fn network_to_dot(components: &SimulatorComponents) -> String {
+    let mut dot = String::from("digraph reactions {\n    node [style=filled];\n");
+
+    let mut states_by_class: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for state in components.state_ids.values() {
+        states_by_class.entry(components.state_colorclasses[state]).or_default().push(*state);
+    }
+    for (class_id, states) in states_by_class.iter_mut() {
+        states.sort();
+        dot.push_str(&format!(
+            "    subgraph cluster_{class_id} {{\n        label={};\n",
+            quote(&components.colorclass_names[*class_id])
+        ));
+        for state in states.iter() {
+            dot.push_str(&format!(
+                "        {} [fillcolor=\"{}\" fontcolor=\"{}\"];\n",
+                quote(&components.state_names[state]),
+                hex_color(components.colorclass_colors[*class_id]),
+                text_color(components.colorclass_colors[*class_id])
+            ));
+        }
+        dot.push_str("    }\n");
+    }
+
+    for (rxn, rate) in components.all_reactions.iter().zip(components.all_rxn_rates.iter()) {
+        let name = |state: usize| quote(&components.state_names[&state]);
+        let mut add_edge = |from: usize, to: usize, partner: Option<usize>| {
+            if from == to {
+                return;
+            }
+            let label = match partner {
+                Some(partner) => format!("+ {} ({rate})", components.state_names[&partner]),
+                None => format!("({rate})"),
+            };
+            dot.push_str(&format!("    {} -> {} [label={}];\n", name(from), name(to), quote(&label)));
+        };
+        add_edge(rxn.r1_num, rxn.p1_num, rxn.r2_num);
+        if let (Some(r2), Some(p2)) = (rxn.r2_num, rxn.p2_num) {
+            add_edge(r2, p2, Some(rxn.r1_num));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// Like network_to_dot, but with one node per color class. Edges are merged
+/// between each pair of classes and labeled with how many rules move a cell
+/// from one class to the other; changes within a class are left out.
+pub fn class_network_to_dot(components: &SimulatorComponents) -> String {
+    let mut dot = String::from("digraph reactions {\n    node [style=filled];\n");
+    let mut used_classes: Vec<usize> = components.state_ids.values().map(|state| components.state_colorclasses[state]).collect();
+    used_classes.sort();
+    used_classes.dedup();
+    for class_id in used_classes {
+        dot.push_str(&format!(
+            "    {} [fillcolor=\"{}\" fontcolor=\"{}\"];\n",
+            quote(&components.colorclass_names[class_id]),
+            hex_color(components.colorclass_colors[class_id]),
+            text_color(components.colorclass_colors[class_id])
+        ));
+    }
+
+    let mut edge_rules: BTreeMap<(usize, usize), BTreeSet<usize>> = BTreeMap::new();
+    for (rxn_idx, rxn) in components.all_reactions.iter().enumerate() {
+        let class = |state: usize| components.state_colorclasses[&state];
+        let mut changes = vec![(rxn.r1_num, rxn.p1_num)];
+        if let (Some(r2), Some(p2)) = (rxn.r2_num, rxn.p2_num) {
+            changes.push((r2, p2));
+        }
+        for (from, to) in changes {
+            if class(from) != class(to) {
+                edge_rules.entry((class(from), class(to))).or_default().insert(rxn_idx);
+            }
+        }
+    }
+    for ((from, to), rules) in edge_rules {
+        let label = if rules.len() == 1 { "1 rule".to_string() } else { format!("{} rules", rules.len()) };
+        dot.push_str(&format!(
+            "    {} -> {} [label={}];\n",
+            quote(&components.colorclass_names[from]),
+            quote(&components.colorclass_names[to]),
+            quote(&label)
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn hex_color(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+/// Black or white, whichever reads better on a node of this color.
+fn text_color(color: Color) -> &'static str {
+    let luminance = 0.299 * color.r as f32 + 0.587 * color.g as f32 + 0.114 * color.b as f32;
+    if luminance > 128.0 { "#000000" } else { "#ffffff" }
+}
+
+/// Runs `chitin dot`: writes a manifest's reaction network as a DOT graph, to
+/// a file or to stdout.
+pub fn export_dot_file(
+    input_file: PathBuf,
+    output_file: Option<PathBuf>,
+    by_class: bool,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, _, _) = match load_from_manifest(&manifest, scenario) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let dot = if by_class { class_network_to_dot(&components) } else { network_to_dot(&components) };
+    match output_file {
+        Some(path) => match fs::write(&path, dot) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("Couldn't write {path:?}: {err}");
+                1
+            }
+        },
+        None => {
+            print!("{dot}");
+            0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{class_network_to_dot, network_to_dot};
+
+    const MANIFEST: &str = "\
+!START_COLORMAP
+{Walls} W: (0,0,0)
+{Movers} A, B: (255,255,255)
+!END_COLORMAP
+!START_TRANSITION_RULES
+A + W -> B + W (2)
+B -> A (0.5)
+A + B -> W + W (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A W
+W B
+!END_INIT_STATE";
+
+    #[test]
+    fn test_state_graph() {
+        let (components, _) = settings_input::settings(MANIFEST).unwrap();
+        let dot = network_to_dot(&components);
+        assert!(dot.contains("\"A\" -> \"B\" [label=\"+ W (2)\"];"), "{dot}");
+        assert!(dot.contains("\"B\" -> \"A\" [label=\"(0.5)\"];"), "{dot}");
+        assert!(dot.contains("\"B\" -> \"W\" [label=\"+ A (1)\"];"), "{dot}");
+        // W is only a catalyst in the first rule, so it gets no self-loop.
+        assert!(!dot.contains("\"W\" -> \"W\""), "{dot}");
+        assert!(dot.contains("label=\"Movers\""), "{dot}");
+        assert!(dot.contains("\"A\" [fillcolor=\"#ffffff\" fontcolor=\"#000000\"];"), "{dot}");
+    }
+
+    #[test]
+    fn test_class_graph() {
+        let (components, _) = settings_input::settings(MANIFEST).unwrap();
+        let dot = class_network_to_dot(&components);
+        assert!(dot.contains("\"Movers\" -> \"Walls\" [label=\"1 rule\"];"), "{dot}");
+        assert_eq!(dot.matches(" -> ").count(), 1, "{dot}");
+    }
+}
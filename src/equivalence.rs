@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use itertools::Itertools;
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::model_checker::{StateSpace, DEFAULT_MAX_BOARDS};
+use crate::reactions::ReactionEvent;
+use crate::state::{Settings, SimulatorComponents};
+use crate::trace::format_trace;
+
+/// A board as seen from outside: each observed state replaced by the name it's
+/// observed as, and every other state blanked out, so boards from two
+/// different rule sets can be compared by name.
+pub type ObservedBoard = Vec<Option<String>>;
+
+/// What one rule set's states are observed as, by state name. States that
+/// aren't in it aren't observed.
+pub type Observation = HashMap<String, String>;
+
+fn observe(board: &[usize], components: &SimulatorComponents, observation: &Observation) -> ObservedBoard {
+    board
+        .iter()
+        .map(|state| observation.get(&components.state_names[state]).cloned())
+        .collect()
+}
+
+/// Reads the states to observe: comma-separated names the two rule sets
+/// share, or `first=second` pairs for a state they name differently, which is
+/// observed under its name in the first.
+pub fn parse_observation(spec: &str) -> Result<[Observation; 2], String> {
+    let mut observations = [Observation::new(), Observation::new()];
+    for item in spec.split(',').map(|item| item.trim()).filter(|item| !item.is_empty()) {
+        let (first, second) = item.split_once('=').map_or((item, item), |(first, second)| (first.trim(), second.trim()));
+        if first.is_empty() || second.is_empty() {
+            return Err(format!("{item:?} should be a state name or two joined by ="));
+        }
+        observations[0].insert(first.to_string(), first.to_string());
+        observations[1].insert(second.to_string(), first.to_string());
+    }
+    Ok(observations)
+}
+
+/// How two rule sets compared.
+#[derive(Debug)]
+pub enum Equivalence {
+    Same, // Both can reach exactly the same observed boards.
+    Different {
+        only_in: usize, // Which rule set (0 or 1) can reach the board.
+        board: ObservedBoard,
+        witness: Vec<ReactionEvent>, // How that rule set gets there.
+    },
+    Unknown, // No difference found, but at least one search gave up.
+}
+
+#[derive(Debug)]
+pub struct Comparison {
+    pub n_boards: [usize; 2],
+    pub result: Equivalence,
+}
+
+/// Checks whether two simulations can reach the same boards, looking only at
+/// the states in each side's observation: every other state is treated as a
+/// blank. Both have to start from the same observed board. Each side's
+/// reachable boards are enumerated (up to `max_boards`), so a difference
+/// comes with a shortest trace showing how one side reaches a board the other
+/// can't.
+pub fn compare_rule_sets(
+    simulations: [(&SimulatorComponents, &Settings); 2],
+    observations: [&Observation; 2],
+    max_boards: usize
+) -> Result<Comparison, String> {
+    let [(components_a, settings_a), (components_b, settings_b)] = simulations;
+    if (settings_a.n_rows, settings_a.n_cols) != (settings_b.n_rows, settings_b.n_cols) {
+        return Err(format!(
+            "the boards are different sizes ({}x{} and {}x{})",
+            settings_a.n_rows, settings_a.n_cols, settings_b.n_rows, settings_b.n_cols
+        ));
+    }
+    if observe(&components_a.current_states, components_a, observations[0]) != observe(&components_b.current_states, components_b, observations[1]) {
+        return Err("the manifests don't start from the same board".to_string());
+    }
+
+    let spaces: Vec<StateSpace> = simulations
+        .iter()
+        .map(|(components, settings)| StateSpace::search(components, settings, max_boards, |_, _| false).0)
+        .collect();
+    // Boards are found breadth first, so the first index for each observed
+    // board has the shortest path to it.
+    let mut reached: Vec<HashMap<ObservedBoard, usize>> = vec![HashMap::new(), HashMap::new()];
+    for (side, space) in spaces.iter().enumerate() {
+        for (board_idx, board) in space.boards.iter().enumerate() {
+            reached[side].entry(observe(board, simulations[side].0, observations[side])).or_insert(board_idx);
+        }
+    }
+
+    let n_boards = [spaces[0].boards.len(), spaces[1].boards.len()];
+    for side in 0..2 {
+        let other = 1 - side;
+        if spaces[other].gave_up {
+            continue;
+        }
+        let missing = reached[side]
+            .iter()
+            .filter(|(board, _)| !reached[other].contains_key(*board))
+            .min_by_key(|(_, board_idx)| **board_idx);
+        if let Some((board, board_idx)) = missing {
+            return Ok(Comparison {
+                n_boards,
+                result: Equivalence::Different { only_in: side, board: board.clone(), witness: spaces[side].path_to(*board_idx) },
+            });
+        }
+    }
+    let result = if spaces.iter().any(|space| space.gave_up) { Equivalence::Unknown } else { Equivalence::Same };
+    Ok(Comparison { n_boards, result })
+}
+
+/// Lays out an observed board as a grid, with `.` for the blanked-out cells.
+fn format_observed_board(board: &ObservedBoard, n_cols: usize) -> String {
+    let width = board.iter().flatten().map(|name| name.len()).max().unwrap_or(1);
+    board
+        .chunks(n_cols)
+        .map(|row| {
+            let cells: Vec<String> = row
+                .iter()
+                .map(|cell| format!("{:width$}", cell.as_deref().unwrap_or(".")))
+                .collect();
+            format!("    {}\n", cells.join(" ").trim_end())
+        })
+        .collect()
+}
+
+/// Runs `chitin equiv`: compares two manifests' reachable boards, looking
+/// only at the states in `observe` (as read by `parse_observation`; by
+/// default, every state the two have in common). Returns the process exit
+/// code: 0 if they match, 1 if they differ or the search gave up, 2 for bad
+/// arguments.
+pub fn compare_manifest_files(
+    input_files: [PathBuf; 2],
+    observe: Option<&str>,
+    witness_file: Option<PathBuf>,
+    max_boards: Option<&str>,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let mut simulations = Vec::new();
+    for input_file in input_files.iter() {
+        let manifest = match read_manifest_file(input_file.clone(), search_path) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                eprintln!("error: couldn't read {input_file:?}: {err}");
+                return 1;
+            }
+        };
+        match load_from_manifest(&manifest, scenario) {
+            Ok((components, settings, _)) => simulations.push((components, settings)),
+            Err(err) => {
+                eprintln!("error: couldn't load {input_file:?}: {err}");
+                return 1;
+            }
+        }
+    }
+    let observations = match observe.map(parse_observation) {
+        Some(Ok(observations)) => observations,
+        Some(Err(err)) => {
+            eprintln!("error: --observe: {err}");
+            return 2;
+        },
+        None => {
+            let shared: Observation = simulations[0].0.state_ids
+                .keys()
+                .filter(|name| simulations[1].0.state_ids.contains_key(*name))
+                .map(|name| (name.clone(), name.clone()))
+                .collect();
+            [shared.clone(), shared]
+        }
+    };
+    let max_boards = match max_boards.map(|n| n.parse::<usize>()) {
+        None => DEFAULT_MAX_BOARDS,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("error: --max-boards should be a whole number");
+            return 2;
+        }
+    };
+
+    let pair = [(&simulations[0].0, &simulations[0].1), (&simulations[1].0, &simulations[1].1)];
+    let comparison = match compare_rule_sets(pair, [&observations[0], &observations[1]], max_boards) {
+        Ok(comparison) => comparison,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 2;
+        }
+    };
+    println!(
+        "Observing {}; searched {} and {} boards.",
+        observations[0].values().sorted().dedup().join(", "),
+        comparison.n_boards[0],
+        comparison.n_boards[1]
+    );
+    match comparison.result {
+        Equivalence::Same => {
+            println!("Equivalent: both reach the same observed boards.");
+            0
+        },
+        Equivalence::Unknown => {
+            println!("No difference found, but the search gave up; try a larger --max-boards.");
+            1
+        },
+        Equivalence::Different { only_in, board, witness } => {
+            let (components, settings) = &simulations[only_in];
+            println!(
+                "Different: only {:?} can reach this board ({} event(s)):",
+                input_files[only_in], witness.len()
+            );
+            print!("{}", format_observed_board(&board, settings.n_cols));
+            let trace = format_trace(&witness, components, settings);
+            match witness_file {
+                Some(path) => match fs::write(&path, trace) {
+                    Ok(()) => println!("Wrote the trace to {path:?}; replay it on {:?} with --trace.", input_files[only_in]),
+                    Err(err) => eprintln!("Couldn't write {path:?}: {err}"),
+                },
+                None => print!("{trace}"),
+            }
+            1
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{compare_rule_sets, parse_observation, Equivalence};
+
+    fn manifest(rules: &str) -> String {
+        format!("!START_TRANSITION_RULES\n{rules}\n!END_TRANSITION_RULES\n!START_INIT_STATE\nT E E\nE E E\n!END_INIT_STATE")
+    }
+
+    #[test]
+    fn test_equivalent_rule_sets() {
+        let (components_a, settings_a) = settings_input::settings(&manifest("T + E -> E + T (1)")).unwrap();
+        let (components_b, settings_b) = settings_input::settings(&manifest("E + T -> T + E (2)")).unwrap();
+        let [observed_a, observed_b] = parse_observation("T").unwrap();
+        let comparison = compare_rule_sets([(&components_a, &settings_a), (&components_b, &settings_b)], [&observed_a, &observed_b], 100).unwrap();
+        assert!(matches!(comparison.result, Equivalence::Same), "{comparison:?}");
+        assert_eq!(comparison.n_boards, [6, 6]);
+    }
+
+    #[test]
+    fn test_renamed_states() {
+        // The same token walk, with the token and the blank named differently.
+        let (components_a, settings_a) = settings_input::settings(&manifest("T + E -> E + T (1)")).unwrap();
+        let (components_b, settings_b) = settings_input::settings(
+            "!START_TRANSITION_RULES\nTok + Gap -> Gap + Tok (1)\n!END_TRANSITION_RULES\n\
+             !START_INIT_STATE\nTok Gap Gap\nGap Gap Gap\n!END_INIT_STATE"
+        ).unwrap();
+        let [observed_a, observed_b] = parse_observation("T=Tok").unwrap();
+        let comparison = compare_rule_sets([(&components_a, &settings_a), (&components_b, &settings_b)], [&observed_a, &observed_b], 100).unwrap();
+        assert!(matches!(comparison.result, Equivalence::Same), "{comparison:?}");
+
+        // Watching the blanks too, under either name.
+        let [observed_a, observed_b] = parse_observation("T=Tok, E=Gap").unwrap();
+        let comparison = compare_rule_sets([(&components_a, &settings_a), (&components_b, &settings_b)], [&observed_a, &observed_b], 100).unwrap();
+        assert!(matches!(comparison.result, Equivalence::Same), "{comparison:?}");
+
+        // By name alone, the second board has nothing the first would see.
+        let [observed_a, observed_b] = parse_observation("T").unwrap();
+        assert!(compare_rule_sets([(&components_a, &settings_a), (&components_b, &settings_b)], [&observed_a, &observed_b], 100).is_err());
+        assert!(parse_observation("T=").is_err());
+    }
+
+    #[test]
+    fn test_distinguishing_trace() {
+        // The second rule set moves the token in two steps, and the board in
+        // between has no token on it.
+        let (components_a, settings_a) = settings_input::settings(&manifest("T + E -> E + T (1)")).unwrap();
+        let (components_b, settings_b) = settings_input::settings(&manifest("T + E -> H + F (1)\nH + F -> E + T (1)")).unwrap();
+        let [observed_a, observed_b] = parse_observation("T").unwrap();
+        let observations = [&observed_a, &observed_b];
+        let comparison = compare_rule_sets([(&components_a, &settings_a), (&components_b, &settings_b)], observations, 100).unwrap();
+        match comparison.result {
+            Equivalence::Different { only_in, board, witness } => {
+                assert_eq!(only_in, 1);
+                assert!(board.iter().all(|cell| cell.is_none()));
+                assert_eq!(witness.len(), 1);
+            },
+            result => panic!("expected a difference, got {result:?}"),
+        }
+
+        // Or with a search too small to tell.
+        let comparison = compare_rule_sets([(&components_a, &settings_a), (&components_b, &settings_b)], observations, 1).unwrap();
+        assert!(matches!(comparison.result, Equivalence::Unknown), "{comparison:?}");
+    }
+}
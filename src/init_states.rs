@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use sdl2::image::LoadSurface;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::surface::Surface;
+
+use crate::input_parsers::ParsedManifest;
+
+/// A fully spelled-out initial state: one state name per cell, row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitGrid {
+    pub states: Vec<String>,
+    pub n_rows: usize,
+    pub n_cols: usize,
+}
+
+/// Somewhere an initial state can come from. Everything but a plain grid gets
+/// expanded into an InitGrid before the board is set up.
+#[derive(Debug, Clone)]
+pub enum InitStateSource {
+    Grid(InitGrid),
+    Image(String), // Path to an image, one pixel per cell.
+    Generated(Vec<InitCommand>), // Drawing commands, run in order.
+}
+
+/// One step in building an initial state procedurally. Coordinates are
+/// (row, column), counted from 0 at the top left; anything drawn off the edge
+/// of the surface is clipped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InitCommand {
+    Size { n_rows: usize, n_cols: usize },
+    Fill(String),
+    Random { weights: Vec<(String, f64)>, seed: Option<u64> },
+    Rect { state: String, row: i64, col: i64, height: i64, width: i64 },
+    Line { state: String, from: (i64, i64), to: (i64, i64) },
+    Circle { state: String, row: i64, col: i64, radius: i64 },
+    Cell { state: String, row: i64, col: i64 },
+    Stamp { pattern: String, row: i64, col: i64, rotation: u32, mirror: bool },
+}
+
+/// A small named grid that init generators can stamp onto the surface. Cells
+/// written as `.` are transparent and leave whatever is underneath alone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub cells: Vec<Option<String>>,
+    pub n_rows: usize,
+    pub n_cols: usize,
+}
+
+impl Pattern {
+    /// The pattern's cells as (row offset, column offset, state), after
+    /// mirroring left-to-right (if asked) and then rotating clockwise by
+    /// `rotation` degrees.
+    pub fn placed_cells(&self, rotation: u32, mirror: bool) -> Vec<(i64, i64, &String)> {
+        let mut placed = Vec::new();
+        for (idx, cell) in self.cells.iter().enumerate() {
+            let Some(state) = cell else {
+                continue;
+            };
+            let (h, w) = (self.n_rows as i64, self.n_cols as i64);
+            let (r, mut c) = ((idx / self.n_cols) as i64, (idx % self.n_cols) as i64);
+            if mirror {
+                c = w - 1 - c;
+            }
+            let (r, c) = match rotation {
+                90 => (c, h - 1 - r),
+                180 => (h - 1 - r, w - 1 - c),
+                270 => (w - 1 - c, r),
+                _ => (r, c)
+            };
+            placed.push((r, c, state));
+        }
+        placed
+    }
+}
+
+impl InitStateSource {
+    pub fn expand(&self, manifest: &ParsedManifest) -> Result<InitGrid, String> {
+        match self {
+            InitStateSource::Grid(grid) => Ok(grid.clone()),
+            InitStateSource::Image(path) => {
+                let (width, height, pixels) = load_image_pixels(Path::new(path))?;
+                let states = image_to_states(width, &pixels, &image_color_map(manifest)?)?;
+                Ok(InitGrid { states, n_rows: height, n_cols: width })
+            },
+            InitStateSource::Generated(commands) => {
+                let default_seed = manifest.variables
+                    .iter()
+                    .rev()
+                    .find(|(key, _)| key == "rng_seed")
+                    .and_then(|(_, value)| value.parse::<u64>().ok());
+                run_init_commands(commands, &manifest.patterns, default_seed)
+            }
+        }
+    }
+}
+
+/// Builds an initial state by running drawing commands in order. The first
+/// command has to be a `size`, and every cell has to end up with a state.
+/// Random fills without their own seed use `default_seed`, if there is one.
+pub fn run_init_commands(
+    commands: &[InitCommand],
+    patterns: &HashMap<String, Pattern>,
+    default_seed: Option<u64>
+) -> Result<InitGrid, String> {
+    let Some(InitCommand::Size { n_rows, n_cols }) = commands.first() else {
+        return Err("an init generator has to start with a size command".to_string());
+    };
+    let (n_rows, n_cols) = (*n_rows, *n_cols);
+    let mut cells: Vec<Option<String>> = vec![None; n_rows * n_cols];
+    let mut set_cell = |row: i64, col: i64, state: &String| {
+        if 0 <= row && row < n_rows as i64 && 0 <= col && col < n_cols as i64 {
+            cells[row as usize * n_cols + col as usize] = Some(state.clone());
+        }
+    };
+
+    for command in commands[1..].iter() {
+        match command {
+            InitCommand::Size { .. } => return Err("an init generator can only have one size command".to_string()),
+            InitCommand::Fill(state) => {
+                for row in 0..n_rows as i64 {
+                    for col in 0..n_cols as i64 {
+                        set_cell(row, col, state);
+                    }
+                }
+            },
+            InitCommand::Random { weights, seed } => {
+                let distribution = WeightedIndex::new(weights.iter().map(|(_, weight)| *weight))
+                    .map_err(|err| format!("bad random fill weights {weights:?}: {err}"))?;
+                let mut rng = match seed.or(default_seed) {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy()
+                };
+                for row in 0..n_rows as i64 {
+                    for col in 0..n_cols as i64 {
+                        set_cell(row, col, &weights[distribution.sample(&mut rng)].0);
+                    }
+                }
+            },
+            InitCommand::Rect { state, row, col, height, width } => {
+                for r in *row..row + height {
+                    for c in *col..col + width {
+                        set_cell(r, c, state);
+                    }
+                }
+            },
+            InitCommand::Line { state, from, to } => {
+                for (r, c) in line_cells(*from, *to) {
+                    set_cell(r, c, state);
+                }
+            },
+            InitCommand::Circle { state, row, col, radius } => {
+                for r in row - radius..=row + radius {
+                    for c in col - radius..=col + radius {
+                        if (r - row).pow(2) + (c - col).pow(2) <= radius.pow(2) {
+                            set_cell(r, c, state);
+                        }
+                    }
+                }
+            },
+            InitCommand::Cell { state, row, col } => set_cell(*row, *col, state),
+            InitCommand::Stamp { pattern, row, col, rotation, mirror } => {
+                let Some(pattern) = patterns.get(pattern) else {
+                    return Err(format!("there's no pattern named {pattern:?} to stamp"));
+                };
+                for (r, c, state) in pattern.placed_cells(*rotation, *mirror) {
+                    set_cell(row + r, col + c, state);
+                }
+            },
+        }
+    }
+
+    let states = cells
+        .into_iter()
+        .enumerate()
+        .map(|(idx, cell)| cell.ok_or_else(|| format!(
+            "cell at row {}, column {} was never given a state; try starting with a fill",
+            idx / n_cols, idx % n_cols
+        )))
+        .collect::<Result<Vec<String>, String>>()?;
+    Ok(InitGrid { states, n_rows, n_cols })
+}
+
+/// The cells on a straight line between two (row, column) cells, both ends
+/// included, in order, by Bresenham's line algorithm.
+pub fn line_cells(from: (i64, i64), to: (i64, i64)) -> Vec<(i64, i64)> {
+    let (mut r, mut c) = from;
+    let (dr, dc) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let (step_r, step_c) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+    let mut error = dr + dc;
+    let mut cells = Vec::new();
+    loop {
+        cells.push((r, c));
+        if (r, c) == to {
+            return cells;
+        }
+        let doubled_error = 2 * error;
+        if doubled_error >= dc {
+            error += dc;
+            r += step_r;
+        }
+        if doubled_error <= dr {
+            error += dr;
+            c += step_c;
+        }
+    }
+}
+
+/// Works out which state each image color stands for. Colors listed in an
+/// image colormap block win; otherwise a colormap class that holds exactly one
+/// state maps its color back to that state.
+pub fn image_color_map(manifest: &ParsedManifest) -> Result<HashMap<(u8, u8, u8), String>, String> {
+    let mut inverse: HashMap<(u8, u8, u8), Vec<&String>> = HashMap::new();
+    for (_, color, states) in manifest.color_classes.iter() {
+        inverse.entry(color.rgb()).or_default().extend(states.iter());
+    }
+    let explicit: HashMap<(u8, u8, u8), &String> = manifest.image_colors
+        .iter()
+        .map(|(state, color)| (color.rgb(), state))
+        .collect();
+
+    let mut color_map = HashMap::new();
+    for (rgb, mut states) in inverse {
+        states.sort();
+        states.dedup();
+        if states.len() == 1 {
+            color_map.insert(rgb, states[0].clone());
+        }
+    }
+    for (rgb, state) in explicit {
+        color_map.insert(rgb, state.clone());
+    }
+    if color_map.is_empty() {
+        return Err("no colors to read an initial state image with; add a colormap or an image colormap block".to_string());
+    }
+    Ok(color_map)
+}
+
+/// Converts pixels (row by row, `width` to a row) to state names.
+pub fn image_to_states(width: usize, pixels: &[Color], color_map: &HashMap<(u8, u8, u8), String>) -> Result<Vec<String>, String> {
+    pixels
+        .iter()
+        .enumerate()
+        .map(|(idx, pixel)| match color_map.get(&pixel.rgb()) {
+            Some(state) => Ok(state.clone()),
+            None => Err(format!(
+                "pixel at row {}, column {} has color {:?}, which isn't mapped to any state",
+                idx / width, idx % width, pixel.rgb()
+            ))
+        })
+        .collect()
+}
+
+/// Reads an image file into (width, height, pixels).
+fn load_image_pixels(path: &Path) -> Result<(usize, usize, Vec<Color>), String> {
+    let surface = Surface::from_file(path)
+        .and_then(|surface| surface.convert_format(PixelFormatEnum::RGB24))
+        .map_err(|err| format!("couldn't read initial state image {path:?}: {err}"))?;
+    let (width, height, pitch) = (surface.width() as usize, surface.height() as usize, surface.pitch() as usize);
+    let mut pixels = Vec::with_capacity(width * height);
+    surface.with_lock(|bytes| {
+        for row in 0..height {
+            for col in 0..width {
+                let offset = row * pitch + 3 * col;
+                pixels.push(Color::RGB(bytes[offset], bytes[offset + 1], bytes[offset + 2]));
+            }
+        }
+    });
+    Ok((width, height, pixels))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+
+    use crate::input_parsers::settings_input;
+    use crate::manifest_lexer::normalize_manifest_text;
+
+    use super::{image_color_map, image_to_states, InitStateSource};
+
+    fn generated_grid(manifest_text: &str) -> Vec<String> {
+        let manifest = settings_input::manifest(manifest_text).unwrap();
+        assert!(matches!(manifest.init_states[0], InitStateSource::Generated(_)));
+        let grid = manifest.init_states[0].expand(&manifest).unwrap();
+        grid.states
+    }
+
+    #[test]
+    fn test_image_colors() {
+        let manifest = settings_input::manifest("\
+!START_COLORMAP
+Q: (230,230,230)
+A: (255,0,0)
+{Wires} B, C: (0,0,0)
+!END_COLORMAP
+!START_IMAGE_COLORMAP
+C: (0,0,0)
+!END_IMAGE_COLORMAP").unwrap();
+        let color_map = image_color_map(&manifest).unwrap();
+        let pixels = [
+            Color::RGB(230, 230, 230), Color::RGB(255, 0, 0),
+            Color::RGB(0, 0, 0), Color::RGB(230, 230, 230),
+        ];
+        assert_eq!(image_to_states(2, &pixels, &color_map).unwrap(), vec!["Q", "A", "C", "Q"]);
+        assert!(image_to_states(2, &[Color::RGB(1, 2, 3)], &color_map).is_err());
+    }
+
+    #[test]
+    fn test_init_generator_shapes() {
+        let states = generated_grid("\
+!START_INIT_GENERATOR
+size 5 5
+fill Q
+# Shapes draw over each other in order.
+rect B 0 0 2 5
+line A 4 0 0 4
+circle R 4 4 1
+cell X 3 1
+!END_INIT_GENERATOR");
+        assert_eq!(states.join(" "), "\
+B B B B A \
+B B B A B \
+Q Q A Q Q \
+Q X Q Q R \
+A Q Q R R");
+    }
+
+    #[test]
+    fn test_pattern_stamping() {
+        let states = generated_grid("\
+!START_PATTERN corner
+A B
+C .
+!END_PATTERN
+!START_INIT_GENERATOR
+size 4 6
+fill I
+stamp corner 0 0
+stamp corner 0 4 mirror
+stamp corner 2 0 rotate=90
+stamp corner 2 4 rotate=180
+!END_INIT_GENERATOR");
+        assert_eq!(states.join(" "), "\
+A B I I B A \
+C I I I I C \
+C A I I I C \
+I B I I B A");
+    }
+
+    #[test]
+    fn test_pattern_with_gaps() {
+        // Blank and comment lines inside a pattern aren't rows.
+        let text = normalize_manifest_text("\
+!START_PATTERN corner
+A B
+
+# the bottom row
+C .
+!END_PATTERN
+!START_INIT_GENERATOR
+size 2 2
+fill I
+stamp corner 0 0
+!END_INIT_GENERATOR").unwrap();
+        assert_eq!(generated_grid(&text).join(" "), "A B C I");
+        assert!(settings_input::manifest("!START_PATTERN empty\n\n!END_PATTERN").is_err());
+    }
+
+    #[test]
+    fn test_init_random() {
+        let manifest_text = "!INIT_RANDOM rows=20 cols=30 Q:0.9 A:0.1 seed=7";
+        let states = generated_grid(manifest_text);
+        assert_eq!(states.len(), 600);
+        assert!(states.iter().all(|s| s == "Q" || s == "A"));
+        assert!(states.iter().filter(|s| *s == "Q").count() > 450);
+        assert_eq!(states, generated_grid(manifest_text));
+    }
+
+    #[test]
+    fn test_empty_board() {
+        for text in ["!START_INIT_GENERATOR\nsize 0 5\nfill Q\n!END_INIT_GENERATOR", "!INIT_RANDOM rows=0 cols=5 Q:1"] {
+            assert!(settings_input::manifest(text).is_err(), "{text}");
+        }
+    }
+}
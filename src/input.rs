@@ -1,12 +1,12 @@
-use std::fs::File;
-use std::io::{BufRead, self};
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use native_dialog::FileDialog;
-use priq::PriorityQueue;
 
 use crate::state::{Settings, SimulatorComponents, SimulatorState};
-use crate::input_parsers::settings_input;
+use crate::input_parsers::{build_simulation, settings_input, ParsedManifest};
+use crate::manifest_lexer::normalize_manifest_text;
+use crate::structured_manifest::{ManifestFormat, StructuredManifest};
 
 pub fn get_input_file() -> PathBuf {
     let path = FileDialog::new()
@@ -18,51 +18,174 @@ pub fn get_input_file() -> PathBuf {
     }
 }
 
-pub fn read_and_splice_settings_file(input_file: PathBuf) -> String {
-    let file = match File::open(&input_file) {
-        Err(why) => panic!("Couldn't open {input_file:?}: {why}", ),
-        Ok(contents) => contents
-    };
-    let lines = io::BufReader::new(file).lines();
-    let mut all_lines_string = String::new();
-    for line in lines {
-        let line = line.unwrap();
-        if line.starts_with("!INCLUDE") {
-            let mut included_path = input_file.clone();
-            included_path.pop();
-            included_path.push(&line.trim()[9..]);
-            all_lines_string.push_str(&read_and_splice_settings_file(included_path));
+pub fn get_output_file() -> Option<PathBuf> {
+    FileDialog::new()
+        .add_filter("Manifest", &["txt", "toml", "json"])
+        .show_save_single_file()
+        .unwrap()
+}
+
+pub fn get_image_output_file() -> Option<PathBuf> {
+    FileDialog::new()
+        .add_filter("PNG image", &["png"])
+        .show_save_single_file()
+        .unwrap()
+}
+
+pub fn get_waveform_output_file() -> Option<PathBuf> {
+    FileDialog::new()
+        .add_filter("Value change dump", &["vcd"])
+        .show_save_single_file()
+        .unwrap()
+}
+
+/// Environment variable listing extra directories to look in for `!INCLUDE`d
+/// files, separated like PATH.
+pub const INCLUDE_PATH_VAR: &str = "CHITIN_INCLUDE_PATH";
+
+/// The directories searched for `!INCLUDE`d files that aren't next to the
+/// manifest including them: `--include-path` directories first, then the ones
+/// in CHITIN_INCLUDE_PATH.
+pub fn include_search_path(flag_dirs: &[&String]) -> Vec<PathBuf> {
+    let mut search_path: Vec<PathBuf> = flag_dirs.iter().map(PathBuf::from).collect();
+    if let Some(env_dirs) = std::env::var_os(INCLUDE_PATH_VAR) {
+        search_path.extend(std::env::split_paths(&env_dirs));
+    }
+    search_path
+}
+
+/// Manifest text with every `!INCLUDE` spliced in, along with where each line
+/// came from.
+pub struct SplicedManifest {
+    pub text: String,
+    pub source_map: Vec<(PathBuf, usize)>, // (file, line number) for each line of text
+}
+
+impl SplicedManifest {
+    /// Describes a (1-based) line of the spliced text as `file:line`.
+    pub fn locate(&self, line: usize) -> String {
+        match self.source_map.get(line.saturating_sub(1)) {
+            Some((file, file_line)) => format!("{}:{file_line}", file.display()),
+            None => format!("line {line}"),
+        }
+    }
+}
+
+/// Reads a text manifest, replacing each `!INCLUDE` line with the contents of
+/// the file it names. Included files are looked for next to the file that
+/// includes them, then in each directory of `search_path`.
+pub fn splice_manifest_file(input_file: &Path, search_path: &[PathBuf]) -> Result<SplicedManifest, String> {
+    let mut spliced = SplicedManifest { text: String::new(), source_map: Vec::new() };
+    splice_into(&mut spliced, input_file, search_path, &mut Vec::new())?;
+    Ok(spliced)
+}
+
+fn splice_into(
+    spliced: &mut SplicedManifest,
+    input_file: &Path,
+    search_path: &[PathBuf],
+    include_chain: &mut Vec<(PathBuf, PathBuf)> // (canonical path, path as written) of each file being spliced
+) -> Result<(), String> {
+    let canonical_file = fs::canonicalize(input_file)
+        .map_err(|err| format!("couldn't open {}: {err}", input_file.display()))?;
+    if let Some(cycle_start) = include_chain.iter().position(|(file, _)| *file == canonical_file) {
+        let cycle: Vec<String> = include_chain[cycle_start..]
+            .iter()
+            .map(|(_, file)| file.as_path())
+            .chain(std::iter::once(input_file))
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(format!("include cycle: {}", cycle.join(" -> ")));
+    }
+    let contents = read_file(input_file)?.replace("\r\n", "\n").replace('\r', "\n");
+    let directory = input_file.parent().unwrap_or(Path::new("")).to_path_buf();
+
+    include_chain.push((canonical_file, input_file.to_path_buf()));
+    for (line_idx, line) in contents.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let trimmed = line.trim();
+        if let Some(include) = trimmed.strip_prefix("!INCLUDE") {
+            let name = include_target(include);
+            let included_file = std::iter::once(&directory)
+                .chain(search_path.iter())
+                .map(|dir| dir.join(&name))
+                .find(|path| path.is_file())
+                .ok_or_else(|| format!(
+                    "{}:{line_number}: couldn't find included file {name:?}", input_file.display()
+                ))?;
+            splice_into(spliced, &included_file, search_path, include_chain)
+                .map_err(|err| format!("{err}\n  included from {}:{line_number}", input_file.display()))?;
+        }
+        else if let Some(image) = trimmed.strip_prefix("!INIT_STATE_IMAGE") {
+            // Image paths are relative to the manifest that names them. The
+            // path is quoted so normalizing the text leaves its spaces alone.
+            let image_path = directory.join(include_target(image));
+            spliced.text.push_str(&format!("!INIT_STATE_IMAGE \"{}\"\n", image_path.display()));
+            spliced.source_map.push((input_file.to_path_buf(), line_number));
         }
         else {
-            all_lines_string.push_str(&line);
-            all_lines_string.push('\n');
+            spliced.text.push_str(line);
+            spliced.text.push('\n');
+            spliced.source_map.push((input_file.to_path_buf(), line_number));
         }
     }
-    println!("Reading out the following text from file {input_file:?}");
-    println!("{}", &all_lines_string);
-    println!("<End of text>");
-    all_lines_string
+    include_chain.pop();
+    Ok(())
 }
 
-pub fn load_from_file(input_file: PathBuf) -> (SimulatorComponents, Settings, SimulatorState) {
-    let (components , settings) = 
-        settings_input::settings(&read_and_splice_settings_file(input_file))
-        .unwrap();
-    // println!("File contents:\n{:?}", &fs::read_to_string(&input_file).unwrap());
-
-    let global_state= SimulatorState {
-        last_states: Vec::new(),
-        rxn_queue: PriorityQueue::new(),
-        speedup: 1.0,
-        current_t: 0.0,
-        next_rxn_event: 0,
-        pressed_button_idx: components.button_boxes.len(),
-        is_playing: false,
-        run_direction_forward: true,
-        tick: false
+/// The path after `!INCLUDE` or `!INIT_STATE_IMAGE`, which may be quoted and
+/// may be followed by a comment.
+fn include_target(rest: &str) -> String {
+    let rest = rest.trim();
+    match rest.strip_prefix('"').and_then(|quoted| quoted.split_once('"')) {
+        Some((path, _)) => path.to_string(),
+        None => rest.split(" #").next().unwrap_or("").trim().to_string(),
+    }
+}
+
+/// Reads a manifest in whichever format its extension says: TOML, JSON, or
+/// (for anything else) the line-based text format. Text manifests can
+/// `!INCLUDE` files from `search_path`, and their errors name the file and
+/// line they come from.
+pub fn read_manifest_file(input_file: PathBuf, search_path: &[PathBuf]) -> Result<ParsedManifest, String> {
+    let structured = match ManifestFormat::from_path(&input_file) {
+        ManifestFormat::Text => {
+            let spliced = splice_manifest_file(&input_file, search_path)?;
+            let text = normalize_manifest_text(&spliced.text)
+                .map_err(|(line, err)| format!("{}: {err}", spliced.locate(line)))?;
+            return settings_input::manifest(&text).map_err(|err| format!(
+                "{}:{}: expected {}", spliced.locate(err.location.line), err.location.column, err.expected
+            ));
+        },
+        ManifestFormat::Toml => StructuredManifest::from_toml(&read_file(&input_file)?)?,
+        ManifestFormat::Json => StructuredManifest::from_json(&read_file(&input_file)?)?,
     };
+    structured.to_parsed()
+}
+
+fn read_file(input_file: &Path) -> Result<String, String> {
+    fs::read_to_string(input_file).map_err(|err| format!("couldn't open {}: {err}", input_file.display()))
+}
 
-    (components, settings, global_state)
+pub fn parse_manifest_file(input_file: PathBuf, search_path: &[PathBuf]) -> ParsedManifest {
+    match read_manifest_file(input_file, search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => panic!("Couldn't read manifest: {err}")
+    }
+}
+
+/// Builds a simulation from a parsed manifest, using the named scenario (or
+/// the manifest's default one).
+pub fn load_from_manifest(manifest: &ParsedManifest, scenario: Option<&str>) -> Result<(SimulatorComponents, Settings, SimulatorState), String> {
+    let manifest = manifest.with_scenario(scenario)?;
+    let (components, settings) = build_simulation(&manifest)?;
+
+    let global_state = SimulatorState::new(
+        settings.rng_seed.map(|seed| seed as u64),
+        components.button_boxes.len()
+    );
+
+    Ok((components, settings, global_state))
 }
 
 
@@ -72,8 +195,15 @@ mod tests {
     use std::path::PathBuf;
     use std::matches;
     use crate::state::SurfaceGeometry;
+    use crate::settings_schema::parse_settings;
+    use crate::input_parsers::settings_input;
+
+    use crate::state::{Settings, SimulatorComponents, SimulatorState};
+    use super::{load_from_manifest, parse_manifest_file, read_manifest_file, splice_manifest_file};
 
-    use super::load_from_file;
+    fn load_from_file(input_file: PathBuf) -> (SimulatorComponents, Settings, SimulatorState) {
+        load_from_manifest(&parse_manifest_file(input_file, &[]), None).unwrap()
+    }
 
     #[test]
     fn test_basic_settings_input() {
@@ -133,4 +263,71 @@ mod tests {
         let b_idx = *sim_components.state_ids.get("B").unwrap();
         assert_eq!(sim_components.current_states, vec![a_idx, a_idx, a_idx, a_idx, b_idx, a_idx, a_idx, a_idx, a_idx]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_scenarios() {
+        let manifest = parse_manifest_file(PathBuf::from("test_resources/manifests/scenario_manifest.txt"), &[]);
+        assert_eq!(manifest.scenario_names(), vec!["small", "fast"]);
+
+        // With no scenario picked, the first one is used.
+        let (sim_components, settings, _) = load_from_manifest(&manifest, None).unwrap();
+        assert_eq!(settings.n_rows, 2);
+        assert_eq!(settings.speedup_factor, 1.0);
+        assert_eq!(sim_components.all_rxn_rates, vec![1.0, 0.5]);
+
+        let (sim_components, settings, _) = load_from_manifest(&manifest, Some("fast")).unwrap();
+        assert_eq!(settings.n_rows, 3);
+        assert_eq!(settings.speedup_factor, 10.0);
+        assert_eq!(sim_components.all_rxn_rates, vec![4.0, 0.5, 2.0]);
+
+        // The scenario's speedup_factor replaces the manifest's rather than repeating it.
+        let fast = manifest.with_scenario(Some("fast")).unwrap();
+        let (_, warnings) = parse_settings(&fast.variables).unwrap();
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn test_init_state_image() {
+        // The image's file name has two spaces in it, written without quotes.
+        let (sim_components, settings, _) = load_from_file(PathBuf::from("test_resources/manifests/init_states/image_manifest.txt"));
+        assert_eq!((settings.n_rows, settings.n_cols), (2, 3));
+        let names: Vec<&str> = sim_components.current_states.iter().map(|state| &sim_components.state_names[state][..]).collect();
+        assert_eq!(names, vec!["Q", "A", "Q", "B", "Q", "A"]);
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let includes = PathBuf::from("test_resources/manifests/includes");
+        let err = read_manifest_file(includes.join("cycle_a.txt"), &[]).unwrap_err();
+        assert!(err.starts_with("include cycle: "), "{err}");
+        assert!(err.contains("cycle_a.txt -> test_resources/manifests/includes/cycle_b.txt -> "), "{err}");
+
+        let err = read_manifest_file(includes.join("uses_library.txt"), &[]).unwrap_err();
+        assert!(err.contains("uses_library.txt:3: couldn't find included file \"library_rules.txt\""), "{err}");
+
+        // Parse errors point into the included file, not the spliced text.
+        let err = read_manifest_file(includes.join("broken_include.txt"), &[]).unwrap_err();
+        assert!(err.starts_with("test_resources/manifests/includes/library/broken_rules.txt:3:"), "{err}");
+    }
+
+    #[test]
+    fn test_include_search_path() {
+        let includes = PathBuf::from("test_resources/manifests/includes");
+        let search_path = vec![includes.join("library")];
+        let spliced = splice_manifest_file(&includes.join("uses_library.txt"), &search_path).unwrap();
+        assert_eq!(spliced.locate(4), "test_resources/manifests/includes/library/library_rules.txt:2");
+        assert_eq!(spliced.locate(7), "test_resources/manifests/includes/uses_library.txt:5");
+
+        let (sim_components, settings, _) = load_from_manifest(&parse_manifest_file(includes.join("uses_library.txt"), &search_path), None).unwrap();
+        assert_eq!(settings.speedup_factor, 2.0);
+        assert_eq!(sim_components.all_rxn_rates, vec![1.0]);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let two_boards = settings_input::manifest("!START_INIT_STATE\nA\n!END_INIT_STATE\n!START_INIT_STATE\nB\n!END_INIT_STATE").unwrap();
+        assert!(load_from_manifest(&two_boards, None).unwrap_err().starts_with("too many initial states"));
+        assert!(load_from_manifest(&settings_input::manifest("speedup_factor = fast").unwrap(), None).is_err());
+        assert!(load_from_manifest(&two_boards, Some("missing")).is_err());
+    }
+}
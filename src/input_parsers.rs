@@ -2,8 +2,13 @@ use std::collections::{HashMap, HashSet};
 
 use sdl2::pixels::Color;
 
-use crate::state::{SimulatorComponents, Settings, SurfaceGeometry};
+use crate::state::{SimulatorComponents, Settings};
+use crate::settings_schema::{find_setting, parse_settings};
 use crate::reactions::ReactionDescription;
+use crate::init_states::{InitCommand, InitGrid, InitStateSource, Pattern};
+use crate::analysis::Invariant;
+use crate::agents::AgentRule;
+use crate::probes::{Level, ProbeSpec, StateOrClass};
 
 #[derive(Debug)]
 enum InputBlock {
@@ -13,140 +18,310 @@ enum InputBlock {
     SingleTransitionRule(ReactionDescription),
     TransitionRuleBlock(Vec<ReactionDescription>),
     SingleColormap((String, (Color, HashSet<String>))),
-    ColormapBlock(HashMap<String, (Color, HashSet<String>)>), // Maps color class -> (color, set(states))
+    ColormapBlock(Vec<(String, (Color, HashSet<String>))>), // (color class, (color, set(states))), in order
+    InitStateImage(String), // Path to the image
+    ImageColormapBlock(Vec<(String, Color)>), // (state, color) pairs
+    InitCommandLine(InitCommand),
+    InitGenerator(Vec<InitCommand>),
+    PatternBlock(String, Pattern),
+    Scenario(String, Box<ParsedManifest>),
+    InvariantLine(Invariant),
+    AgentsLine(Vec<String>),
+    AgentRuleLine(AgentRule),
+    ProbeLine(ProbeSpec),
+    ProbeLevelLine(Level, Vec<StateOrClass>),
 }
 
-peg::parser!{
-    pub grammar settings_input() for str {
-        pub rule settings() -> (SimulatorComponents, Settings)
-         = all_lines:((line() / init_state_block() / transition_rule_block() / colormap_block() / comment() / blank()) ** ['\n']) {
-            //////////////////////// 
-            // SETTINGS VARIABLES //
-            ////////////////////////
-            let variables: HashMap<String, String> = all_lines
-                .iter()
-                .filter_map(|block| match block {
-                    InputBlock::VariableLine(var, val) => Some((var.to_string(), val.to_string())),
-                    _ => None
-                })
-                .collect();
-                         
-            let mut settings = Settings {
-                n_rows: 0,
-                n_cols: 0,
-                cell_size: variables.get("pixels_per_node").map_or(5, |s| s.parse::<u32>().unwrap()),
-                margin: 60,
-                fps: variables.get("fps").map_or(60.0, |s| s.parse::<f32>().unwrap()),
-                speedup_factor: variables.get("speedup_factor").map_or(1.0, |s| s.parse::<f32>().unwrap()),
-                wrap: variables.get("wrap_grid").map_or(false, |s| s.parse::<bool>().unwrap()),
-                debug: variables.get("debug").map_or(false, |s| match s.to_lowercase().as_str() {
-                    "true" | "on" | "yes" | "1" => true,
-                    "false" | "off" | "no" | "0" => false,
-                    _ => false
-                }),
-                rng_seed: variables.get("rng_seed").map(|s| s.parse::<i32>().unwrap()),
-                max_duration: variables.get("max_duration").map_or(1_000_000.0, |s| s.parse::<f32>().unwrap()),
-                display_text: match variables.get("display_text") {
-                        Some(s) => Some(&s[..]), 
-                        None => match variables.get("node_text") {
-                            Some(s) => Some(&s[..]), 
-                            None => Some("false")
-                        }
-                    }.map_or(false, |s| match s.to_lowercase().as_str() {
-                    "true" | "yes" | "text" => true,
-                    "false" | "no" | "color" => false,
-                    _ => false
-                }),
-                surface_geometry: variables.get("surface_geometry").map_or(SurfaceGeometry::Square, |s| match s.to_lowercase().as_str() {
-                    "hex" | "hexagonal" | "hexagons" | "honeycomb" => SurfaceGeometry::Hex,
-                    "square" | "box" | "grid" => SurfaceGeometry::Square,
-                    _ => SurfaceGeometry::Square
-                })
-            };
-
-            //////////////
-            // COLORMAP //
-            //////////////
-            
-            let mut components = SimulatorComponents::new();
-            let mut state_to_class_id: HashMap<String, usize> = HashMap::new();
-            
-            let colormap_block_candidates: Vec<&HashMap<String, (Color, HashSet<String>)>> = all_lines
-                .iter()
-                .filter_map(|block| match block {
-                    InputBlock::ColormapBlock(colormap) => Some(colormap),
-                    _ => None
-                })
-                .collect();
+#[derive(Debug)]
+enum RandomArg {
+    Rows(usize),
+    Cols(usize),
+    Seed(u64),
+    Weight(String, f64),
+}
 
-            for colormap_block in colormap_block_candidates {
-                for (class_name, (color, states)) in colormap_block {
-                    // components.color_classes.insert(class_name.clone(), *color);
-                    let class_id = components.add_color_class(class_name, color, states);
-                    for state in states {
-                        state_to_class_id.insert((*state).clone(), class_id);
-                    }
-                }
-            }
+/// Turns the arguments of a `random` command (or an `!INIT_RANDOM` line, which
+/// also carries the surface size) into init commands.
+fn random_init_commands(args: Vec<RandomArg>, with_size: bool) -> Result<Vec<InitCommand>, &'static str> {
+    let (mut n_rows, mut n_cols, mut seed) = (None, None, None);
+    let mut weights = Vec::new();
+    for arg in args {
+        match arg {
+            RandomArg::Rows(n) => n_rows = Some(n),
+            RandomArg::Cols(n) => n_cols = Some(n),
+            RandomArg::Seed(n) => seed = Some(n),
+            RandomArg::Weight(state, weight) => weights.push((state, weight)),
+        }
+    }
+    let mut commands = Vec::new();
+    match (with_size, n_rows, n_cols) {
+        (true, Some(n_rows), Some(n_cols)) => commands.push(InitCommand::Size { n_rows, n_cols }),
+        (true, _, _) => return Err("rows= and cols= in !INIT_RANDOM"),
+        (false, None, None) => {},
+        (false, _, _) => return Err("a random command without rows= or cols= (use a size command instead)"),
+    }
+    if weights.is_empty() {
+        return Err("at least one State:weight pair");
+    }
+    commands.push(InitCommand::Random { weights, seed });
+    Ok(commands)
+}
 
-            ////////////////
-            // INIT STATE //
-            ////////////////
-            
-            let init_state_block_candidates: Vec<(&Vec<String>, u32, u32)> = all_lines
-                .iter()
-                .filter_map(|block| match block {
-                    InputBlock::InitStateBlock(state_vec, n_rows, n_cols) => Some((state_vec, *n_rows, *n_cols)),
-                    _ => None
-                })
-                .collect();
-            if init_state_block_candidates.len() != 1 {
-                if init_state_block_candidates.is_empty() {
-                    panic!("Couldn't find an initial state in manifest! Input blocks are: {all_lines:?}");
-                }
-                panic!("Too many or too few initial states in manifest!");
-            }
+/// The raw contents of a manifest, before any of it has been turned into
+/// simulator components. Kept around so that tools like the manifest linter
+/// can inspect the blocks as written.
+#[derive(Debug, Default, Clone)]
+pub struct ParsedManifest {
+    pub variables: Vec<(String, String)>, // (name, value), in the order they appear
+    pub color_classes: Vec<(String, Color, HashSet<String>)>, // (class name, color, states)
+    pub init_states: Vec<InitStateSource>,
+    pub image_colors: Vec<(String, Color)>, // (state, color) for reading initial state images
+    pub patterns: HashMap<String, Pattern>, // Named grids that init generators can stamp
+    pub transition_rules: Vec<ReactionDescription>,
+    pub scenarios: Vec<(String, ParsedManifest)>, // Named alternatives, applied on top of everything else
+    pub invariants: Vec<Invariant>, // Weighted state counts the rules are expected to conserve
+    pub agent_states: Vec<String>, // States whose cells are tracked as agents
+    pub agent_rules: Vec<AgentRule>, // How particular rules move agents, overriding the default
+    pub probes: Vec<ProbeSpec>, // Cells whose logical levels are recorded
+    pub probe_levels: Vec<(Level, StateOrClass)>, // Which states probes read as high or low
+}
+
+impl ParsedManifest {
+    /// Adds everything from another manifest to this one.
+    pub fn absorb(&mut self, other: ParsedManifest) {
+        self.variables.extend(other.variables);
+        self.color_classes.extend(other.color_classes);
+        self.init_states.extend(other.init_states);
+        self.image_colors.extend(other.image_colors);
+        self.patterns.extend(other.patterns);
+        self.transition_rules.extend(other.transition_rules);
+        self.invariants.extend(other.invariants);
+        self.agent_states.extend(other.agent_states);
+        self.agent_rules.extend(other.agent_rules);
+        self.probes.extend(other.probes);
+        self.probe_levels.extend(other.probe_levels);
+    }
 
-            let (init_state_string_parts, n_rows, n_cols) = init_state_block_candidates[0];
-            let all_states: HashSet<&str>  = init_state_string_parts.iter().map(|s| &s[..]).collect();
+    pub fn scenario_names(&self) -> Vec<&str> {
+        self.scenarios.iter().map(|(name, _)| &name[..]).collect()
+    }
 
-            for state in all_states {
-                // println!("Adding state {state} to components.");
-                components.add_state(state, state_to_class_id.get(state).copied());
-            }
-            
-            settings.n_rows = n_rows as usize;
-            settings.n_cols = n_cols as usize;
-            if components.current_states.len() % settings.n_rows != 0 {
-                panic!("This initial state isn't square: {init_state_string_parts:?}");
+    /// The scenario to use when none is asked for: none at all if the manifest
+    /// has its own initial state, and otherwise the first scenario.
+    pub fn default_scenario(&self) -> Option<&str> {
+        if self.init_states.is_empty() {
+            self.scenarios.first().map(|(name, _)| &name[..])
+        } else {
+            None
+        }
+    }
+
+    /// The manifest as it reads with one scenario applied. A scenario's
+    /// settings win over the manifest's, its initial state replaces the
+    /// manifest's, and a rule with the same reactants and products as an
+    /// existing one changes that rule's rate instead of adding a new rule.
+    pub fn with_scenario(&self, name: Option<&str>) -> Result<ParsedManifest, String> {
+        let mut manifest = self.clone();
+        manifest.scenarios.clear();
+        let Some(name) = name.or(self.default_scenario()) else {
+            return Ok(manifest);
+        };
+        let Some((_, scenario)) = self.scenarios.iter().find(|(existing, _)| existing == name) else {
+            return Err(format!("there's no scenario named {name:?}; the manifest has {:?}", self.scenario_names()));
+        };
+        let mut scenario = scenario.clone();
+        for (key, value) in std::mem::take(&mut scenario.variables) {
+            // Replace the manifest's line for the same setting, under any of
+            // its names, so it doesn't read as a repeated key.
+            let setting = find_setting(&key).map(|spec| spec.name);
+            manifest.variables.retain(|(existing, _)| match setting {
+                Some(name) => find_setting(existing).is_none_or(|spec| spec.name != name),
+                None => *existing != key
+            });
+            manifest.variables.push((key, value));
+        }
+        if !scenario.init_states.is_empty() {
+            manifest.init_states.clear();
+        }
+        for rule in std::mem::take(&mut scenario.transition_rules) {
+            match manifest.transition_rules.iter_mut().find(|existing| existing.same_reaction(&rule)) {
+                Some(existing) => existing.rate = rule.rate,
+                None => manifest.transition_rules.push(rule)
             }
-            
-            components.set_board_state(init_state_string_parts.iter().map(|s| &s[..]), &settings);
-
-            //////////////////////
-            // TRANSITION RULES //
-            //////////////////////
-            
-            let transition_rule_blocks: Vec<(&Vec<ReactionDescription>)> = all_lines
-                .iter()
-                .filter_map(|block| match block {
-                    InputBlock::TransitionRuleBlock(rules) => Some(rules),
-                    _ => None
-                })
-                .collect();
+        }
+        manifest.absorb(scenario);
+        Ok(manifest)
+    }
+}
+
+/// Builds the simulator components and settings described by a parsed manifest.
+pub fn build_simulation(manifest: &ParsedManifest) -> Result<(SimulatorComponents, Settings), String> {
+    //////////////////////// 
+    // SETTINGS VARIABLES //
+    ////////////////////////
+    let (mut settings, setting_warnings) = match parse_settings(&manifest.variables) {
+        Ok(parsed) => parsed,
+        Err(err) => return Err(format!("bad setting in manifest: {err}"))
+    };
+    for warning in setting_warnings {
+        eprintln!("Warning: {warning}");
+    }
+
+    //////////////
+    // COLORMAP //
+    //////////////
+    
+    let mut components = SimulatorComponents::new();
+    let mut state_to_class_id: HashMap<String, usize> = HashMap::new();
+
+    for (class_name, color, states) in manifest.color_classes.iter() {
+        let class_id = components.add_color_class(class_name, color, states);
+        for state in states {
+            state_to_class_id.insert((*state).clone(), class_id);
+        }
+    }
+
+    ////////////////
+    // INIT STATE //
+    ////////////////
+    
+    if manifest.init_states.len() != 1 {
+        if manifest.init_states.is_empty() {
+            return Err("couldn't find an initial state in manifest".to_string());
+        }
+        return Err("too many initial states in manifest; give each one a name (!START_INIT_STATE <name>) to pick between them".to_string());
+    }
+
+    let init_grid = match manifest.init_states[0].expand(manifest) {
+        Ok(grid) => grid,
+        Err(err) => return Err(format!("couldn't build the initial state: {err}"))
+    };
+    let init_state_string_parts = &init_grid.states;
+    let all_states: HashSet<&str>  = init_state_string_parts.iter().map(|s| &s[..]).collect();
+
+    for state in all_states {
+        // println!("Adding state {state} to components.");
+        components.add_state(state, state_to_class_id.get(state).copied());
+    }
+    
+    settings.n_rows = init_grid.n_rows;
+    settings.n_cols = init_grid.n_cols;
+    if settings.n_rows == 0 || settings.n_cols == 0 {
+        return Err("the initial state is empty".to_string());
+    }
+    if components.current_states.len() % settings.n_rows != 0 {
+        return Err(format!("this initial state isn't square: {init_state_string_parts:?}"));
+    }
+    
+    components.set_board_state(init_state_string_parts.iter().map(|s| &s[..]), &settings);
 
-            for rule_block in transition_rule_blocks {
-                for rule in rule_block {
-                    components.add_transition_rule(rule);
+    //////////////////////
+    // TRANSITION RULES //
+    //////////////////////
+
+    for rule in manifest.transition_rules.iter() {
+        components.add_transition_rule(rule);
+    }
+
+    ////////////
+    // RETURN //
+    ////////////
+
+    Ok((components, settings))
+}
+
+/// Wraps a block in a scenario of its own if it was given a name.
+fn named_block(name: Option<String>, block: InputBlock) -> InputBlock {
+    match name {
+        Some(name) => InputBlock::Scenario(name, Box::new(collect_blocks(vec![block]))),
+        None => block
+    }
+}
+
+/// Gathers parsed blocks into a manifest. Scenarios with the same name (say, a
+/// named init state and a scenario block) are combined.
+fn collect_blocks(blocks: Vec<InputBlock>) -> ParsedManifest {
+    let mut manifest = ParsedManifest::default();
+    for block in blocks {
+        match block {
+            InputBlock::VariableLine(var, val) => {
+                manifest.variables.push((var, val));
+            },
+            InputBlock::InitStateBlock(states, n_rows, n_cols) => {
+                manifest.init_states.push(InitStateSource::Grid(InitGrid {
+                    states,
+                    n_rows: n_rows as usize,
+                    n_cols: n_cols as usize
+                }));
+            },
+            InputBlock::InitStateImage(path) => {
+                manifest.init_states.push(InitStateSource::Image(path));
+            },
+            InputBlock::ImageColormapBlock(colors) => {
+                manifest.image_colors.extend(colors);
+            },
+            InputBlock::InitGenerator(commands) => {
+                manifest.init_states.push(InitStateSource::Generated(commands));
+            },
+            InputBlock::PatternBlock(name, pattern) => {
+                manifest.patterns.insert(name, pattern);
+            },
+            InputBlock::TransitionRuleBlock(rules) => {
+                manifest.transition_rules.extend(rules);
+            },
+            InputBlock::ColormapBlock(colormap) => {
+                manifest.color_classes.extend(
+                    colormap.into_iter().map(|(class_name, (color, states))| (class_name, color, states))
+                );
+            },
+            InputBlock::InvariantLine(invariant) => {
+                manifest.invariants.push(invariant);
+            },
+            InputBlock::AgentsLine(states) => {
+                manifest.agent_states.extend(states);
+            },
+            InputBlock::AgentRuleLine(rule) => {
+                manifest.agent_rules.push(rule);
+            },
+            InputBlock::ProbeLine(probe) => {
+                manifest.probes.push(probe);
+            },
+            InputBlock::ProbeLevelLine(level, states) => {
+                manifest.probe_levels.extend(states.into_iter().map(|states| (level, states)));
+            },
+            InputBlock::Scenario(name, scenario) => {
+                match manifest.scenarios.iter_mut().find(|(existing, _)| *existing == name) {
+                    Some((_, existing)) => existing.absorb(*scenario),
+                    None => manifest.scenarios.push((name, *scenario))
                 }
-            }
+            },
+            _ => {}
+        }
+    }
+    manifest
+}
 
-            ////////////
-            // RETURN //
-            ////////////
-        
-            (components, settings)
-         }
+peg::parser!{
+    pub grammar settings_input() for str {
+        pub rule settings() -> (SimulatorComponents, Settings)
+         = manifest:manifest() {build_simulation(&manifest.with_scenario(None).unwrap()).unwrap()}
+
+        pub rule manifest() -> ParsedManifest
+         = all_lines:(manifest_item() ** ['\n']) {collect_blocks(all_lines)}
+
+        rule manifest_item() -> InputBlock
+         = scenario_block() / scenario_item()
+
+        rule scenario_item() -> InputBlock
+         = line() / init_state_block() / init_state_image() / init_random() / init_generator_block() / pattern_block() / invariant_line()
+           / agents_line() / agent_rule_line() / probe_level_line() / probe_line()
+           / transition_rule_block() / colormap_block() / image_colormap_block() / comment() / blank()
+
+        rule scenario_block() -> InputBlock
+         = "!START_SCENARIO" [' ']+ name:pattern_name() [' ']* "\n" items:(scenario_item() ** ['\n']) "!END_SCENARIO"
+            {
+                InputBlock::Scenario(name, Box::new(collect_blocks(items)))
+            }
 
         rule line() -> InputBlock
          = setting:variable() [' ']* "=" [' ']* val:value() 
@@ -155,19 +330,17 @@ peg::parser!{
         }
 
         rule variable() -> String
-         = v:$("pixels_per_node" / "fps" / "wrap_grid" / "speedup_factor" / "debug" 
-                / "rng_seed" / "max_duration" / "display_text" / "node_display" / "surface_geometry") 
-              {String::from(v)}
+         = v:$(['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) {String::from(v)}
 
         rule value() -> String
-         = v:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_']+) {String::from(v)}
+         = "\"" v:$([^'"' | '\n']*) "\"" {String::from(v)}
+         / v:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' | '+']+) {String::from(v)}
 
-         ////// START HERE ///////
-         // Problem 1) This rule doesn't allow states starting with digits (i.e., 1Ax)
-         // Problem 2) If you delete the first bit that just matches letters, then 
-         //             this rule *does* match to "!END_INIT_STATE" for some reason, which breaks blocks.
-        rule state() -> String 
-        = v:$(['a'..='z' | 'A'..='Z' | '0'..='9']+) {String::from(v)}
+        // A state name: letters, digits, and underscores, or anything at all in
+        // double quotes ("Edge-U").
+        rule state() -> String
+         = "\"" v:$([^'"' | '\n']+) "\"" {String::from(v)}
+         / v:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_']+) {String::from(v)}
 
         rule comment() -> InputBlock
          = "#" [^'\n']* {InputBlock::None}
@@ -176,14 +349,183 @@ peg::parser!{
          = blank:$(" "*) {InputBlock::None}
 
         rule init_state_block() -> InputBlock
-         = "!START_INIT_STATE\n" state:$(state() ** ([',' | '\n' | ' ' | '\t']*)) "\n!END_INIT_STATE" 
+         = "!START_INIT_STATE" name:block_name() "\n" rows:(init_state_row() ** ['\n']) "!END_INIT_STATE"
+            {?
+                let rows: Vec<Vec<String>> = rows.into_iter().filter(|row| !row.is_empty()).collect();
+                let n_rows = rows.len();
+                let n_cols = rows.first().map_or(0, |row| row.len());
+                if rows.iter().any(|row| row.len() != n_cols) {
+                    return Err("initial state rows that are all the same length");
+                }
+                Ok(named_block(name, InputBlock::InitStateBlock(rows.concat(), n_rows as u32, n_cols as u32)))
+            }
+
+        // States in a row can be separated by spaces, tabs, or commas.
+        rule init_state_row() -> Vec<String>
+         = [' ' | '\t']* states:(state() ** ([' ' | '\t' | ',']+)) [' ' | '\t' | ',']* {states}
+
+        // An optional name after a block marker, which makes the block its own scenario.
+        rule block_name() -> Option<String>
+         = name:([' ']+ name:pattern_name() {name})? [' ']* {name}
+
+        rule invariant_line() -> InputBlock
+         = "!INVARIANT" [' ']+ terms:(invariant_term() ++ ([' ']* "+" [' ']*)) [' ']*
+            {
+                InputBlock::InvariantLine(Invariant { terms })
+            }
+
+        // A state with an optional whole-number weight in front: `2 Carrying`.
+        rule invariant_term() -> (String, i64)
+         = weight:count() [' ']+ state:state() {(state, weight as i64)}
+         / state:state() {(state, 1)}
+
+        rule agents_line() -> InputBlock
+         = "!AGENTS" states:(([' ']+ / [' ']* "," [' ']*) state:state() {state})+ [' ']*
+            {
+                InputBlock::AgentsLine(states)
+            }
+
+        // `!AGENT_RULE A + B -> B + A: 1->2, 2->1` says where each reactant's
+        // agent ends up, numbering reactants and products from 1.
+        rule agent_rule_line() -> InputBlock
+         = "!AGENT_RULE" [' ']+ r1:state() r2:(ws() "+" ws() state:state() {state})? ws() "->" ws()
+           p1:state() p2:(ws() "+" ws() state:state() {state})? ws() ":" carries:((ws() carry:agent_carry() ws() {carry}) ** ",") ws()
+            {?
+                AgentRule::new(ReactionDescription { r1, r2, p1, p2, rate: 0.0 }, carries).map(InputBlock::AgentRuleLine)
+            }
+
+        rule agent_carry() -> (usize, usize)
+         = from:count() ws() "->" ws() to:count() {(from, to)}
+
+        // `!PROBE carry 27,5` records the levels of the cell at row 27, column 5.
+        rule probe_line() -> InputBlock
+         = "!PROBE" [' ']+ name:pattern_name() [' ']+ row:count() ws() "," ws() col:count() ws()
+            {
+                InputBlock::ProbeLine(ProbeSpec { name, row, col })
+            }
+
+        rule probe_level_line() -> InputBlock
+         = "!PROBE_" level:("HIGH" {Level::High} / "LOW" {Level::Low})
+           states:(([' ']+ / [' ']* "," [' ']*) states:state_or_class() {states})+ [' ']*
+            {
+                InputBlock::ProbeLevelLine(level, states)
+            }
+
+        rule state_or_class() -> StateOrClass
+         = "{" class:$([^'}' | '\n']+) "}" {StateOrClass::Class(class.to_string())}
+         / state:state() {StateOrClass::State(state)}
+
+        rule init_state_image() -> InputBlock
+         = "!INIT_STATE_IMAGE" [' ']+ "\"" path:$([^'"' | '\n']+) "\"" [' ']*
+            {
+                InputBlock::InitStateImage(path.to_string())
+            }
+         / "!INIT_STATE_IMAGE" [' ']+ path:$([^'\n']+)
             {
-                let state_bits: Vec<String> = state.split_whitespace().map(|s| s.to_string()).collect();
-                let n_rows = 1 + state.chars().filter(|s| *s == '\n').count();
-                let n_cols = (state_bits.len() / n_rows);
-                InputBlock::InitStateBlock(state_bits, n_rows as u32, n_cols as u32)
+                InputBlock::InitStateImage(path.trim().to_string())
             }
 
+        rule init_random() -> InputBlock
+         = "!INIT_RANDOM" args:([' ']+ arg:random_arg() {arg})+ [' ']*
+            {?
+                random_init_commands(args, true).map(InputBlock::InitGenerator)
+            }
+
+        rule init_generator_block() -> InputBlock
+         = "!START_INIT_GENERATOR" name:block_name() "\n" lines:((comment() / init_command_line() / ws()) ** ['\n']) "!END_INIT_GENERATOR"
+            {
+                named_block(name, InputBlock::InitGenerator(
+                    lines
+                    .into_iter()
+                    .filter_map(|line| match line {
+                        InputBlock::InitCommandLine(command) => Some(command),
+                        _ => None
+                    })
+                    .collect()))
+            }
+
+        rule init_command_line() -> InputBlock
+         = ws() command:(size_command() / fill_command() / random_command() / rect_command()
+                        / line_command() / circle_command() / cell_command() / stamp_command()) ws()
+            {
+                InputBlock::InitCommandLine(command)
+            }
+
+        rule size_command() -> InitCommand
+         = "size" [' ']+ n_rows:side() [' ']+ n_cols:side() {InitCommand::Size { n_rows, n_cols }}
+
+        rule fill_command() -> InitCommand
+         = "fill" [' ']+ state:state() {InitCommand::Fill(state)}
+
+        rule random_command() -> InitCommand
+         = "random" args:([' ']+ arg:random_arg() {arg})+
+            {?
+                random_init_commands(args, false).map(|mut commands| commands.pop().unwrap())
+            }
+
+        rule rect_command() -> InitCommand
+         = "rect" [' ']+ state:state() [' ']+ row:integer() [' ']+ col:integer() [' ']+ height:integer() [' ']+ width:integer()
+            {InitCommand::Rect { state, row, col, height, width }}
+
+        rule line_command() -> InitCommand
+         = "line" [' ']+ state:state() [' ']+ r1:integer() [' ']+ c1:integer() [' ']+ r2:integer() [' ']+ c2:integer()
+            {InitCommand::Line { state, from: (r1, c1), to: (r2, c2) }}
+
+        rule circle_command() -> InitCommand
+         = "circle" [' ']+ state:state() [' ']+ row:integer() [' ']+ col:integer() [' ']+ radius:integer()
+            {InitCommand::Circle { state, row, col, radius }}
+
+        rule cell_command() -> InitCommand
+         = "cell" [' ']+ state:state() [' ']+ row:integer() [' ']+ col:integer()
+            {InitCommand::Cell { state, row, col }}
+
+        rule stamp_command() -> InitCommand
+         = "stamp" [' ']+ pattern:pattern_name() [' ']+ row:integer() [' ']+ col:integer()
+           rotation:([' ']+ "rotate=" r:$("0" / "90" / "180" / "270") {r.parse::<u32>().unwrap()})?
+           mirror:([' ']+ "mirror")?
+            {InitCommand::Stamp { pattern, row, col, rotation: rotation.unwrap_or(0), mirror: mirror.is_some() }}
+
+        rule pattern_name() -> String
+         = name:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-']+) {String::from(name)}
+
+        rule pattern_block() -> InputBlock
+         = "!START_PATTERN" [' ']+ name:pattern_name() [' ']* "\n" rows:(pattern_row() ** "\n") "!END_PATTERN"
+            {?
+                let rows: Vec<Vec<Option<String>>> = rows.into_iter().filter(|row| !row.is_empty()).collect();
+                let Some(n_cols) = rows.first().map(|row| row.len()) else {
+                    return Err("a pattern with at least one row");
+                };
+                if rows.iter().any(|row| row.len() != n_cols) {
+                    return Err("every row of a pattern to have the same number of cells");
+                }
+                let n_rows = rows.len();
+                let cells = rows.into_iter().flatten().collect();
+                Ok(InputBlock::PatternBlock(name, Pattern { cells, n_rows, n_cols }))
+            }
+
+        rule pattern_row() -> Vec<Option<String>>
+         = [' ' | '\t']* cells:(pattern_cell() ** ([' ' | '\t' | ',']+)) [' ' | '\t' | ',']* {cells}
+
+        rule pattern_cell() -> Option<String>
+         = "." {None}
+         / state:state() {Some(state)}
+
+        rule random_arg() -> RandomArg
+         = "rows=" n:side() {RandomArg::Rows(n)}
+         / "cols=" n:side() {RandomArg::Cols(n)}
+         / "seed=" n:count() {RandomArg::Seed(n as u64)}
+         / state:state() ":" weight:$(['0'..='9']* ("." ['0'..='9']+)?) {? weight.parse::<f64>().map(|w| RandomArg::Weight(state, w)).or(Err("a weight")) }
+
+        rule integer() -> i64
+         = n:$("-"? ['0'..='9']+) {? n.parse::<i64>().or(Err("an integer")) }
+
+        rule count() -> usize
+         = n:$(['0'..='9']+) {? n.parse::<usize>().or(Err("a whole number")) }
+
+        // How many rows or columns a board has; an empty board has nothing to simulate.
+        rule side() -> usize
+         = n:count() {? if n == 0 { Err("at least one row and column") } else { Ok(n) } }
+
         rule transition_rule_block() -> InputBlock
          = "!START_TRANSITION_RULES\n" transition_rules:(((comment() / unimolecular_rule() / bimolecular_rule() / ws())) ** ['\n']) "!END_TRANSITION_RULES"
             {
@@ -203,13 +545,13 @@ peg::parser!{
          = r:(rate_first_unimolecular_rule() / rate_last_unimolecular_rule()) {r}
         
         rule rate_first_unimolecular_rule() -> InputBlock
-         = ws() r1:$(state()) ws() "->" ws() p1:$(state()) ws() rate:(rate()) ws()
+         = ws() r1:state() ws() "->" ws() p1:state() ws() rate:(rate()) ws()
          {
             InputBlock::SingleTransitionRule(
                 ReactionDescription {
-                    r1: r1.to_string(), 
+                    r1, 
                     r2: None, 
-                    p1: p1.to_string(), 
+                    p1, 
                     p2: None, 
                     rate
                 }
@@ -217,13 +559,13 @@ peg::parser!{
          }
 
         rule rate_last_unimolecular_rule() -> InputBlock
-         = ws() rate:(rate()) ws() r1:$(state()) ws() "->" ws() p1:$(state()) ws()
+         = ws() rate:(rate()) ws() r1:state() ws() "->" ws() p1:state() ws()
          {
             InputBlock::SingleTransitionRule(
                 ReactionDescription {
-                    r1: r1.to_string(), 
+                    r1, 
                     r2: None, 
-                    p1: p1.to_string(), 
+                    p1, 
                     p2: None, 
                     rate
                 }
@@ -234,28 +576,28 @@ peg::parser!{
          = r:(rate_first_bimolecular_rule() / rate_last_bimolecular_rule()) {r}
 
         rule rate_first_bimolecular_rule() -> InputBlock
-         = ws() r1:$(state()) ws() "+" ws() r2:$(state()) ws() "->" ws() p1:$(state()) ws() "+" ws() p2:$(state()) ws() rate:(rate()) ws()
+         = ws() r1:state() ws() "+" ws() r2:state() ws() "->" ws() p1:state() ws() "+" ws() p2:state() ws() rate:(rate()) ws()
          {
             InputBlock::SingleTransitionRule(
                 ReactionDescription {
-                    r1: r1.to_string(), 
-                    r2: Some(r2.to_string()), 
-                    p1: p1.to_string(), 
-                    p2: Some(p2.to_string()),
+                    r1, 
+                    r2: Some(r2), 
+                    p1, 
+                    p2: Some(p2),
                     rate
                 }
             )
          }
 
          rule rate_last_bimolecular_rule() -> InputBlock
-         = ws() rate:(rate()) ws() r1:$(state()) ws() "+" ws() r2:$(state()) ws() "->" ws() p1:$(state()) ws() "+" ws() p2:$(state()) ws()
+         = ws() rate:(rate()) ws() r1:state() ws() "+" ws() r2:state() ws() "->" ws() p1:state() ws() "+" ws() p2:state() ws()
          {
             InputBlock::SingleTransitionRule(
                 ReactionDescription {
-                    r1: r1.to_string(),
-                    r2: Some(r2.to_string()),
-                    p1: p1.to_string(),
-                    p2: Some(p2.to_string()),
+                    r1,
+                    r2: Some(r2),
+                    p1,
+                    p2: Some(p2),
                     rate
                 }
             )
@@ -267,13 +609,29 @@ peg::parser!{
         rule colormap_block() -> InputBlock
          = "!START_COLORMAP\n" colors:((comment() / color_class() / ws()) ** ['\n']) "!END_COLORMAP"
          {
-            let colormap: HashMap<String, (Color, HashSet<String>)> = colors
+            let colormap: Vec<(String, (Color, HashSet<String>))> = colors
                 .into_iter()
                 .filter_map(|line| match line {InputBlock::SingleColormap(cm) => Some(cm), _ => None})
                 .collect();
             InputBlock::ColormapBlock(colormap)
          }
 
+        rule image_colormap_block() -> InputBlock
+         = "!START_IMAGE_COLORMAP\n" colors:((comment() / image_color() / ws()) ** ['\n']) "!END_IMAGE_COLORMAP"
+         {
+            let image_colors: Vec<(String, Color)> = colors
+                .into_iter()
+                .filter_map(|line| match line {InputBlock::SingleColormap((state, (color, _))) => Some((state, color)), _ => None})
+                .collect();
+            InputBlock::ImageColormapBlock(image_colors)
+         }
+
+        rule image_color() -> InputBlock
+         = class:single_color_def()
+        {
+            InputBlock::SingleColormap(class)
+        }
+
         rule color_class() -> InputBlock
          = class:(single_color_def() / color_class_def())
         {
@@ -310,6 +668,6 @@ peg::parser!{
             }
         }
 
-        rule ws() -> InputBlock = [' ']* {InputBlock::None}
+        rule ws() -> InputBlock = [' ' | '\t']* {InputBlock::None}
     }
 }
@@ -0,0 +1,253 @@
+use std::path::PathBuf;
+
+use itertools::Itertools;
+use rand::random;
+use sdl2::image::SaveSurface;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::surface::Surface;
+
+use crate::init_states::line_cells;
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::patterns::sample_boards;
+use crate::state::{Settings, SimulatorComponents};
+use crate::trace::parse_cell;
+
+/// A space-time diagram: the states along a line of cells, sampled at
+/// regular simulated times, one row per sample.
+pub struct Kymograph {
+    pub cells: Vec<usize>,             // Board indices along the line, in order.
+    pub dt: f64,                       // Time between samples.
+    pub samples: Vec<(f64, Vec<usize>)>, // Each sample's time and the states along the line.
+}
+
+impl Kymograph {
+    pub fn new(cells: Vec<usize>, dt: f64) -> Kymograph {
+        Kymograph { cells, dt, samples: Vec::new() }
+    }
+
+    pub fn record(&mut self, t: f64, board: &[usize]) {
+        self.samples.push((t, self.cells.iter().map(|cell| board[*cell]).collect()));
+    }
+
+    /// Keeps up with a board that's being played back: records it for every
+    /// sample time from the last sample up to `t`, and forgets samples after
+    /// `t` when playback has gone backwards. The board only changes between
+    /// frames, so sample times that pass within one frame all get that
+    /// frame's board.
+    pub fn follow(&mut self, t: f64, board: &[usize]) {
+        while self.samples.last().is_some_and(|(sample_t, _)| *sample_t > t) {
+            self.samples.pop();
+        }
+        let mut next_t = self.samples.len() as f64 * self.dt;
+        while next_t <= t {
+            self.record(next_t, board);
+            next_t = self.samples.len() as f64 * self.dt;
+        }
+    }
+
+    /// Each sample's time and the position along the line of the farthest
+    /// cell in one of `states`, for the samples that have any.
+    pub fn front_positions(&self, states: &[usize]) -> Vec<(f64, usize)> {
+        self.samples
+            .iter()
+            .filter_map(|(t, row)| row.iter().rposition(|state| states.contains(state)).map(|position| (*t, position)))
+            .collect()
+    }
+
+    /// Draws the kymograph with each sample as a row of `scale`-pixel
+    /// squares, the earliest at the top, in the states' legend colors.
+    pub fn to_surface(&self, components: &SimulatorComponents, scale: u32) -> Result<Surface<'static>, String> {
+        let (width, height) = (self.cells.len() as u32 * scale, self.samples.len().max(1) as u32 * scale);
+        let mut surface = Surface::new(width, height, PixelFormatEnum::RGB24)?;
+        for (sample_idx, (_, row)) in self.samples.iter().enumerate() {
+            let mut x = 0;
+            for (run_length, state) in row.iter().dedup_with_count() {
+                let rect = Rect::new(x as i32 * scale as i32, sample_idx as i32 * scale as i32, run_length as u32 * scale, scale);
+                surface.fill_rect(rect, state_color(components, *state))?;
+                x += run_length;
+            }
+        }
+        Ok(surface)
+    }
+}
+
+/// The color a state is drawn in on the board.
+pub fn state_color(components: &SimulatorComponents, state: usize) -> Color {
+    components.colorclass_colors[components.state_colorclasses[&state]]
+}
+
+/// Reads which cells a kymograph follows: a row number, as in `12`, or a
+/// line segment between two `row,column` cells, as in `0,0:20,40`.
+pub fn parse_line(spec: &str, settings: &Settings) -> Result<Vec<usize>, String> {
+    if let Some((from, to)) = spec.split_once(':') {
+        let [from, to] = [from, to].map(|cell| parse_cell(cell, settings).map(|idx| ((idx / settings.n_cols) as i64, (idx % settings.n_cols) as i64)));
+        return Ok(line_cells(from?, to?).into_iter().map(|(row, col)| row as usize * settings.n_cols + col as usize).collect());
+    }
+    match spec.trim().parse::<usize>() {
+        Ok(row) if row < settings.n_rows => Ok((row * settings.n_cols..(row + 1) * settings.n_cols).collect()),
+        Ok(row) => Err(format!("row {row} is off the {}x{} board", settings.n_rows, settings.n_cols)),
+        Err(_) => Err(format!("{spec:?} should be a row number or two row,column cells like 0,0:20,40")),
+    }
+}
+
+/// The least-squares slope of position against time, in cells per unit
+/// time, over a front's positions from `Kymograph::front_positions`. Only the
+/// first stretch of samples up to the front reaching `end` (the line's last
+/// cell) is used, so a front that has run off the end doesn't count as
+/// stopped. None without two distinct times to fit.
+pub fn front_speed(positions: &[(f64, usize)], end: usize) -> Option<f64> {
+    let n_used = positions.iter().position(|(_, position)| *position >= end).map_or(positions.len(), |idx| idx + 1);
+    let used = &positions[..n_used];
+    let n = used.len() as f64;
+    let mean_t = used.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let mean_x = used.iter().map(|(_, x)| *x as f64).sum::<f64>() / n;
+    let (covariance, variance) = used.iter().fold((0.0, 0.0), |(covariance, variance), (t, x)| {
+        (covariance + (t - mean_t) * (*x as f64 - mean_x), variance + (t - mean_t).powi(2))
+    });
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+/// The settings for `chitin kymograph`, as given on the command line.
+pub struct KymographOptions<'a> {
+    pub line: Option<&'a str>,
+    pub time: Option<&'a str>,
+    pub samples: Option<&'a str>,
+    pub seed: Option<&'a str>,
+    pub scale: Option<&'a str>,
+    pub front: Option<&'a str>,
+}
+
+/// Runs `chitin kymograph`: samples a row (by default the middle one) or a
+/// line segment over one seeded run and saves the space-time diagram as a
+/// PNG, time running down. With `--front <state>`, also prints how fast the
+/// farthest cell in that state moves along the line. Returns the process
+/// exit code.
+pub fn kymograph_manifest_file(
+    input_file: PathBuf,
+    output_file: PathBuf,
+    options: KymographOptions,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings, _) = match load_from_manifest(&manifest, scenario) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let parsed = (|| -> Result<(Vec<usize>, f64, usize, u64, u32), String> {
+        let cells = parse_line(options.line.unwrap_or(&(settings.n_rows / 2).to_string()), &settings)?;
+        let parse = |value: Option<&str>, flag: &str| value.map(|value| value.parse::<f64>().map_err(|_| format!("{flag} can't be {value:?}")));
+        let t_end = parse(options.time, "--time").transpose()?.unwrap_or(settings.max_duration as f64);
+        let n_samples = parse(options.samples, "--samples").transpose()?.unwrap_or(200.0) as usize;
+        let scale = parse(options.scale, "--scale").transpose()?.unwrap_or(4.0) as u32;
+        let seed = match options.seed {
+            Some(seed) => seed.parse().map_err(|_| format!("--seed can't be {seed:?}"))?,
+            None => settings.rng_seed.map_or_else(random::<u32>, |seed| seed as u32) as u64,
+        };
+        if !(t_end > 0.0 && t_end.is_finite()) || n_samples == 0 || scale == 0 {
+            return Err("--time, --samples, and --scale should be positive".to_string());
+        }
+        Ok((cells, t_end, n_samples, seed, scale))
+    })();
+    let (cells, t_end, n_samples, seed, scale) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 2;
+        }
+    };
+    let front = options.front.map(|name| {
+        let ids: Vec<usize> = components.state_names.iter().filter(|(_, state_name)| *state_name == name).map(|(id, _)| *id).collect();
+        (name, ids)
+    });
+    if let Some((name, _)) = front.as_ref().filter(|(_, ids)| ids.is_empty()) {
+        eprintln!("error: there's no state named {name:?}");
+        return 2;
+    }
+
+    let mut kymograph = Kymograph::new(cells, t_end / n_samples as f64);
+    let sample_times: Vec<f64> = (0..=n_samples).map(|idx| kymograph.dt * idx as f64).collect();
+    sample_boards(&components, &settings, seed, &sample_times, |idx, board| kymograph.record(sample_times[idx], board));
+    if let Err(err) = kymograph.to_surface(&components, scale).and_then(|surface| surface.save(&output_file)) {
+        eprintln!("Couldn't write {output_file:?}: {err}");
+        return 1;
+    }
+    println!(
+        "Wrote {} sample(s) of {} cell(s) up to t={t_end} (seed {seed}) to {output_file:?}.",
+        kymograph.samples.len(), kymograph.cells.len()
+    );
+    if let Some((name, states)) = front {
+        let positions = kymograph.front_positions(&states);
+        match front_speed(&positions, kymograph.cells.len() - 1) {
+            Some(speed) => println!("The {name} front moves {speed:.4} cell(s) per unit time along the line."),
+            None => println!("There isn't enough of {name} on the line to measure a front speed."),
+        }
+    }
+    0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{front_speed, parse_line, Kymograph};
+
+    #[test]
+    fn test_parse_line() {
+        let manifest = "\
+!START_TRANSITION_RULES
+A -> B (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A A A A
+A A A A
+A A A A
+!END_INIT_STATE";
+        let (_, settings) = settings_input::settings(manifest).unwrap();
+        assert_eq!(parse_line("1", &settings), Ok(vec![4, 5, 6, 7]));
+        assert_eq!(parse_line("0,0:2,3", &settings), Ok(vec![0, 5, 6, 11]));
+        assert_eq!(parse_line("2,3:2,1", &settings), Ok(vec![11, 10, 9]));
+        assert!(parse_line("3", &settings).is_err());
+        assert!(parse_line("0,0:3,0", &settings).is_err());
+    }
+
+    #[test]
+    fn test_follow_playback() {
+        let mut kymograph = Kymograph::new(vec![2, 0], 0.5);
+        kymograph.follow(1.2, &[1, 2, 3]);
+        assert_eq!(kymograph.samples, vec![(0.0, vec![3, 1]), (0.5, vec![3, 1]), (1.0, vec![3, 1])]);
+        // Going back forgets the samples after the board on screen.
+        kymograph.follow(0.7, &[4, 5, 6]);
+        kymograph.follow(1.0, &[7, 8, 9]);
+        assert_eq!(kymograph.samples, vec![(0.0, vec![3, 1]), (0.5, vec![3, 1]), (1.0, vec![9, 7])]);
+    }
+
+    #[test]
+    fn test_front_speed() {
+        // A front two cells per unit time, which stops once it runs off the
+        // end of a six-cell line.
+        let (front, rest) = (1, 0);
+        let mut kymograph = Kymograph::new((0..6).collect(), 0.5);
+        for step in 0..8 {
+            let reached = (step + 1).min(6);
+            let board: Vec<usize> = (0..6).map(|cell| if cell < reached { front } else { rest }).collect();
+            kymograph.record(step as f64 * 0.5, &board);
+        }
+        let positions = kymograph.front_positions(&[front]);
+        assert_eq!(positions.len(), 8);
+        assert!((front_speed(&positions, 5).unwrap() - 2.0).abs() < 1e-9);
+        assert_eq!(front_speed(&positions[..1], 5), None);
+        assert!(kymograph.front_positions(&[2]).is_empty());
+    }
+}
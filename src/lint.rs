@@ -0,0 +1,295 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::analysis::reachable_states;
+use crate::input::read_manifest_file;
+use crate::input_parsers::ParsedManifest;
+use crate::reactions::ReactionDescription;
+use crate::settings_schema::parse_settings;
+
+// (r1, r2, p1, p2), used to spot repeated rules.
+type RuleKey<'a> = (&'a str, Option<&'a str>, &'a str, Option<&'a str>);
+
+/// A problem found in a manifest that won't stop it from loading, but probably
+/// isn't what the author meant.
+#[derive(Debug, PartialEq)]
+pub enum LintWarning {
+    UnreachableRule { rule_idx: usize, rule: String, missing: Vec<String> },
+    ColormapOnlyState { state: String, class_name: String },
+    DuplicateRule { rule_idx: usize, rule: String, original_idx: usize },
+    MirroredRule { rule_idx: usize, rule: String, original_idx: usize },
+    ZeroRateRule { rule_idx: usize, rule: String },
+    OverlappingColorClasses { state: String, class_names: Vec<String> },
+    BrokenInvariant { invariant: String, rule_idx: usize, rule: String, change: i64 },
+    Setting(String),
+    InitState(String),
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintWarning::UnreachableRule { rule_idx, rule, missing } => write!(
+                f,
+                "rule {rule_idx} ({rule}) can never fire: {} never {} starting from the initial state",
+                missing.join(", "),
+                if missing.len() == 1 { "appears" } else { "appear" }
+            ),
+            LintWarning::ColormapOnlyState { state, class_name } => write!(
+                f,
+                "state {state} (color class {class_name:?}) appears only in the colormap"
+            ),
+            LintWarning::DuplicateRule { rule_idx, rule, original_idx } => write!(
+                f,
+                "rule {rule_idx} ({rule}) duplicates rule {original_idx}"
+            ),
+            LintWarning::MirroredRule { rule_idx, rule, original_idx } => write!(
+                f,
+                "rule {rule_idx} ({rule}) is rule {original_idx} with its reactants swapped, \
+                 so it fires on the same neighbor pairs"
+            ),
+            LintWarning::ZeroRateRule { rule_idx, rule } => write!(
+                f,
+                "rule {rule_idx} ({rule}) has a rate of zero and will never fire"
+            ),
+            LintWarning::OverlappingColorClasses { state, class_names } => write!(
+                f,
+                "state {state} is listed in more than one color class ({}); only one of them will be used",
+                class_names.join(", ")
+            ),
+            LintWarning::BrokenInvariant { invariant, rule_idx, rule, change } => write!(
+                f,
+                "rule {rule_idx} ({rule}) changes the invariant {invariant} by {change:+}"
+            ),
+            LintWarning::Setting(message) => write!(f, "{message}"),
+            LintWarning::InitState(message) => write!(f, "initial state: {message}"),
+        }
+    }
+}
+
+/// Writes out a rule in the same form it would take in a manifest.
+pub fn describe_rule(rule: &ReactionDescription) -> String {
+    match (&rule.r2, &rule.p2) {
+        (Some(r2), Some(p2)) => format!("{} + {} -> {} + {} ({})", rule.r1, r2, rule.p1, p2, rule.rate),
+        _ => format!("{} -> {} ({})", rule.r1, rule.p1, rule.rate),
+    }
+}
+
+/// Checks a parsed manifest for rules that can't fire, redundant rules, and
+/// colormap entries that don't do anything.
+pub fn lint_manifest(manifest: &ParsedManifest) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    let rules = &manifest.transition_rules;
+
+    // Settings lines that are unknown, repeated, or can't be parsed.
+    match parse_settings(&manifest.variables) {
+        Ok((_, setting_warnings)) => warnings.extend(setting_warnings.into_iter().map(LintWarning::Setting)),
+        Err(err) => warnings.push(LintWarning::Setting(err))
+    }
+
+    // Initial states that can't be built. Every scenario's initial state
+    // counts as a possible starting point, and its rules as possible rules.
+    let mut init_grids = Vec::new();
+    let mut all_rules: Vec<ReactionDescription> = rules.clone();
+    let mut expand_into_grids = |manifest: &ParsedManifest| {
+        for source in manifest.init_states.iter() {
+            match source.expand(manifest) {
+                Ok(grid) => init_grids.push(grid),
+                Err(err) => warnings.push(LintWarning::InitState(err))
+            }
+        }
+    };
+    expand_into_grids(manifest);
+    for (name, scenario) in manifest.scenarios.iter() {
+        if !scenario.init_states.is_empty() {
+            expand_into_grids(&manifest.with_scenario(Some(name)).unwrap());
+        }
+    }
+    for (_, scenario) in manifest.scenarios.iter() {
+        all_rules.extend(scenario.transition_rules.iter().cloned());
+    }
+    let initial_states: HashSet<&str> = init_grids
+        .iter()
+        .flat_map(|grid| grid.states.iter().map(|s| &s[..]))
+        .collect();
+
+    // Rules with reactants that never show up.
+    let reachable = reachable_states(initial_states.iter().copied(), &all_rules);
+    for (rule_idx, rule) in rules.iter().enumerate() {
+        let mut missing: Vec<String> = std::iter::once(&rule.r1)
+            .chain(rule.r2.iter())
+            .filter(|state| !reachable.contains(&state[..]))
+            .cloned()
+            .collect();
+        missing.dedup();
+        if !missing.is_empty() {
+            warnings.push(LintWarning::UnreachableRule { rule_idx, rule: describe_rule(rule), missing });
+        }
+    }
+
+    // Zero-rate, duplicate, and mirrored rules.
+    let mut seen_rules: HashMap<RuleKey, usize> = HashMap::new();
+    for (rule_idx, rule) in rules.iter().enumerate() {
+        if rule.rate == 0.0 {
+            warnings.push(LintWarning::ZeroRateRule { rule_idx, rule: describe_rule(rule) });
+        }
+        let key = (&rule.r1[..], rule.r2.as_deref(), &rule.p1[..], rule.p2.as_deref());
+        if let Some(&original_idx) = seen_rules.get(&key) {
+            warnings.push(LintWarning::DuplicateRule { rule_idx, rule: describe_rule(rule), original_idx });
+            continue;
+        }
+        if let (Some(r2), Some(p2)) = (rule.r2.as_deref(), rule.p2.as_deref()) {
+            let mirrored_key = (r2, Some(&rule.r1[..]), p2, Some(&rule.p1[..]));
+            if mirrored_key != key {
+                if let Some(&original_idx) = seen_rules.get(&mirrored_key) {
+                    warnings.push(LintWarning::MirroredRule { rule_idx, rule: describe_rule(rule), original_idx });
+                    continue;
+                }
+            }
+        }
+        seen_rules.insert(key, rule_idx);
+    }
+
+    // Rules that break a declared invariant, among those that can fire.
+    for invariant in manifest.invariants.iter() {
+        for (rule_idx, rule) in rules.iter().enumerate() {
+            if !rule.all_states().iter().all(|state| reachable.contains(state)) {
+                continue;
+            }
+            let change = invariant.change_under(rule);
+            if change != 0 {
+                warnings.push(LintWarning::BrokenInvariant {
+                    invariant: invariant.to_string(), rule_idx, rule: describe_rule(rule), change
+                });
+            }
+        }
+    }
+
+    // Colormap states that are never used, and states in more than one class.
+    let used_states: HashSet<&str> = initial_states
+        .iter()
+        .copied()
+        .chain(all_rules.iter().flat_map(|rule| rule.all_states()))
+        .collect();
+    let mut classes_by_state: HashMap<&str, Vec<String>> = HashMap::new();
+    for (class_name, _, states) in manifest.color_classes.iter() {
+        let mut sorted_states: Vec<&String> = states.iter().collect();
+        sorted_states.sort();
+        for state in sorted_states {
+            if !used_states.contains(&state[..]) {
+                warnings.push(LintWarning::ColormapOnlyState { state: state.clone(), class_name: class_name.clone() });
+            }
+            classes_by_state.entry(&state[..]).or_default().push(class_name.clone());
+        }
+    }
+    let mut overlapping: Vec<(&str, Vec<String>)> = classes_by_state
+        .into_iter()
+        .filter(|(_, class_names)| class_names.len() > 1)
+        .map(|(state, mut class_names)| {
+            class_names.sort();
+            (state, class_names)
+        })
+        .collect();
+    overlapping.sort();
+    for (state, class_names) in overlapping {
+        warnings.push(LintWarning::OverlappingColorClasses { state: state.to_string(), class_names });
+    }
+
+    warnings
+}
+
+/// Runs `chitin check` on a manifest file, printing any warnings. Returns the
+/// process exit code: 0 for a clean manifest, 1 otherwise.
+pub fn check_manifest_file(input_file: PathBuf, search_path: &[PathBuf]) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let warnings = lint_manifest(&manifest);
+    for warning in warnings.iter() {
+        println!("warning: {warning}");
+    }
+    println!(
+        "{input_file:?}: {} rules, {} warning(s)",
+        manifest.transition_rules.len(),
+        warnings.len()
+    );
+    if warnings.is_empty() { 0 } else { 1 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{lint_manifest, LintWarning};
+
+    const MANIFEST: &str = "\
+!START_INIT_STATE
+A B
+B A
+!END_INIT_STATE
+!START_TRANSITION_RULES
+A + B -> C + B (1)
+C -> A (2)
+A + B -> C + B (3)
+B + A -> B + C (1)
+D -> A (1)
+C + D -> A + A (1)
+B -> A (0)
+!END_TRANSITION_RULES
+!START_COLORMAP
+{Unused} E: (0,0,0)
+{First} A, C: (255,0,0)
+{Second} C: (0,255,0)
+!END_COLORMAP";
+
+    #[test]
+    fn test_lint_warnings() {
+        let manifest = settings_input::manifest(MANIFEST).unwrap();
+        let warnings = lint_manifest(&manifest);
+
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::UnreachableRule { rule_idx: 4, .. })));
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::UnreachableRule { rule_idx: 5, .. })));
+        let unreachable = warnings.iter().find(|w| matches!(w, LintWarning::UnreachableRule { rule_idx: 4, .. })).unwrap();
+        assert!(unreachable.to_string().contains(": D never appears starting"), "{unreachable}");
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::DuplicateRule { rule_idx: 2, original_idx: 0, .. })));
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::MirroredRule { rule_idx: 3, original_idx: 0, .. })));
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::ZeroRateRule { rule_idx: 6, .. })));
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::ColormapOnlyState { state, .. } if state == "E")));
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::OverlappingColorClasses { state, .. } if state == "C")));
+        assert_eq!(warnings.len(), 7);
+    }
+
+    #[test]
+    fn test_dead_rule_keeps_invariant() {
+        // Ghost -> Ant would add an ant, but there are no ghosts.
+        let manifest = settings_input::manifest("\
+!INVARIANT Ant + Carrying
+!START_INIT_STATE
+Ant Empty
+!END_INIT_STATE
+!START_TRANSITION_RULES
+Ant + Empty -> Empty + Ant (1)
+Ghost -> Ant (1)
+!END_TRANSITION_RULES").unwrap();
+        let warnings = lint_manifest(&manifest);
+        assert!(!warnings.iter().any(|w| matches!(w, LintWarning::BrokenInvariant { .. })), "{warnings:?}");
+        assert!(warnings.iter().any(|w| matches!(w, LintWarning::UnreachableRule { rule_idx: 1, .. })));
+    }
+
+    #[test]
+    fn test_clean_manifest() {
+        let manifest = settings_input::manifest("\
+!START_INIT_STATE
+A B
+!END_INIT_STATE
+!START_TRANSITION_RULES
+A + B -> B + A (1)
+!END_TRANSITION_RULES").unwrap();
+        assert_eq!(lint_manifest(&manifest), vec![]);
+    }
+}
@@ -2,27 +2,51 @@ mod renderer;
 mod state;
 mod input;
 mod input_parsers;
+mod manifest_lexer;
 mod simulator;
 mod reactions;
 mod textures;
 mod button;
+mod lint;
+mod analysis;
+mod settings_schema;
+mod init_states;
+mod scenario_chooser;
+mod manifest_writer;
+mod dot_export;
+mod structured_manifest;
+mod model_checker;
+mod equivalence;
+mod simplify;
+mod trace;
+mod ca_compiler;
+mod monte_carlo;
+mod sweep;
+mod mean_field;
+mod rate_inference;
+mod optimizer;
+mod patterns;
+mod agents;
+mod kymograph;
+mod probes;
 
-use sdl2::image::{self, InitFlag, LoadTexture};
+use sdl2::image::{self, InitFlag, LoadTexture, SaveSurface};
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
 use sdl2::render::Texture;
 use sdl2::surface::Surface;
 use sdl2::ttf::{Sdl2TtfContext, Font};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
 
 use std::collections::HashMap;
 
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-use crate::input::{load_from_file, get_input_file};
+use crate::input::{get_image_output_file, get_input_file, get_output_file, get_waveform_output_file, include_search_path, load_from_manifest, parse_manifest_file};
 
 // fn get_opengl_backend_idx() -> Option<u32>{ 
 //     for (index, item) in sdl2::render::drivers().enumerate() {
@@ -33,7 +57,429 @@ use crate::input::{load_from_file, get_input_file};
 //     None
 // }
 
+/// The flags a command takes: ones followed by a value, as in
+/// `--scenario spiral`, and switches that stand on their own. Every command
+/// also takes `--include-path <dir>`, as many times as needed.
+struct CommandFlags {
+    values: &'static [&'static str],
+    switches: &'static [&'static str],
+}
+
+/// Each command-line tool's flags.
+const COMMAND_FLAGS: &[(&str, CommandFlags)] = &[
+    ("check", CommandFlags { values: &[], switches: &[] }),
+    ("analyze", CommandFlags { values: &["--scenario"], switches: &[] }),
+    ("normalize", CommandFlags { values: &["--scenario"], switches: &[] }),
+    ("convert", CommandFlags { values: &["--scenario"], switches: &[] }),
+    ("dot", CommandFlags { values: &["--scenario"], switches: &["--classes"] }),
+    ("explore", CommandFlags { values: &["--cell", "--witness", "--max-boards", "--scenario"], switches: &["--deadlock"] }),
+    ("equiv", CommandFlags { values: &["--observe", "--witness", "--max-boards", "--scenario"], switches: &[] }),
+    ("simplify", CommandFlags { values: &["--scenario"], switches: &[] }),
+    ("compile-ca", CommandFlags { values: &[], switches: &[] }),
+    ("estimate", CommandFlags {
+        values: &["--until", "--time", "--runs", "--threshold", "--confidence", "--bins", "--seed", "--times", "--scenario"],
+        switches: &[]
+    }),
+    ("sweep", CommandFlags { values: &["--scenario"], switches: &[] }),
+    ("ode", CommandFlags { values: &["--time", "--samples", "--coordination", "--seed", "--scenario"], switches: &["--pair"] }),
+    ("infer", CommandFlags { values: &["--simulate", "--seed", "--confidence", "--scenario"], switches: &[] }),
+    ("optimize", CommandFlags { values: &["--scenario"], switches: &[] }),
+    ("patterns", CommandFlags { values: &["--by", "--time", "--samples", "--seed", "--spectrum", "--scenario"], switches: &[] }),
+    ("agents", CommandFlags { values: &["--trace", "--time", "--samples", "--seed", "--msd", "--scenario"], switches: &[] }),
+    ("kymograph", CommandFlags {
+        values: &["--line", "--time", "--samples", "--seed", "--scale", "--front", "--scenario"],
+        switches: &[]
+    }),
+    ("probes", CommandFlags { values: &["--trace", "--time", "--seed", "--resolution", "--scenario"], switches: &[] }),
+    ("settings", CommandFlags { values: &[], switches: &[] }),
+];
+
+/// The flags for opening a manifest in the GUI.
+const GUI_FLAGS: CommandFlags = CommandFlags { values: &["--scenario", "--trace", "--kymograph"], switches: &[] };
+
+/// A command line sorted into positional arguments (counting the program
+/// name as the 0th) and the flags given.
+struct CommandLine<'a> {
+    positionals: Vec<&'a String>,
+    values: Vec<(&'a str, &'a String)>,
+    switches: Vec<&'a str>,
+}
+
+impl<'a> CommandLine<'a> {
+    /// Sorts out `args` by the flags a command takes. A flag it doesn't take,
+    /// or one missing its value, is an error.
+    fn parse(args: &'a [String], flags: &CommandFlags) -> Result<CommandLine<'a>, String> {
+        let mut command_line = CommandLine { positionals: Vec::new(), values: Vec::new(), switches: Vec::new() };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                command_line.positionals.push(arg);
+            } else if flags.switches.contains(&arg.as_str()) {
+                command_line.switches.push(arg);
+            } else if arg == "--include-path" || flags.values.contains(&arg.as_str()) {
+                let value = args.next().ok_or_else(|| format!("{arg} needs a value after it"))?;
+                command_line.values.push((arg, value));
+            } else {
+                let known: Vec<&str> = flags.values.iter().chain(flags.switches).copied().chain(["--include-path"]).collect();
+                return Err(format!("unknown flag {arg}; the flags here are {}", known.join(", ")));
+            }
+        }
+        Ok(command_line)
+    }
+
+    /// The nth positional argument.
+    fn positional(&self, n: usize) -> Option<&'a String> {
+        self.positionals.get(n).copied()
+    }
+
+    /// The value given after a flag, as in `--scenario spiral`.
+    fn value(&self, flag: &str) -> Option<&'a String> {
+        self.values.iter().find(|(name, _)| *name == flag).map(|(_, value)| *value)
+    }
+
+    /// Every value given after a flag that can be repeated, as in
+    /// `--include-path rules --include-path shared`.
+    fn values(&self, flag: &str) -> Vec<&'a String> {
+        self.values.iter().filter(|(name, _)| *name == flag).map(|(_, value)| *value).collect()
+    }
+
+    /// Whether a switch was given.
+    fn switch(&self, flag: &str) -> bool {
+        self.switches.contains(&flag)
+    }
+}
+
+/// Runs one of the command-line tools, if one was asked for. Returns the exit
+/// code to finish with, or None if the GUI should start instead.
+fn run_command(args: &[String]) -> Option<i32> {
+    let command = args.get(1)?;
+    let (_, flags) = COMMAND_FLAGS.iter().find(|(name, _)| name == command)?;
+    let args = match CommandLine::parse(args, flags) {
+        Ok(command_line) => command_line,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return Some(2);
+        }
+    };
+    let search_path = include_search_path(&args.values("--include-path"));
+    let exit_code = match command.as_str() {
+        "check" => match args.positional(2) {
+            Some(manifest_file) => lint::check_manifest_file(PathBuf::from(manifest_file), &search_path),
+            None => {
+                eprintln!("Usage: chitin check <manifest> [--include-path <dir>]");
+                2
+            }
+        },
+        "analyze" => match args.positional(2) {
+            Some(manifest_file) => analysis::analyze_manifest_file(
+                PathBuf::from(manifest_file),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!("Usage: chitin analyze <manifest> [--scenario <name>] [--include-path <dir>]");
+                2
+            }
+        },
+        "normalize" => match args.positional(2) {
+            Some(manifest_file) => manifest_writer::normalize_manifest_file(
+                PathBuf::from(manifest_file),
+                args.positional(3).map(PathBuf::from),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!("Usage: chitin normalize <manifest> [output file] [--scenario <name>] [--include-path <dir>]");
+                2
+            }
+        },
+        "convert" => match (args.positional(2), args.positional(3)) {
+            (Some(input_file), Some(output_file)) => structured_manifest::convert_manifest_file(
+                PathBuf::from(input_file),
+                PathBuf::from(output_file),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            _ => {
+                eprintln!(
+                    "Usage: chitin convert <manifest> <output file (.txt, .toml, or .json)> [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "dot" => match args.positional(2) {
+            Some(manifest_file) => dot_export::export_dot_file(
+                PathBuf::from(manifest_file),
+                args.positional(3).map(PathBuf::from),
+                args.switch("--classes"),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!("Usage: chitin dot <manifest> [output file] [--classes] [--scenario <name>] [--include-path <dir>]");
+                2
+            }
+        },
+        "explore" => match args.positional(2) {
+            Some(manifest_file) => model_checker::explore_manifest_file(
+                PathBuf::from(manifest_file),
+                args.value("--cell").map(|s| &s[..]),
+                args.switch("--deadlock"),
+                args.value("--witness").map(PathBuf::from),
+                args.value("--max-boards").map(|s| &s[..]),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!(
+                    "Usage: chitin explore <manifest> (--cell <row,column=state> | --deadlock) [--witness <trace file>] \
+                     [--max-boards <n>] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "equiv" => match (args.positional(2), args.positional(3)) {
+            (Some(first_file), Some(second_file)) => equivalence::compare_manifest_files(
+                [PathBuf::from(first_file), PathBuf::from(second_file)],
+                args.value("--observe").map(|s| &s[..]),
+                args.value("--witness").map(PathBuf::from),
+                args.value("--max-boards").map(|s| &s[..]),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            _ => {
+                eprintln!(
+                    "Usage: chitin equiv <manifest> <other manifest> [--observe <state,first=second,...>] [--witness <trace file>] \
+                     [--max-boards <n>] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "simplify" => match args.positional(2) {
+            Some(manifest_file) => simplify::simplify_manifest_file(
+                PathBuf::from(manifest_file),
+                args.positional(3).map(PathBuf::from),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!("Usage: chitin simplify <manifest> [output file] [--scenario <name>] [--include-path <dir>]");
+                2
+            }
+        },
+        "compile-ca" => match (args.positional(2), args.positional(3)) {
+            (Some(input_file), Some(output_file)) => ca_compiler::compile_ca_file(PathBuf::from(input_file), PathBuf::from(output_file)),
+            _ => {
+                eprintln!("Usage: chitin compile-ca <automaton (.toml or .json)> <output file (.txt, .toml, or .json)>");
+                2
+            }
+        },
+        "estimate" => match (args.positional(2), args.value("--until")) {
+            (Some(manifest_file), Some(until)) => monte_carlo::estimate_manifest_file(
+                PathBuf::from(manifest_file),
+                monte_carlo::EstimateOptions {
+                    until,
+                    time_bound: args.value("--time").map(|s| &s[..]),
+                    runs: args.value("--runs").map(|s| &s[..]),
+                    threshold: args.value("--threshold").map(|s| &s[..]),
+                    confidence: args.value("--confidence").map(|s| &s[..]),
+                    bins: args.value("--bins").map(|s| &s[..]),
+                    seed: args.value("--seed").map(|s| &s[..]),
+                    times_file: args.value("--times").map(PathBuf::from),
+                },
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            _ => {
+                eprintln!(
+                    "Usage: chitin estimate <manifest> --until <condition> [--time <bound>] [--runs <n>] [--threshold <probability>] \
+                     [--confidence <level>] [--bins <n>] [--seed <n>] [--times <csv file>] [--scenario <name>] [--include-path <dir>]"
+                );
+                eprintln!("Conditions look like 2,3=A or count(A)>=10, joined with & (and) and | (or).");
+                2
+            }
+        },
+        "sweep" => match (args.positional(2), args.positional(3)) {
+            (Some(manifest_file), Some(sweep_file)) => sweep::sweep_manifest_file(
+                PathBuf::from(manifest_file),
+                PathBuf::from(sweep_file),
+                args.positional(4).map(PathBuf::from),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            _ => {
+                eprintln!(
+                    "Usage: chitin sweep <manifest> <sweep (.toml or .json)> [output csv file] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "ode" => match args.positional(2) {
+            Some(manifest_file) => mean_field::ode_manifest_file(
+                PathBuf::from(manifest_file),
+                args.positional(3).map(PathBuf::from),
+                mean_field::OdeOptions {
+                    pair: args.switch("--pair"),
+                    time: args.value("--time").map(|s| &s[..]),
+                    samples: args.value("--samples").map(|s| &s[..]),
+                    coordination: args.value("--coordination").map(|s| &s[..]),
+                    seed: args.value("--seed").map(|s| &s[..]),
+                },
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!(
+                    "Usage: chitin ode <manifest> [output csv file] [--pair] [--time <t>] [--samples <n>] [--coordination <neighbors>] \
+                     [--seed <n>] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "infer" => match args.positional(2) {
+            Some(manifest_file) => rate_inference::infer_manifest_file(
+                PathBuf::from(manifest_file),
+                args.positional(3).map(PathBuf::from),
+                args.value("--simulate").map(|s| &s[..]),
+                args.value("--seed").map(|s| &s[..]),
+                args.value("--confidence").map(|s| &s[..]),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!(
+                    "Usage: chitin infer <manifest> (<trace file> | --simulate <time>) [--seed <n>] [--confidence <level>] \
+                     [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "optimize" => match (args.positional(2), args.positional(3)) {
+            (Some(manifest_file), Some(spec_file)) => optimizer::optimize_manifest_file(
+                PathBuf::from(manifest_file),
+                PathBuf::from(spec_file),
+                args.positional(4).map(PathBuf::from),
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            _ => {
+                eprintln!(
+                    "Usage: chitin optimize <manifest> <optimization spec (.toml or .json)> [tuned manifest output file] \
+                     [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "patterns" => match args.positional(2) {
+            Some(manifest_file) => patterns::patterns_manifest_file(
+                PathBuf::from(manifest_file),
+                args.positional(3).map(PathBuf::from),
+                patterns::PatternOptions {
+                    by: args.value("--by").map(|s| &s[..]),
+                    time: args.value("--time").map(|s| &s[..]),
+                    samples: args.value("--samples").map(|s| &s[..]),
+                    seed: args.value("--seed").map(|s| &s[..]),
+                    spectrum: args.value("--spectrum").map(|s| &s[..]),
+                },
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!(
+                    "Usage: chitin patterns <manifest> [output csv file] [--by state|class] [--time <t>] [--samples <n>] [--seed <n>] \
+                     [--spectrum <csv file>] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "agents" => match args.positional(2) {
+            Some(manifest_file) => agents::agents_manifest_file(
+                PathBuf::from(manifest_file),
+                args.positional(3).map(PathBuf::from),
+                agents::AgentOptions {
+                    trace: args.value("--trace").map(PathBuf::from),
+                    time: args.value("--time").map(|s| &s[..]),
+                    samples: args.value("--samples").map(|s| &s[..]),
+                    seed: args.value("--seed").map(|s| &s[..]),
+                    msd: args.value("--msd").map(|s| &s[..]),
+                },
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            None => {
+                eprintln!(
+                    "Usage: chitin agents <manifest> [trajectory csv file] [--trace <file> | --time <t> --seed <n>] \
+                     [--msd <csv file>] [--samples <n>] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "kymograph" => match (args.positional(2), args.positional(3)) {
+            (Some(manifest_file), Some(output_file)) => kymograph::kymograph_manifest_file(
+                PathBuf::from(manifest_file),
+                PathBuf::from(output_file),
+                kymograph::KymographOptions {
+                    line: args.value("--line").map(|s| &s[..]),
+                    time: args.value("--time").map(|s| &s[..]),
+                    samples: args.value("--samples").map(|s| &s[..]),
+                    seed: args.value("--seed").map(|s| &s[..]),
+                    scale: args.value("--scale").map(|s| &s[..]),
+                    front: args.value("--front").map(|s| &s[..]),
+                },
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            _ => {
+                eprintln!(
+                    "Usage: chitin kymograph <manifest> <output png file> [--line <row> | --line <row,col>:<row,col>] [--time <t>] \
+                     [--samples <n>] [--seed <n>] [--scale <pixels>] [--front <state>] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "probes" => match (args.positional(2), args.positional(3)) {
+            (Some(manifest_file), Some(output_file)) => probes::probes_manifest_file(
+                PathBuf::from(manifest_file),
+                PathBuf::from(output_file),
+                probes::ProbeOptions {
+                    trace: args.value("--trace").map(PathBuf::from),
+                    time: args.value("--time").map(|s| &s[..]),
+                    seed: args.value("--seed").map(|s| &s[..]),
+                    resolution: args.value("--resolution").map(|s| &s[..]),
+                },
+                args.value("--scenario").map(|s| &s[..]),
+                &search_path
+            ),
+            _ => {
+                eprintln!(
+                    "Usage: chitin probes <manifest> <output vcd file> [--trace <file> | --time <t> --seed <n>] \
+                     [--resolution <ticks per unit time>] [--scenario <name>] [--include-path <dir>]"
+                );
+                2
+            }
+        },
+        "settings" => {
+            print!("{}", settings_schema::settings_documentation());
+            0
+        },
+        _ => return None
+    };
+    Some(exit_code)
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(exit_code) = run_command(&args) {
+        std::process::exit(exit_code);
+    }
+    let args = match CommandLine::parse(&args, &GUI_FLAGS) {
+        Ok(command_line) => command_line,
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(2);
+        }
+    };
+
     let profiling = true;
     println!("Hello, world! Starting up...");
 
@@ -57,9 +503,57 @@ fn main() {
     let text_context: Sdl2TtfContext = sdl2::ttf::init().unwrap();
     let default_font: Font = text_context.load_font("fonts/Swansea-q3pd.ttf",16).unwrap();
 
-    // Load init file.
-    let init_file =  get_input_file();
-    let (mut sim_components, settings, mut global_state) = load_from_file(init_file);
+    // Load init file, from the command line if one was given.
+    let init_file = match args.positional(1) {
+        Some(path) => PathBuf::from(path),
+        None => get_input_file()
+    };
+    let manifest = parse_manifest_file(init_file.clone(), &include_search_path(&args.values("--include-path")));
+    let scenario = match args.value("--scenario") {
+        Some(name) => Some(name.clone()),
+        None if manifest.scenarios.len() > 1 => {
+            match scenario_chooser::choose_scenario(&sdl_context, &video_subsystem, &default_font, &manifest.scenario_names()) {
+                Some(name) => Some(name),
+                None => return
+            }
+        },
+        None => None
+    };
+    let loaded = manifest.with_scenario(scenario.as_deref()).and_then(|manifest| {
+        load_from_manifest(&manifest, None).map(|simulation| (manifest, simulation))
+    });
+    let (manifest, (mut sim_components, settings, mut global_state)) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {init_file:?}: {err}");
+            std::process::exit(1);
+        }
+    };
+    // A trace (like a witness from `chitin explore`) replaces the random history.
+    let trace_file = args.value("--trace");
+    if let Some(trace_file) = trace_file {
+        if let Err(err) = trace::load_trace(Path::new(trace_file), &mut sim_components, &settings) {
+            panic!("Couldn't load trace: {err}");
+        }
+    }
+
+    // Follow agents, if the manifest names any, to draw their trails, and
+    // record the levels of probe cells for the waveform panel.
+    let followers = agents::AgentTracker::new(&manifest, &sim_components, &settings)
+        .and_then(|agent_tracker| Ok((agent_tracker, probes::ProbeRecorder::new(&manifest, &sim_components, &settings)?)));
+    let (mut agent_tracker, mut probe_recorder) = match followers {
+        Ok(followers) => followers,
+        Err(err) => panic!("Couldn't track agents or probes: {err}")
+    };
+
+    // Sample a row or line over time for a kymograph panel, once a frame
+    // when playing at normal speed.
+    let mut kymograph = args.value("--kymograph").map(|spec| {
+        match kymograph::parse_line(spec, &settings) {
+            Ok(cells) => kymograph::Kymograph::new(cells, (settings.speedup_factor / settings.fps) as f64),
+            Err(err) => panic!("Couldn't follow {spec:?} for a kymograph: {err}")
+        }
+    });
 
     // Pre-render graphics and figure out how big the screen will need to be.
     let prerendered_surfaces = renderer::prerender_surfaces(
@@ -69,8 +563,11 @@ fn main() {
     );
 
     // Make a window
-    let (window_width, window_height): (u32, u32) = renderer::calculate_window_size(&mut sim_components, &settings, &mut global_state, &prerendered_surfaces);
-    let window = video_subsystem.window("Chitin", window_width, window_height)
+    let (window_width, window_height): (u32, u32) = renderer::calculate_window_size(
+        &mut sim_components, &settings, &mut global_state, &prerendered_surfaces, kymograph.as_ref()
+    );
+    let waveform_height = |n_probes: usize| window_height + renderer::waveform_panel_height(&settings, n_probes);
+    let window = video_subsystem.window("Chitin", window_width, waveform_height(probe_recorder.probes.len()))
         .opengl()
         .position_centered()
         .build()
@@ -164,7 +661,9 @@ fn main() {
     println!("Simulation starts on? {}", global_state.is_playing);
 
     // Set up the event queue
-    simulator::initialize_queue(&sim_components, &mut global_state, &settings);
+    if trace_file.is_none() {
+        simulator::initialize_queue(&sim_components, &mut global_state, &settings);
+    }
 
     // Set up event loop
     let mut event_pump = sdl_context.event_pump().unwrap();
@@ -210,6 +709,49 @@ fn main() {
                         global_state.tick = true;
                     }
                 },
+                Event::KeyDown{keycode: Some(Keycode::S), keymod, ..} => {
+                    // Save the board as the start of a new manifest: the one on
+                    // screen, or with shift held, the furthest-simulated one.
+                    let board = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        manifest_writer::BoardSnapshot::Latest
+                    } else {
+                        manifest_writer::BoardSnapshot::Current
+                    };
+                    if let Some(path) = get_output_file() {
+                        manifest_writer::save_manifest(&path, &sim_components, &settings, &manifest, board);
+                    }
+                },
+                Event::KeyDown{keycode: Some(Keycode::P), ..} => {
+                    // Describe the clusters and interfaces on screen.
+                    let groups = patterns::Groups::new(&sim_components, patterns::Grouping::Class);
+                    let stats = patterns::pattern_stats(&sim_components.current_states, &groups, &settings);
+                    print!("{}", patterns::describe_patterns(&stats, &groups, &settings));
+                },
+                Event::KeyDown{keycode: Some(Keycode::K), ..} => {
+                    // Save the kymograph so far as an image.
+                    if let Some(kymograph) = kymograph.as_ref() {
+                        if let Some(path) = get_image_output_file() {
+                            if let Err(err) = kymograph.to_surface(&sim_components, 4).and_then(|surface| surface.save(&path)) {
+                                eprintln!("Couldn't save the kymograph to {path:?}: {err}");
+                            }
+                        }
+                    }
+                },
+                Event::KeyDown{keycode: Some(Keycode::V), ..} if !probe_recorder.probes.is_empty() => {
+                    // Save the probes' waveforms for a waveform viewer.
+                    if let Some(path) = get_waveform_output_file() {
+                        if let Err(err) = std::fs::write(&path, probes::write_vcd(&probe_recorder.probes, 1000.0)) {
+                            eprintln!("Couldn't save the waveforms to {path:?}: {err}");
+                        }
+                    }
+                },
+                Event::MouseButtonDown{mouse_btn: MouseButton::Right, x, y, ..} => {
+                    // Right-clicking a cell starts or stops probing it.
+                    if let Some(loc) = renderer::cell_at(&sim_components, &settings, x, y) {
+                        probe_recorder.toggle_probe(loc, &sim_components);
+                        canvas.window_mut().set_size(window_width, waveform_height(probe_recorder.probes.len())).ok();
+                    }
+                },
                 Event::MouseButtonDown{..} | Event::MouseButtonUp{..} => {
                     button::process_click(&event, &mut sim_components, &mut global_state, &settings);
                 }
@@ -225,6 +767,19 @@ fn main() {
 
         // Render results
         renderer::render(&mut canvas, &sim_components, &global_state, &settings, &default_font, &state_textures, &prerendered_textures);
+        if let Some(tracker) = agent_tracker.as_mut() {
+            tracker.catch_up(&sim_components);
+            renderer::draw_agent_trails(&mut canvas, &sim_components, &global_state, tracker);
+        }
+        probe_recorder.catch_up(&sim_components);
+        if !probe_recorder.probes.is_empty() {
+            renderer::draw_waveforms(&mut canvas, &sim_components, &global_state, &settings, &default_font, &probe_recorder, window_height);
+        }
+        if let Some(kymograph) = kymograph.as_mut() {
+            kymograph.follow(global_state.current_t as f64, &sim_components.current_states);
+            renderer::draw_kymograph(&mut canvas, &sim_components, &settings, &prerendered_textures, kymograph);
+        }
+        canvas.present();
 
         // Spend idle time building up simulation history, leaving a bit of buffer time.
         if one_tick > 2 * avg_rxn_sim_time && tick_start_time.elapsed() < one_tick - 2 * avg_rxn_sim_time {
@@ -242,4 +797,29 @@ fn main() {
         flame::end("main");
         flamescope::dump(&mut File::create("flamescope.json").unwrap()).unwrap();
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandFlags, CommandLine};
+
+    #[test]
+    fn test_command_line() {
+        let flags = CommandFlags { values: &["--scenario"], switches: &["--classes"] };
+        let args: Vec<String> = ["chitin", "dot", "--classes", "in.txt", "--scenario", "fast", "out.dot"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let command_line = CommandLine::parse(&args, &flags).unwrap();
+        assert_eq!(command_line.positional(2).map(|s| &s[..]), Some("in.txt"));
+        assert_eq!(command_line.positional(3).map(|s| &s[..]), Some("out.dot"));
+        assert_eq!(command_line.value("--scenario").map(|s| &s[..]), Some("fast"));
+        assert!(command_line.switch("--classes"));
+
+        // --pair isn't one of these flags, and --scenario needs a value.
+        let err = CommandLine::parse(&[args.clone(), vec!["--pair".to_string()]].concat(), &flags).err().unwrap();
+        assert!(err.starts_with("unknown flag --pair"), "{err}");
+        assert!(CommandLine::parse(&args[..5], &flags).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+/// Cleans up manifest text before the grammar sees it, so the grammar only has
+/// to handle one spelling of everything. Windows (CRLF) and old Mac (CR) line
+/// endings become plain newlines; tabs and runs of spaces become a single
+/// space, with leading and trailing whitespace dropped; and a `#` starts a
+/// comment that runs to the end of the line, so comments can follow a rule or
+/// setting. Anything inside double quotes ("Edge-U") is passed through
+/// untouched. Each line of the input becomes exactly one line of the output,
+/// so line numbers still match the original file; errors come with the
+/// (1-based) line they're on.
+pub fn normalize_manifest_text(text: &str) -> Result<String, (usize, String)> {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let mut normalized = String::with_capacity(text.len());
+    for (line_idx, line) in text.split('\n').enumerate() {
+        if line_idx > 0 {
+            normalized.push('\n');
+        }
+        normalized.push_str(&normalize_line(line).map_err(|err| (line_idx + 1, err))?);
+    }
+    Ok(normalized)
+}
+
+fn normalize_line(line: &str) -> Result<String, String> {
+    let mut normalized = String::with_capacity(line.len());
+    let mut in_quotes = false;
+    let mut pending_space = false;
+    for c in line.chars() {
+        if in_quotes {
+            normalized.push(c);
+            in_quotes = c != '"';
+            continue;
+        }
+        match c {
+            '#' => break,
+            ' ' | '\t' => pending_space = true,
+            _ => {
+                if pending_space && !normalized.is_empty() {
+                    normalized.push(' ');
+                }
+                pending_space = false;
+                normalized.push(c);
+                in_quotes = c == '"';
+            }
+        }
+    }
+    if in_quotes {
+        return Err("unterminated quoted name".to_string());
+    }
+    Ok(normalized)
+}
+
+/// Whether a state name can be written in a manifest without quotes.
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Writes a state name the way a manifest needs it: as-is if it's plain, or
+/// in double quotes if it has spaces or punctuation.
+pub fn quote_name(name: &str) -> String {
+    if is_plain_name(name) {
+        name.to_string()
+    } else {
+        format!("\"{name}\"")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{normalize_manifest_text, quote_name};
+
+    #[test]
+    fn test_normalize_manifest_text() {
+        assert_eq!(
+            normalize_manifest_text("fps\t= 30  # frames\r\n\t!START_INIT_STATE \r\n\"Edge  #1\"  B\r\n").unwrap(),
+            "fps = 30\n!START_INIT_STATE\n\"Edge  #1\" B\n"
+        );
+        assert!(normalize_manifest_text("A\n\"B C\nD").is_err());
+        assert_eq!(quote_name("1Ax"), "1Ax");
+        assert_eq!(quote_name("Edge-U"), "\"Edge-U\"");
+    }
+
+    #[test]
+    fn test_messy_manifest() {
+        let text = "# Saved on Windows\r\n\
+                    wrap = true # wraps around\r\n\
+                    geometry = \"hex\"\r\n\
+                    !START_INIT_STATE\r\n\
+                    \t\"Edge-U\", 1Ax\t\r\n\
+                    \r\n\
+                    1Ax,  \"Edge-U\"\r\n\
+                    !END_INIT_STATE\r\n\
+                    !START_TRANSITION_RULES\r\n\
+                    \"Edge-U\" + 1Ax -> 1Ax + \"Edge-U\" (2) # swap\r\n\
+                    !END_TRANSITION_RULES\r\n";
+        let manifest = settings_input::manifest(&normalize_manifest_text(text).unwrap()).unwrap();
+        assert_eq!(manifest.variables[1], ("geometry".to_string(), "hex".to_string()));
+        assert_eq!(manifest.transition_rules[0].r1, "Edge-U");
+        assert_eq!(manifest.transition_rules[0].r2.as_deref(), Some("1Ax"));
+
+        let grid = manifest.init_states[0].expand(&manifest).unwrap();
+        assert_eq!((grid.n_rows, grid.n_cols), (2, 2));
+        assert_eq!(grid.states, vec!["Edge-U", "1Ax", "1Ax", "Edge-U"]);
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::input_parsers::ParsedManifest;
+use crate::manifest_lexer::quote_name;
+use crate::probes::{Level, StateOrClass};
+use crate::settings_schema::{default_settings, SETTINGS_SCHEMA};
+use crate::state::{Settings, SimulatorComponents};
+
+/// Which board to write out as the initial state.
+#[derive(Debug, Clone, Copy)]
+pub enum BoardSnapshot {
+    Current, // What's on screen, at the current playback time.
+    Latest,  // The furthest-simulated board.
+}
+
+/// Writes a manifest that loads back into the same settings, colors, rules,
+/// and (chosen) board. Settings that are at their defaults are left out.
+/// `source` is the parsed manifest (with its scenario applied) the simulation
+/// was built from; the declarations that don't make it into the simulation,
+/// like invariants, agents, and probes, are copied from it.
+pub fn write_manifest(components: &SimulatorComponents, settings: &Settings, source: &ParsedManifest, board: BoardSnapshot) -> String {
+    let mut manifest = String::from("# Written by chitin.\n");
+
+    // Settings
+    let defaults = default_settings();
+    for spec in SETTINGS_SCHEMA {
+        let value = (spec.format)(settings);
+        if value != (spec.format)(&defaults) {
+            manifest.push_str(&format!("{} = {value}\n", spec.name));
+        }
+    }
+
+    // Colormap, one line per class the manifest declared. States it left
+    // out got a random color when loaded, which isn't worth writing down.
+    let mut class_states: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (state_id, class_id) in components.state_colorclasses.iter() {
+        // States re-added by an overlapping colormap class leave an old id
+        // behind; only write the one the name points to now.
+        if components.state_ids.get(&components.state_names[state_id]) == Some(state_id) {
+            class_states.entry(*class_id).or_default().push(*state_id);
+        }
+    }
+    manifest.push_str("\n!START_COLORMAP\n");
+    for (class_id, class_name) in components.colorclass_names.iter().enumerate() {
+        let Some(states) = class_states.get(&class_id).filter(|_| !components.implicit_colorclasses.contains(&class_id)) else {
+            continue;
+        };
+        let mut state_names: Vec<&str> = states.iter().map(|state| &components.state_names[state][..]).collect();
+        state_names.sort();
+        let quoted_names: Vec<String> = state_names.iter().map(|name| quote_name(name)).collect();
+        let (r, g, b) = components.colorclass_colors[class_id].rgb();
+        if state_names == [class_name.as_str()] {
+            manifest.push_str(&format!("{}: ({r}, {g}, {b})\n", quoted_names[0]));
+        } else {
+            manifest.push_str(&format!("{{{class_name}}} {}: ({r}, {g}, {b})\n", quoted_names.join(", ")));
+        }
+    }
+    manifest.push_str("!END_COLORMAP\n");
+
+    // Transition rules
+    manifest.push_str("\n!START_TRANSITION_RULES\n");
+    for (rxn, rate) in components.all_reactions.iter().zip(components.all_rxn_rates.iter()) {
+        let name = |state: usize| quote_name(&components.state_names[&state]);
+        match (rxn.r2_num, rxn.p2_num) {
+            (Some(r2), Some(p2)) => manifest.push_str(&format!(
+                "{} + {} -> {} + {} ({rate})\n", name(rxn.r1_num), name(r2), name(rxn.p1_num), name(p2)
+            )),
+            _ => manifest.push_str(&format!("{} -> {} ({rate})\n", name(rxn.r1_num), name(rxn.p1_num))),
+        }
+    }
+    manifest.push_str("!END_TRANSITION_RULES\n");
+
+    // Declared invariants.
+    if !source.invariants.is_empty() {
+        manifest.push('\n');
+        for invariant in source.invariants.iter() {
+            manifest.push_str(&format!("!INVARIANT {invariant}\n"));
+        }
+    }
+
+    // Agent states, and how particular rules move agents.
+    if !source.agent_states.is_empty() || !source.agent_rules.is_empty() {
+        manifest.push('\n');
+        if !source.agent_states.is_empty() {
+            let states: Vec<String> = source.agent_states.iter().map(|state| quote_name(state)).collect();
+            manifest.push_str(&format!("!AGENTS {}\n", states.join(", ")));
+        }
+        for agent_rule in source.agent_rules.iter() {
+            let rule = &agent_rule.rule;
+            let side = |first: &str, second: &Option<String>| match second {
+                Some(second) => format!("{} + {}", quote_name(first), quote_name(second)),
+                None => quote_name(first),
+            };
+            // Reactants and products are numbered from 1 in a manifest.
+            let carries: Vec<String> = agent_rule.carries.iter().map(|(from, to)| format!(" {}->{}", from + 1, to + 1)).collect();
+            manifest.push_str(&format!("!AGENT_RULE {} -> {}:{}\n", side(&rule.r1, &rule.r2), side(&rule.p1, &rule.p2), carries.join(",")));
+        }
+    }
+
+    // Probes, and which states they read as high or low.
+    if !source.probes.is_empty() || !source.probe_levels.is_empty() {
+        manifest.push('\n');
+        for probe in source.probes.iter() {
+            manifest.push_str(&format!("!PROBE {} {},{}\n", probe.name, probe.row, probe.col));
+        }
+        for (level, keyword) in [(Level::High, "!PROBE_HIGH"), (Level::Low, "!PROBE_LOW")] {
+            let states: Vec<String> = source.probe_levels
+                .iter()
+                .filter(|(state_level, _)| *state_level == level)
+                .map(|(_, states)| match states {
+                    StateOrClass::State(name) => quote_name(name),
+                    StateOrClass::Class(name) => format!("{{{name}}}"),
+                })
+                .collect();
+            if !states.is_empty() {
+                manifest.push_str(&format!("{keyword} {}\n", states.join(", ")));
+            }
+        }
+    }
+
+    // Initial state
+    let board_states = match board {
+        BoardSnapshot::Current => &components.current_states,
+        BoardSnapshot::Latest => &components.latest_states,
+    };
+    manifest.push_str("\n!START_INIT_STATE\n");
+    for row in board_states.chunks(settings.n_cols) {
+        let row_names: Vec<String> = row.iter().map(|state| quote_name(&components.state_names[state])).collect();
+        manifest.push_str(&row_names.join(" "));
+        manifest.push('\n');
+    }
+    manifest.push_str("!END_INIT_STATE\n");
+    manifest
+}
+
+pub fn save_manifest(path: &Path, components: &SimulatorComponents, settings: &Settings, source: &ParsedManifest, board: BoardSnapshot) {
+    match fs::write(path, write_manifest(components, settings, source, board)) {
+        Ok(()) => println!("Saved manifest to {path:?}"),
+        Err(err) => eprintln!("Couldn't save manifest to {path:?}: {err}")
+    }
+}
+
+/// Runs `chitin normalize`: reads a manifest (with all of its includes) and
+/// writes it back out in canonical form, to a file or to stdout.
+pub fn normalize_manifest_file(
+    input_file: PathBuf,
+    output_file: Option<PathBuf>,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path).and_then(|manifest| manifest.with_scenario(scenario)) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings, _) = match load_from_manifest(&manifest, None) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let normalized = write_manifest(&components, &settings, &manifest, BoardSnapshot::Current);
+    match output_file {
+        Some(path) => match fs::write(&path, normalized) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("Couldn't write {path:?}: {err}");
+                1
+            }
+        },
+        None => {
+            print!("{normalized}");
+            0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::input::{load_from_manifest, parse_manifest_file};
+    use crate::input_parsers::{build_simulation, settings_input};
+
+    use super::{write_manifest, BoardSnapshot};
+
+    /// Loads manifest text and writes it back out.
+    fn normalize(text: &str) -> String {
+        let manifest = settings_input::manifest(text).unwrap();
+        let (components, settings) = build_simulation(&manifest).unwrap();
+        write_manifest(&components, &settings, &manifest, BoardSnapshot::Current)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let manifest = parse_manifest_file(PathBuf::from("test_resources/manifests/scenario_manifest.txt"), &[]);
+        let fast = manifest.with_scenario(Some("fast")).unwrap();
+        let (mut components, settings, _) = load_from_manifest(&fast, None).unwrap();
+        components.latest_states[0] = components.state_ids["C"];
+
+        let written = write_manifest(&components, &settings, &fast, BoardSnapshot::Latest);
+        let (reloaded, reloaded_settings) = settings_input::settings(&written).unwrap();
+        assert_eq!(reloaded_settings.speedup_factor, settings.speedup_factor);
+        assert_eq!((reloaded_settings.n_rows, reloaded_settings.n_cols), (settings.n_rows, settings.n_cols));
+        let names = |c: &crate::state::SimulatorComponents, states: &Vec<usize>| -> Vec<String> {
+            states.iter().map(|s| c.state_names[s].clone()).collect()
+        };
+        assert_eq!(names(&reloaded, &reloaded.current_states), names(&components, &components.latest_states));
+        assert_eq!(reloaded.all_rxn_rates, components.all_rxn_rates);
+
+        // Writing the reloaded manifest again changes nothing.
+        assert_eq!(normalize(&written), written);
+    }
+
+    #[test]
+    fn test_normalize_is_deterministic() {
+        // No colormap, so every state gets a random color when loaded.
+        let text = std::fs::read_to_string("test_resources/manifests/basic_settings_manifest.txt").unwrap();
+        let first = normalize(&text);
+        assert_eq!(normalize(&text), first);
+        assert!(!first.contains("A:"));
+
+        // Settings with nowhere else to go still survive the trip.
+        let first = normalize(&format!("frame_capture_rate = 2\n{text}"));
+        assert!(first.contains("frame_capture_rate = 2\n"));
+        assert_eq!(normalize(&first), first);
+    }
+
+    #[test]
+    fn test_declarations_round_trip() {
+        let text = "\
+!INVARIANT Ant + 2 Carrying
+!START_TRANSITION_RULES
+Ant + Food -> Carrying + Empty (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+Ant Food
+!END_INIT_STATE";
+        let written = normalize(text);
+        assert!(written.contains("\n!INVARIANT Ant + 2 Carrying\n"), "{written}");
+        assert_eq!(normalize(&written), written);
+        assert_eq!(settings_input::manifest(&written).unwrap().invariants, settings_input::manifest(text).unwrap().invariants);
+    }
+
+    #[test]
+    fn test_agents_round_trip() {
+        let text = "\
+!AGENTS A, B
+!AGENT_RULE A + B -> B + A: 1->2, 2->1
+!AGENT_RULE A -> O:
+!START_TRANSITION_RULES
+A + B -> B + A (1)
+A -> O (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A B O
+!END_INIT_STATE";
+        let written = normalize(text);
+        assert!(written.contains("\n!AGENTS A, B\n!AGENT_RULE A + B -> B + A: 1->2, 2->1\n!AGENT_RULE A -> O:\n"), "{written}");
+        assert_eq!(normalize(&written), written);
+
+        let carries = |text: &str| settings_input::manifest(text).unwrap().agent_rules
+            .into_iter()
+            .map(|agent_rule| (agent_rule.rule.r1, agent_rule.rule.r2, agent_rule.rule.p1, agent_rule.rule.p2, agent_rule.carries))
+            .collect::<Vec<_>>();
+        assert_eq!(carries(&written), carries(text));
+        assert_eq!(settings_input::manifest(&written).unwrap().agent_states, ["A", "B"]);
+    }
+
+    #[test]
+    fn test_probes_round_trip() {
+        let text = "\
+!PROBE out 0,1
+!PROBE_HIGH On, {Lit}
+!PROBE_LOW Off
+!START_COLORMAP
+{Lit} Glow: (255, 255, 0)
+!END_COLORMAP
+!START_TRANSITION_RULES
+On + Off -> Off + On (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+On Off Glow
+!END_INIT_STATE";
+        let written = normalize(text);
+        assert!(written.contains("!PROBE out 0,1\n"), "{written}");
+        assert!(written.contains("!PROBE_HIGH On, {Lit}\n"), "{written}");
+        assert_eq!(normalize(&written), written);
+
+        let before = settings_input::manifest(text).unwrap();
+        let after = settings_input::manifest(&written).unwrap();
+        assert_eq!(after.probes, before.probes);
+        assert_eq!(after.probe_levels, before.probe_levels);
+    }
+}
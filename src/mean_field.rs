@@ -0,0 +1,408 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rand::random;
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::monte_carlo::run_replicate;
+use crate::reactions::Reaction;
+use crate::simulator::square_neighbors;
+use crate::state::{Settings, SimulatorComponents};
+
+/// More states than this and the pair approximation, with one variable per
+/// ordered pair of states, gets too big to integrate.
+const MAX_PAIR_STATES: usize = 300;
+const RELATIVE_TOLERANCE: f64 = 1e-6;
+const ABSOLUTE_TOLERANCE: f64 = 1e-9;
+
+/// A deterministic approximation of the surface CRN, in terms of the fraction
+/// of cells in each state. The mean-field system treats every cell's
+/// neighbors as independent draws from the whole surface; the pair
+/// approximation tracks the fraction of neighboring pairs in each pair of
+/// states instead, and assumes only that a cell's other neighbors are
+/// independent given its own state.
+pub struct OdeSystem<'a> {
+    pub reactions: &'a [Reaction],
+    pub n_states: usize,
+    pub coordination: f64, // How many neighbors each cell has.
+    pub pair: bool,
+}
+
+impl OdeSystem<'_> {
+    /// How many variables the system has: one per state, or one per ordered
+    /// pair of states.
+    pub fn n_variables(&self) -> usize {
+        if self.pair { self.n_states * self.n_states } else { self.n_states }
+    }
+
+    /// The starting point: the fraction of cells in each state on the board,
+    /// or the fraction of (cell, neighbor) pairs in each pair of states.
+    pub fn initial_values(&self, board: &[usize], settings: &Settings) -> Vec<f64> {
+        let mut y = vec![0.0; self.n_variables()];
+        if self.pair {
+            let mut n_pairs = 0;
+            for (loc, state) in board.iter().enumerate() {
+                for neighbor in square_neighbors(loc, settings.n_cols, settings.n_rows, settings.wrap) {
+                    y[state * self.n_states + board[neighbor]] += 1.0;
+                    n_pairs += 1;
+                }
+            }
+            y.iter_mut().for_each(|p| *p /= n_pairs.max(1) as f64);
+        } else {
+            for state in board {
+                y[*state] += 1.0 / board.len() as f64;
+            }
+        }
+        y
+    }
+
+    /// The fraction of cells in each state.
+    pub fn densities(&self, y: &[f64]) -> Vec<f64> {
+        if self.pair {
+            y.chunks(self.n_states).map(|row| row.iter().sum()).collect()
+        } else {
+            y.to_vec()
+        }
+    }
+
+    /// Fills `dy` with the time derivative at `y`. Each rule fires on every
+    /// (reactant, neighbor) pair at its rate, the way the simulator schedules
+    /// it, so a two-cell rule consumes its first reactant at rate
+    /// `rate * coordination * x[r1] * x[r2]` in the mean-field system. A pair
+    /// of cells in the same state only counts once, so `A + A` rules fire at
+    /// half that.
+    pub fn derivative(&self, y: &[f64], dy: &mut [f64]) {
+        dy.iter_mut().for_each(|d| *d = 0.0);
+        if !self.pair {
+            for rxn in self.reactions {
+                let flux = match rxn.r2_num {
+                    Some(r2) => pair_rate(rxn) * self.coordination * y[rxn.r1_num] * y[r2],
+                    None => rxn.rate as f64 * y[rxn.r1_num],
+                };
+                dy[rxn.r1_num] -= flux;
+                dy[rxn.p1_num] += flux;
+                if let (Some(r2), Some(p2)) = (rxn.r2_num, rxn.p2_num) {
+                    dy[r2] -= flux;
+                    dy[p2] += flux;
+                }
+            }
+            return;
+        }
+
+        // Pairs are (first cell, second cell) along a bond. Changes to the
+        // first cell, from a rule within its own cell or across one of its
+        // other bonds, are added along with the mirror-image change to the
+        // second cell of the reversed pair.
+        let n = self.n_states;
+        let x = self.densities(y);
+        let flow = |dy: &mut [f64], from: (usize, usize), to: (usize, usize), rate: f64| {
+            dy[from.0 * n + from.1] -= rate;
+            dy[to.0 * n + to.1] += rate;
+        };
+        let other_bonds = (self.coordination - 1.0).max(0.0);
+        for rxn in self.reactions {
+            let k = pair_rate(rxn);
+            match (rxn.r2_num, rxn.p2_num) {
+                (Some(r2), Some(p2)) => {
+                    let (r1, p1) = (rxn.r1_num, rxn.p1_num);
+                    // Both cells of this bond react together, in either
+                    // direction.
+                    flow(dy, (r1, r2), (p1, p2), k * y[r1 * n + r2]);
+                    flow(dy, (r2, r1), (p2, p1), k * y[r2 * n + r1]);
+                    // The first cell reacts with a neighbor across another
+                    // bond, as either reactant.
+                    for (a, partner, product) in [(r1, r2, p1), (r2, r1, p2)] {
+                        if x[a] <= 0.0 {
+                            continue;
+                        }
+                        let rate = k * other_bonds * y[a * n + partner] / x[a];
+                        for b in 0..n {
+                            let p = y[a * n + b];
+                            if p > 0.0 {
+                                flow(dy, (a, b), (product, b), rate * p);
+                                flow(dy, (b, a), (b, product), rate * p);
+                            }
+                        }
+                    }
+                },
+                _ => {
+                    let (a, product) = (rxn.r1_num, rxn.p1_num);
+                    for b in 0..n {
+                        let p = y[a * n + b];
+                        if p > 0.0 {
+                            flow(dy, (a, b), (product, b), k * p);
+                            flow(dy, (b, a), (b, product), k * p);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Integrates from `y` at time 0, with an adaptive Bogacki-Shampine
+    /// (Runge-Kutta 2/3) method, and returns the densities at each of the
+    /// (increasing) sample times.
+    pub fn integrate(&self, mut y: Vec<f64>, sample_times: &[f64]) -> Vec<Vec<f64>> {
+        let len = y.len();
+        let (mut k1, mut k2, mut k3, mut k4) = (vec![0.0; len], vec![0.0; len], vec![0.0; len], vec![0.0; len]);
+        let mut trial = vec![0.0; len];
+        let mut next_y = vec![0.0; len];
+        let (mut t, mut h): (f64, f64) = (0.0, 1e-3);
+        self.derivative(&y, &mut k1);
+
+        let mut samples = Vec::new();
+        for sample_t in sample_times {
+            while t < *sample_t {
+                let step = h.min(sample_t - t);
+                for i in 0..len {
+                    trial[i] = y[i] + step / 2.0 * k1[i];
+                }
+                self.derivative(&trial, &mut k2);
+                for i in 0..len {
+                    trial[i] = y[i] + 3.0 * step / 4.0 * k2[i];
+                }
+                self.derivative(&trial, &mut k3);
+                for i in 0..len {
+                    next_y[i] = y[i] + step * (2.0 / 9.0 * k1[i] + 1.0 / 3.0 * k2[i] + 4.0 / 9.0 * k3[i]);
+                }
+                self.derivative(&next_y, &mut k4);
+                let error = (0..len)
+                    .map(|i| {
+                        let estimate = step * (-5.0 / 72.0 * k1[i] + 1.0 / 12.0 * k2[i] + 1.0 / 9.0 * k3[i] - 1.0 / 8.0 * k4[i]);
+                        estimate.abs() / (ABSOLUTE_TOLERANCE + RELATIVE_TOLERANCE * y[i].abs().max(next_y[i].abs()))
+                    })
+                    .fold(0.0, f64::max);
+                if error <= 1.0 {
+                    t += step;
+                    std::mem::swap(&mut y, &mut next_y);
+                    std::mem::swap(&mut k1, &mut k4); // The last stage is the next step's first.
+                }
+                h = step * (0.9 * error.max(1e-10).powf(-1.0 / 3.0)).clamp(0.2, 5.0);
+            }
+            samples.push(self.densities(&y));
+        }
+        samples
+    }
+}
+
+/// A rule's rate per ordered (reactant, neighbor) pair. The simulator
+/// schedules a pair of cells in the same state once, not once each way, so
+/// that's half the rate for an `A + A` rule.
+fn pair_rate(rxn: &Reaction) -> f64 {
+    match rxn.r2_num {
+        Some(r2) if r2 == rxn.r1_num => rxn.rate as f64 / 2.0,
+        _ => rxn.rate as f64,
+    }
+}
+
+/// How many neighbors the simulator gives a cell in the middle of the board.
+pub fn coordination_number(settings: &Settings) -> usize {
+    let (n_rows, n_cols) = (settings.n_rows.max(3), settings.n_cols.max(3));
+    square_neighbors(n_cols + 1, n_cols, n_rows, settings.wrap).len()
+}
+
+/// One seeded stochastic run's state counts at each sample time.
+fn stochastic_counts(
+    components: &SimulatorComponents,
+    settings: &Settings,
+    seed: u64,
+    sample_times: &[f64]
+) -> Vec<Vec<usize>> {
+    let mut samples: Vec<Vec<usize>> = Vec::new();
+    let mut previous: Vec<usize> = Vec::new();
+    let t_end = sample_times.last().copied().unwrap_or(0.0);
+    let outcome = run_replicate(components, settings, seed, t_end as f32, |t, _, counts| {
+        // The counts before this event held up to time t.
+        while samples.len() < sample_times.len() && sample_times[samples.len()] < t as f64 {
+            samples.push(previous.clone());
+        }
+        previous = counts.to_vec();
+        false
+    });
+    samples.resize(sample_times.len(), outcome.counts);
+    samples
+}
+
+/// The settings for `chitin ode`, as given on the command line.
+pub struct OdeOptions<'a> {
+    pub pair: bool,
+    pub time: Option<&'a str>,
+    pub samples: Option<&'a str>,
+    pub coordination: Option<&'a str>,
+    pub seed: Option<&'a str>,
+}
+
+/// Runs `chitin ode`: integrates the mean-field (or, with `--pair`, the pair
+/// approximation) ODE for a manifest, next to one seeded stochastic run, and
+/// writes both as state counts over time to a CSV file (or stdout), with a
+/// summary of where they end up. Returns the process exit code.
+pub fn ode_manifest_file(
+    input_file: PathBuf,
+    output_file: Option<PathBuf>,
+    options: OdeOptions,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings, _) = match load_from_manifest(&manifest, scenario) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let parsed = (|| -> Result<(f64, usize, f64, u64), String> {
+        let parse = |value: Option<&str>, flag: &str| value.map(|value| value.parse::<f64>().map_err(|_| format!("{flag} can't be {value:?}")));
+        let t_end = parse(options.time, "--time").transpose()?.unwrap_or(settings.max_duration as f64);
+        let n_samples = parse(options.samples, "--samples").transpose()?.unwrap_or(100.0) as usize;
+        let coordination = parse(options.coordination, "--coordination").transpose()?.unwrap_or(coordination_number(&settings) as f64);
+        let seed = match options.seed {
+            Some(seed) => seed.parse().map_err(|_| format!("--seed can't be {seed:?}"))?,
+            None => settings.rng_seed.map_or_else(random::<u32>, |seed| seed as u32) as u64,
+        };
+        if !(t_end > 0.0 && t_end.is_finite()) || n_samples == 0 {
+            return Err("--time and --samples should be positive".to_string());
+        }
+        Ok((t_end, n_samples, coordination, seed))
+    })();
+    let (t_end, n_samples, coordination, seed) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 2;
+        }
+    };
+    let n_states = components.state_names.keys().max().map_or(0, |id| id + 1);
+    if options.pair && n_states > MAX_PAIR_STATES {
+        eprintln!("error: the pair approximation can't handle {n_states} states (at most {MAX_PAIR_STATES})");
+        return 2;
+    }
+
+    let pair = options.pair;
+    let system = OdeSystem { reactions: &components.all_reactions, n_states, coordination, pair };
+    let sample_times: Vec<f64> = (0..=n_samples).map(|idx| t_end * idx as f64 / n_samples as f64).collect();
+    let approximation = system.integrate(system.initial_values(&components.current_states, &settings), &sample_times);
+    let stochastic = stochastic_counts(&components, &settings, seed, &sample_times);
+
+    let n_cells = components.current_states.len() as f64;
+    let mut states: Vec<(usize, &String)> = components.state_names.iter().map(|(id, name)| (*id, name)).collect();
+    states.sort_by_key(|(_, name)| *name);
+    let kind = if pair { "pair" } else { "mean_field" };
+    let mut text = String::from("t");
+    for (_, name) in states.iter() {
+        text.push_str(&format!(",{kind}({name}),stochastic({name})"));
+    }
+    text.push('\n');
+    for (sample_idx, t) in sample_times.iter().enumerate() {
+        text.push_str(&t.to_string());
+        for (state, _) in states.iter() {
+            text.push_str(&format!(",{},{}", approximation[sample_idx][*state] * n_cells, stochastic[sample_idx][*state]));
+        }
+        text.push('\n');
+    }
+
+    let label = if pair { "Pair approximation" } else { "Mean field" };
+    let summary = format!("{label} (coordination {coordination}) vs. one stochastic run (seed {seed}), at t={t_end}:");
+    let final_counts: String = states
+        .iter()
+        .map(|(state, name)| {
+            format!("    {name}: {:.1} vs. {}\n", approximation[n_samples][*state] * n_cells, stochastic[n_samples][*state])
+        })
+        .collect();
+    match output_file {
+        Some(path) => {
+            if let Err(err) = fs::write(&path, text) {
+                eprintln!("Couldn't write {path:?}: {err}");
+                return 1;
+            }
+            print!("{summary}\n{final_counts}");
+            println!("Wrote the trajectories to {path:?}.");
+        },
+        None => {
+            // Keep stdout usable as a CSV file.
+            eprint!("{summary}\n{final_counts}");
+            print!("{text}");
+        }
+    }
+    0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{coordination_number, OdeSystem};
+
+    fn system_for<'a>(components: &'a crate::state::SimulatorComponents, pair: bool) -> OdeSystem<'a> {
+        let n_states = components.state_names.len();
+        OdeSystem { reactions: &components.all_reactions, n_states, coordination: 4.0, pair }
+    }
+
+    #[test]
+    fn test_decay() {
+        // Decay doesn't care about neighbors, so both approximations are
+        // exact: x_A = e^-t.
+        let manifest = "\
+!START_TRANSITION_RULES
+A -> B (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A A A A
+A A A A
+A A A A
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        assert_eq!(coordination_number(&settings), 4);
+        let a = components.state_ids["A"];
+        for pair in [false, true] {
+            let system = system_for(&components, pair);
+            let samples = system.integrate(system.initial_values(&components.current_states, &settings), &[0.0, 1.0, 2.0]);
+            assert!((samples[0][a] - 1.0).abs() < 1e-9);
+            assert!((samples[1][a] - (-1.0f64).exp()).abs() < 1e-5, "{samples:?}");
+            assert!((samples[2][a] - (-2.0f64).exp()).abs() < 1e-5, "{samples:?}");
+        }
+    }
+
+    #[test]
+    fn test_infection() {
+        // Infection spreading from one corner. Mean field assumes the
+        // infected cells are spread out, so it runs ahead of the pair
+        // approximation, which knows they're clumped together.
+        let manifest = "\
+wrap = true
+!START_TRANSITION_RULES
+I + S -> I + I (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+I S S S S S
+S S S S S S
+S S S S S S
+S S S S S S
+S S S S S S
+S S S S S S
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        let i = components.state_ids["I"];
+        let mean_field = system_for(&components, false);
+        let pair = system_for(&components, true);
+        let times = [0.0, 0.5, 1.0];
+        let mean_field_samples = mean_field.integrate(mean_field.initial_values(&components.current_states, &settings), &times);
+        let pair_values = pair.initial_values(&components.current_states, &settings);
+        assert!((pair_values.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        let pair_samples = pair.integrate(pair_values, &times);
+        for (mean_field_x, pair_x) in mean_field_samples.iter().zip(pair_samples.iter()) {
+            assert!((mean_field_x.iter().sum::<f64>() - 1.0).abs() < 1e-6);
+            assert!((pair_x.iter().sum::<f64>() - 1.0).abs() < 1e-6);
+        }
+        assert!((mean_field_samples[0][i] - 1.0 / 36.0).abs() < 1e-12);
+        assert!(pair_samples[2][i] > 1.0 / 36.0);
+        assert!(mean_field_samples[2][i] > pair_samples[2][i], "{mean_field_samples:?} {pair_samples:?}");
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::reactions::ReactionEvent;
+use crate::simulator::square_neighbors;
+use crate::state::{Settings, SimulatorComponents};
+use crate::trace::{format_trace, parse_cell};
+
+/// How many distinct boards `chitin explore` looks at before giving up,
+/// unless `--max-boards` says otherwise.
+pub const DEFAULT_MAX_BOARDS: usize = 1_000_000;
+
+/// A kind of board to look for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Goal {
+    CellIs { loc: usize, state: usize }, // A board with this state at this position.
+    Deadlock,                            // A board where no rule can fire.
+}
+
+impl Goal {
+    /// Reads a goal like `2,3=1`: the cell at row 2, column 3 is in state 1.
+    pub fn parse_cell(spec: &str, components: &SimulatorComponents, settings: &Settings) -> Result<Goal, String> {
+        let (cell, state_name) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("{spec:?} should look like row,column=state"))?;
+        let loc = parse_cell(cell, settings)?;
+        let state = *components.state_ids
+            .get(state_name.trim().trim_matches('"'))
+            .ok_or_else(|| format!("there's no state named {state_name:?}"))?;
+        Ok(Goal::CellIs { loc, state })
+    }
+}
+
+/// What an exploration found.
+#[derive(Debug)]
+pub struct Exploration {
+    pub n_boards: usize,                      // How many distinct boards were looked at.
+    pub gave_up: bool,                        // Whether the search stopped at max_boards.
+    pub witness: Option<Vec<ReactionEvent>>, // The shortest way to reach the goal, if there is one.
+}
+
+/// An event that could fire on a board: (rule, first cell, second cell).
+pub type Move = (usize, usize, Option<usize>);
+
+/// Every event that could fire next on a board.
+fn possible_events(
+    board: &[usize],
+    components: &SimulatorComponents,
+    neighbors: &[Vec<usize>]
+) -> Vec<Move> {
+    let mut events = Vec::new();
+    for (loc, state) in board.iter().enumerate() {
+        for (rxn_idx, rxn) in components.all_reactions.iter().enumerate() {
+            if rxn.r1_num != *state {
+                continue;
+            }
+            match rxn.r2_num {
+                Some(r2) => events.extend(
+                    neighbors[loc].iter().filter(|neighbor| board[**neighbor] == r2).map(|neighbor| (rxn_idx, loc, Some(*neighbor)))
+                ),
+                None => events.push((rxn_idx, loc, None)),
+            }
+        }
+    }
+    events
+}
+
+/// The boards reachable from the one on screen, found breadth first, with
+/// the event that first led to each.
+pub struct StateSpace {
+    pub boards: Vec<Vec<usize>>,
+    parents: Vec<Option<(usize, Move)>>, // (board, event leading here)
+    pub gave_up: bool,                   // Whether the search stopped at max_boards.
+}
+
+impl StateSpace {
+    /// Searches every board reachable from the one on screen, stopping early
+    /// at the first board `stop` picks (given the board and the events that
+    /// could fire on it) and returning its index. Neighbors are worked out the
+    /// same way the simulator does. Gives up after looking at `max_boards`
+    /// boards; the state space grows exponentially with the number of cells,
+    /// so this is for small surfaces.
+    pub fn search(
+        components: &SimulatorComponents,
+        settings: &Settings,
+        max_boards: usize,
+        mut stop: impl FnMut(&[usize], &[Move]) -> bool
+    ) -> (StateSpace, Option<usize>) {
+        let n_cells = components.current_states.len();
+        let neighbors: Vec<Vec<usize>> = (0..n_cells)
+            .map(|loc| square_neighbors(loc, settings.n_cols, settings.n_rows, settings.wrap))
+            .collect();
+
+        let mut space = StateSpace { boards: vec![components.current_states.clone()], parents: vec![None], gave_up: false };
+        let mut seen: HashSet<Vec<usize>> = HashSet::from([components.current_states.clone()]);
+        let mut next = 0;
+        while next < space.boards.len() {
+            let events = possible_events(&space.boards[next], components, &neighbors);
+            if stop(&space.boards[next], &events) {
+                return (space, Some(next));
+            }
+            for event in events {
+                let (rxn_idx, r1_loc, r2_loc) = event;
+                let rxn = &components.all_reactions[rxn_idx];
+                let mut board = space.boards[next].clone();
+                board[r1_loc] = rxn.p1_num;
+                if let (Some(r2_loc), Some(p2)) = (r2_loc, rxn.p2_num) {
+                    board[r2_loc] = p2;
+                }
+                if seen.contains(&board) {
+                    continue;
+                }
+                if space.boards.len() >= max_boards {
+                    space.gave_up = true;
+                    return (space, None);
+                }
+                seen.insert(board.clone());
+                space.boards.push(board);
+                space.parents.push(Some((next, event)));
+            }
+            next += 1;
+        }
+        (space, None)
+    }
+
+    /// A shortest sequence of events from the starting board to one of the
+    /// boards found, timed one second apart so it plays back at a readable
+    /// pace.
+    pub fn path_to(&self, mut board_idx: usize) -> Vec<ReactionEvent> {
+        let mut steps = Vec::new();
+        while let Some((parent, event)) = self.parents[board_idx] {
+            steps.push(event);
+            board_idx = parent;
+        }
+        steps
+            .into_iter()
+            .rev()
+            .enumerate()
+            .map(|(step, (rxn_idx, r1_loc, r2_loc))| ReactionEvent {
+                r1_loc,
+                r2_loc,
+                rxn_idx,
+                t: (step + 1) as f32,
+                t_issued: step as f32,
+            })
+            .collect()
+    }
+}
+
+/// Searches the boards reachable from the one on screen for one that meets
+/// the goal, returning a shortest way there as the witness.
+pub fn explore(components: &SimulatorComponents, settings: &Settings, goal: Goal, max_boards: usize) -> Exploration {
+    let (space, found) = StateSpace::search(components, settings, max_boards, |board, events| match goal {
+        Goal::CellIs { loc, state } => board[loc] == state,
+        Goal::Deadlock => events.is_empty(),
+    });
+    Exploration {
+        n_boards: space.boards.len(),
+        gave_up: space.gave_up,
+        witness: found.map(|board_idx| space.path_to(board_idx)),
+    }
+}
+
+/// Runs `chitin explore`: searches a manifest's small board for a cell
+/// reaching a state (`cell`, as `row,column=state`) or for a deadlock, and
+/// prints or saves a witness trace that can be replayed with `--trace`.
+/// Returns the process exit code: 0 if such a board is reachable, 1 if not
+/// (or if the search gave up), 2 for bad arguments.
+pub fn explore_manifest_file(
+    input_file: PathBuf,
+    cell: Option<&str>,
+    deadlock: bool,
+    witness_file: Option<PathBuf>,
+    max_boards: Option<&str>,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings, _) = match load_from_manifest(&manifest, scenario) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let goal = match (cell, deadlock) {
+        (Some(spec), false) => match Goal::parse_cell(spec, &components, &settings) {
+            Ok(goal) => goal,
+            Err(err) => {
+                eprintln!("error: {err}");
+                return 2;
+            }
+        },
+        (None, true) => Goal::Deadlock,
+        _ => {
+            eprintln!("error: give exactly one of --cell <row,column=state> or --deadlock");
+            return 2;
+        }
+    };
+    let max_boards = match max_boards.map(|n| n.parse::<usize>()) {
+        None => DEFAULT_MAX_BOARDS,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("error: --max-boards should be a whole number");
+            return 2;
+        }
+    };
+
+    let exploration = explore(&components, &settings, goal, max_boards);
+    let witness = match exploration.witness {
+        Some(witness) => witness,
+        None if exploration.gave_up => {
+            println!("Gave up after {} boards without finding one; try a larger --max-boards.", exploration.n_boards);
+            return 1;
+        },
+        None => {
+            println!("Not reachable: searched all {} reachable boards.", exploration.n_boards);
+            return 1;
+        }
+    };
+    println!("Reachable in {} event(s), after searching {} boards.", witness.len(), exploration.n_boards);
+    let trace = format_trace(&witness, &components, &settings);
+    match witness_file {
+        Some(path) => {
+            if let Err(err) = fs::write(&path, trace) {
+                eprintln!("Couldn't write {path:?}: {err}");
+                return 1;
+            }
+            println!("Wrote the witness to {path:?}; replay it with --trace.");
+        },
+        None => print!("{trace}"),
+    }
+    0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+    use crate::trace::{format_trace, parse_trace};
+
+    use super::{explore, Goal};
+
+    // A token walks right along a 1x4 strip and can be stuck in a trap at
+    // the end.
+    const STRIP: &str = "\
+!START_TRANSITION_RULES
+T + E -> E + T (1)
+T + X -> E + S (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+T E E X
+!END_INIT_STATE";
+
+    #[test]
+    fn test_reachability() {
+        let (components, settings) = settings_input::settings(STRIP).unwrap();
+        let goal = Goal::parse_cell("0,3=S", &components, &settings).unwrap();
+        let exploration = explore(&components, &settings, goal, 100);
+        let witness = exploration.witness.unwrap();
+        assert_eq!(witness.len(), 3);
+        assert_eq!(witness.iter().map(|event| event.r1_loc).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        // The witness replays cleanly from the starting board.
+        let trace = format_trace(&witness, &components, &settings);
+        assert_eq!(parse_trace(&trace, &components, &settings).unwrap().len(), 3);
+
+        // T only moves right, so S can't appear anywhere but the end.
+        let goal = Goal::parse_cell("0,1=S", &components, &settings).unwrap();
+        let exploration = explore(&components, &settings, goal, 100);
+        assert!(!exploration.gave_up && exploration.witness.is_none());
+        assert_eq!(exploration.n_boards, 4);
+
+        assert!(Goal::parse_cell("0,4=S", &components, &settings).is_err());
+        assert!(Goal::parse_cell("0,1=Q", &components, &settings).is_err());
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let (components, settings) = settings_input::settings(STRIP).unwrap();
+        let exploration = explore(&components, &settings, Goal::Deadlock, 100);
+        assert_eq!(exploration.witness.unwrap().len(), 3);
+        assert!(explore(&components, &settings, Goal::Deadlock, 2).gave_up);
+    }
+}
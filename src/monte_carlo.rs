@@ -0,0 +1,451 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rand::random;
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::simulator::{extend_reaction_history, initialize_queue};
+use crate::state::{Settings, SimulatorComponents, SimulatorState};
+use crate::trace::parse_cell;
+
+/// How many replicates `chitin estimate` runs, unless `--runs` says otherwise.
+pub const DEFAULT_RUNS: usize = 1000;
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// One test on the board, like `2,3=A` or `count(A)>=10`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Atom {
+    CellIs { loc: usize, state: usize },
+    Count { state: usize, comparison: Comparison, n: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn holds(&self, a: usize, b: usize) -> bool {
+        match self {
+            Comparison::Less => a < b,
+            Comparison::LessOrEqual => a <= b,
+            Comparison::Equal => a == b,
+            Comparison::NotEqual => a != b,
+            Comparison::GreaterOrEqual => a >= b,
+            Comparison::Greater => a > b,
+        }
+    }
+}
+
+/// A condition on the board: tests joined by `&` (all of them hold) and `|`
+/// (any group holds), with `&` binding tighter. For example,
+/// `0,0=1 & 0,1=1 & 0,2=1 | count(Done)>=1`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub alternatives: Vec<Vec<Atom>>,
+}
+
+impl Condition {
+    pub fn parse(text: &str, components: &SimulatorComponents, settings: &Settings) -> Result<Condition, String> {
+        let alternatives = text
+            .split('|')
+            .map(|group| group.split('&').map(|atom| parse_atom(atom.trim(), components, settings)).collect())
+            .collect::<Result<Vec<Vec<Atom>>, String>>()?;
+        Ok(Condition { alternatives })
+    }
+
+    /// Whether the condition holds, given the board and how many cells are in
+    /// each state.
+    pub fn holds(&self, board: &[usize], counts: &[usize]) -> bool {
+        self.alternatives.iter().any(|atoms| {
+            atoms.iter().all(|atom| match atom {
+                Atom::CellIs { loc, state } => board[*loc] == *state,
+                Atom::Count { state, comparison, n } => comparison.holds(counts[*state], *n),
+            })
+        })
+    }
+}
+
+fn parse_atom(text: &str, components: &SimulatorComponents, settings: &Settings) -> Result<Atom, String> {
+    let state_id = |name: &str| {
+        let name = name.trim().trim_matches('"');
+        components.state_ids.get(name).copied().ok_or_else(|| format!("there's no state named {name:?}"))
+    };
+    if let Some(rest) = text.strip_prefix("count(") {
+        let (name, rest) = rest.split_once(')').ok_or_else(|| format!("{text:?} is missing a closing parenthesis"))?;
+        let rest = rest.trim();
+        let (comparison, n) = [
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ]
+        .iter()
+        .find_map(|(op, comparison)| rest.strip_prefix(op).map(|n| (*comparison, n)))
+        .ok_or_else(|| format!("{text:?} should compare the count with <, <=, ==, !=, >=, or >"))?;
+        let n = n.trim().parse().map_err(|_| format!("{:?} isn't a whole number", n.trim()))?;
+        return Ok(Atom::Count { state: state_id(name)?, comparison, n });
+    }
+    match text.split_once('=') {
+        Some((cell, name)) => Ok(Atom::CellIs { loc: parse_cell(cell, settings)?, state: state_id(name)? }),
+        None => Err(format!("{text:?} should look like row,column=state or count(state)>=n")),
+    }
+}
+
+/// How one replicate went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replicate {
+    pub reached: Option<f32>, // When the stop condition first held, if it did.
+    pub t_end: f32,           // The time of the last event.
+    pub n_events: usize,
+    pub counts: Vec<usize>,   // How many cells ended up in each state, by state id.
+}
+
+/// Runs one replicate of the simulation, seeded with `seed`, until `stop`
+/// says so (given the time, the board, and how many cells are in each state),
+/// the next event would come after `time_bound`, or nothing more can happen.
+pub fn run_replicate(
+    components: &SimulatorComponents,
+    settings: &Settings,
+    seed: u64,
+    time_bound: f32,
+    mut stop: impl FnMut(f32, &[usize], &[usize]) -> bool
+) -> Replicate {
+    let mut components = components.clone();
+    let mut global_state = SimulatorState::new(Some(seed), 0);
+    initialize_queue(&components, &mut global_state, settings);
+    let n_states = components.state_names.keys().max().map_or(0, |id| id + 1);
+    let mut counts = vec![0; n_states];
+    for state in components.latest_states.iter() {
+        counts[*state] += 1;
+    }
+
+    let (mut t, mut n_events) = (0.0, 0);
+    let reached = loop {
+        if stop(t, &components.latest_states, &counts) {
+            break Some(t);
+        }
+        let n_old = components.reaction_history.len();
+        extend_reaction_history(&mut components, &mut global_state, settings);
+        if components.reaction_history.len() == n_old {
+            break None;
+        }
+        let event = *components.reaction_history.last().unwrap();
+        if event.t > time_bound {
+            break None;
+        }
+        let rxn = components.all_reactions[event.rxn_idx];
+        counts[rxn.r1_num] -= 1;
+        counts[rxn.p1_num] += 1;
+        if let (Some(r2), Some(p2)) = (rxn.r2_num, rxn.p2_num) {
+            counts[r2] -= 1;
+            counts[p2] += 1;
+        }
+        t = event.t;
+        n_events += 1;
+        // Only the latest event is needed to time the next ones, so don't let
+        // long runs pile up history.
+        components.reaction_history.drain(..n_old);
+    };
+    Replicate { reached, t_end: t, n_events, counts }
+}
+
+/// A two-sided normal quantile, for a confidence level like 0.95 (Abramowitz
+/// and Stegun 26.2.23, good to about four decimal places).
+pub fn normal_quantile(confidence: f64) -> f64 {
+    let p = (1.0 - confidence) / 2.0;
+    let t = (-2.0 * p.ln()).sqrt();
+    t - (2.515517 + 0.802853 * t + 0.010328 * t * t) / (1.0 + 1.432788 * t + 0.189269 * t * t + 0.001308 * t * t * t)
+}
+
+/// The Wilson score interval for `successes` out of `n` trials, which stays
+/// sensible when the estimate is near 0 or 1.
+pub fn wilson_interval(successes: usize, n: usize, confidence: f64) -> (f64, f64) {
+    if n == 0 {
+        return (0.0, 1.0);
+    }
+    let (n, z) = (n as f64, normal_quantile(confidence));
+    let p = successes as f64 / n;
+    let center = (p + z * z / (2.0 * n)) / (1.0 + z * z / n);
+    let half_width = z / (1.0 + z * z / n) * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt();
+    ((center - half_width).max(0.0), (center + half_width).min(1.0))
+}
+
+/// What a sequential test decided about whether the probability is at least
+/// a threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    AtLeast,
+    Below,
+    Undecided,
+}
+
+/// Wald's sequential probability ratio test of whether the probability is at
+/// least `threshold`, with an indifference region of `threshold ± 0.01` and
+/// error rates of `1 - confidence` both ways. Stops as soon as the outcomes
+/// so far are enough to decide; returns the decision and how many outcomes
+/// it used.
+pub fn sequential_test(outcomes: impl IntoIterator<Item = bool>, threshold: f64, confidence: f64) -> (Decision, usize) {
+    let p_at_least = (threshold + 0.01).min(1.0 - 1e-9);
+    let p_below = (threshold - 0.01).max(1e-9);
+    let error = 1.0 - confidence;
+    let (accept_below, accept_at_least) = (((1.0 - error) / error).ln(), (error / (1.0 - error)).ln());
+    let mut log_ratio = 0.0;
+    let mut n = 0;
+    for reached in outcomes {
+        n += 1;
+        log_ratio += match reached {
+            true => (p_below / p_at_least).ln(),
+            false => ((1.0 - p_below) / (1.0 - p_at_least)).ln(),
+        };
+        if log_ratio >= accept_below {
+            return (Decision::Below, n);
+        }
+        if log_ratio <= accept_at_least {
+            return (Decision::AtLeast, n);
+        }
+    }
+    (Decision::Undecided, n)
+}
+
+/// A text histogram of first-passage times, one line per bin.
+fn format_histogram(times: &[f32], n_bins: usize) -> String {
+    let (min, max) = times.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), t| (min.min(*t), max.max(*t)));
+    let width = ((max - min) / n_bins as f32).max(f32::EPSILON);
+    let mut bins = vec![0; n_bins];
+    for t in times {
+        bins[(((t - min) / width) as usize).min(n_bins - 1)] += 1;
+    }
+    let tallest = bins.iter().copied().max().unwrap_or(1).max(1);
+    bins.iter()
+        .enumerate()
+        .map(|(bin_idx, count)| {
+            let start = min + width * bin_idx as f32;
+            let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(tallest));
+            format!("    {:>10.3} - {:<10.3} {count:>6} {bar}\n", start, start + width)
+        })
+        .collect()
+}
+
+/// The settings for `chitin estimate`, as given on the command line.
+pub struct EstimateOptions<'a> {
+    pub until: &'a str,
+    pub time_bound: Option<&'a str>,
+    pub runs: Option<&'a str>,
+    pub threshold: Option<&'a str>,
+    pub confidence: Option<&'a str>,
+    pub bins: Option<&'a str>,
+    pub seed: Option<&'a str>,
+    pub times_file: Option<PathBuf>,
+}
+
+fn parse_option<T: std::str::FromStr>(value: Option<&str>, flag: &str, default: T) -> Result<T, String> {
+    match value {
+        Some(value) => value.parse().map_err(|_| format!("{flag} can't be {value:?}")),
+        None => Ok(default),
+    }
+}
+
+/// Runs `chitin estimate`: simulates independent, seeded replicates of a
+/// manifest until `--until` holds or `--time` passes, then prints the chance
+/// of reaching it with a confidence interval (or, with `--threshold`, runs a
+/// sequential test against that probability) and a histogram of the times it
+/// took. Replicate i is seeded with the base seed plus i, so any one of them
+/// can be rerun. Returns the process exit code: 0 on success, 1 if the
+/// manifest can't be read, 2 for bad arguments.
+pub fn estimate_manifest_file(
+    input_file: PathBuf,
+    options: EstimateOptions,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings, _) = match load_from_manifest(&manifest, scenario) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let parsed = (|| -> Result<_, String> {
+        let condition = Condition::parse(options.until, &components, &settings)?;
+        let time_bound: f32 = parse_option(options.time_bound, "--time", settings.max_duration)?;
+        let runs: usize = parse_option(options.runs, "--runs", DEFAULT_RUNS)?;
+        let confidence: f64 = parse_option(options.confidence, "--confidence", 0.95)?;
+        if !(0.5..1.0).contains(&confidence) {
+            return Err("--confidence should be at least 0.5 and less than 1".to_string());
+        }
+        let threshold: Option<f64> = options.threshold.map(|value| parse_option(Some(value), "--threshold", 0.0)).transpose()?;
+        if threshold.is_some_and(|threshold| !(0.0..=1.0).contains(&threshold)) {
+            return Err("--threshold should be a probability".to_string());
+        }
+        let n_bins: usize = parse_option(options.bins, "--bins", 10)?.max(1);
+        let default_seed = settings.rng_seed.map_or_else(random::<u32>, |seed| seed as u32) as u64;
+        let seed: u64 = parse_option(options.seed, "--seed", default_seed)?;
+        Ok((condition, time_bound, runs, confidence, threshold, n_bins, seed))
+    })();
+    let (condition, time_bound, runs, confidence, threshold, n_bins, base_seed) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 2;
+        }
+    };
+
+    let mut outcomes: Vec<Option<f32>> = Vec::new();
+    let run = |replicate: usize| {
+        let seed = base_seed.wrapping_add(replicate as u64);
+        run_replicate(&components, &settings, seed, time_bound, |_, board, counts| condition.holds(board, counts)).reached
+    };
+    let decision = match threshold {
+        Some(threshold) => {
+            let (decision, _) = sequential_test(
+                (0..runs).map(|replicate| {
+                    outcomes.push(run(replicate));
+                    outcomes.last().unwrap().is_some()
+                }),
+                threshold,
+                confidence
+            );
+            Some((threshold, decision))
+        },
+        None => {
+            outcomes.extend((0..runs).map(run));
+            None
+        }
+    };
+
+    let n = outcomes.len();
+    let times: Vec<f32> = outcomes.iter().flatten().copied().collect();
+    println!(
+        "Ran {n} replicate(s) (seeds {base_seed} to {}), each up to t={time_bound}.",
+        base_seed.wrapping_add(n.saturating_sub(1) as u64)
+    );
+    let (low, high) = wilson_interval(times.len(), n, confidence);
+    println!(
+        "Reached in {} ({:.1}%); {}% confidence interval {:.1}% to {:.1}%.",
+        times.len(),
+        100.0 * times.len() as f64 / n.max(1) as f64,
+        100.0 * confidence,
+        100.0 * low,
+        100.0 * high
+    );
+    if let Some((threshold, decision)) = decision {
+        match decision {
+            Decision::AtLeast => println!("The probability is at least {threshold} (sequential test, after {n} replicates)."),
+            Decision::Below => println!("The probability is below {threshold} (sequential test, after {n} replicates)."),
+            Decision::Undecided => println!("Couldn't tell whether the probability is at least {threshold}; try more --runs."),
+        }
+    }
+    if !times.is_empty() {
+        let mut sorted = times.clone();
+        sorted.sort_by(f32::total_cmp);
+        println!(
+            "First-passage time: mean {:.3}, median {:.3}, range {:.3} to {:.3}.",
+            sorted.iter().sum::<f32>() / sorted.len() as f32,
+            sorted[sorted.len() / 2],
+            sorted[0],
+            sorted[sorted.len() - 1]
+        );
+        print!("{}", format_histogram(&sorted, n_bins));
+    }
+
+    if let Some(path) = options.times_file {
+        let mut text = String::from("replicate,seed,reached,time\n");
+        for (replicate, outcome) in outcomes.iter().enumerate() {
+            let seed = base_seed.wrapping_add(replicate as u64);
+            match outcome {
+                Some(t) => text.push_str(&format!("{replicate},{seed},true,{t}\n")),
+                None => text.push_str(&format!("{replicate},{seed},false,\n")),
+            }
+        }
+        if let Err(err) = fs::write(&path, text) {
+            eprintln!("Couldn't write {path:?}: {err}");
+            return 1;
+        }
+        println!("Wrote each replicate's outcome to {path:?}.");
+    }
+    0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{run_replicate, sequential_test, wilson_interval, Atom, Comparison, Condition, Decision};
+
+    // A single cell that decays at rate 1, so it has decayed by time t with
+    // probability 1 - e^-t.
+    const DECAY: &str = "\
+!START_TRANSITION_RULES
+A -> B (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A B
+!END_INIT_STATE";
+
+    #[test]
+    fn test_condition() {
+        let (components, settings) = settings_input::settings(DECAY).unwrap();
+        let condition = Condition::parse("0,0=B & count(B) >= 2 | count(A)==3", &components, &settings).unwrap();
+        let (a, b) = (components.state_ids["A"], components.state_ids["B"]);
+        assert_eq!(condition.alternatives[0][0], Atom::CellIs { loc: 0, state: b });
+        assert_eq!(condition.alternatives[1][0], Atom::Count { state: a, comparison: Comparison::Equal, n: 3 });
+
+        let mut counts = vec![0; 2];
+        counts[a] = 1;
+        counts[b] = 1;
+        assert!(!condition.holds(&[a, b], &counts));
+        counts[a] = 0;
+        counts[b] = 2;
+        assert!(condition.holds(&[b, b], &counts));
+
+        assert!(Condition::parse("0,5=B", &components, &settings).is_err());
+        assert!(Condition::parse("count(C)>1", &components, &settings).is_err());
+        assert!(Condition::parse("count(A)~1", &components, &settings).is_err());
+    }
+
+    #[test]
+    fn test_replicates() {
+        let (components, settings) = settings_input::settings(DECAY).unwrap();
+        let b = components.state_ids["B"];
+        let decayed = |seed| run_replicate(&components, &settings, seed, 1.0, |_, board, _| board[0] == b);
+
+        // The same seed gives the same run.
+        assert_eq!(decayed(7), decayed(7));
+
+        let n_runs = 2000;
+        let n_decayed = (0..n_runs).filter(|seed| decayed(*seed).reached.is_some()).count();
+        let (low, high) = wilson_interval(n_decayed, n_runs as usize, 0.999);
+        let expected = 1.0 - (-1.0f64).exp();
+        assert!(low < expected && expected < high, "{n_decayed} of {n_runs} decayed");
+    }
+
+    #[test]
+    fn test_statistics() {
+        let (low, high) = wilson_interval(50, 100, 0.95);
+        assert!((low - 0.404).abs() < 0.002 && (high - 0.596).abs() < 0.002, "{low} {high}");
+        assert_eq!(wilson_interval(0, 10, 0.95).0, 0.0);
+
+        // Always reaching it is enough to decide quickly either way.
+        let (decision, n) = sequential_test(std::iter::repeat_n(true, 1000), 0.5, 0.95);
+        assert_eq!(decision, Decision::AtLeast);
+        assert!(n < 200, "{n}");
+        assert_eq!(sequential_test(std::iter::repeat_n(false, 1000), 0.5, 0.95).0, Decision::Below);
+        assert_eq!(sequential_test([true, false], 0.5, 0.95).0, Decision::Undecided);
+    }
+}
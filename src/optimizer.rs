@@ -0,0 +1,439 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rand::random;
+use serde::Deserialize;
+
+use crate::input::read_manifest_file;
+use crate::input_parsers::{build_simulation, ParsedManifest};
+use crate::monte_carlo::{run_replicate, Condition};
+use crate::structured_manifest::{format_manifest, ManifestFormat, SettingValue};
+use crate::sweep::{apply_point, parameter_target, with_image_read, Target};
+
+/// A rate optimization, written as TOML or JSON: which rule rates to adjust,
+/// within what bounds, and what to maximize (or minimize) over seeded runs.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OptimizeSpec {
+    #[serde(default)]
+    pub maximize: Option<String>,
+    #[serde(default)]
+    pub minimize: Option<String>,
+    #[serde(default)]
+    pub until: Option<String>, // The condition `probability` and `time` refer to; it also stops a run.
+    #[serde(default)]
+    pub time: Option<f32>, // How long each run lasts; max_duration by default.
+    #[serde(default = "ten")]
+    pub replicates: usize,
+    #[serde(default)]
+    pub seed: Option<u64>, // Every candidate is run with the seeds counting up from this one.
+    #[serde(default = "fifty")]
+    pub iterations: usize,
+    #[serde(rename = "parameter")]
+    pub parameters: Vec<RateBound>,
+}
+
+fn ten() -> usize {
+    10
+}
+
+fn fifty() -> usize {
+    50
+}
+
+/// One rate to adjust, named like `rate(A + B -> B + B)`, and the bounds it
+/// stays within. With `log = true` the search moves in steps proportional to
+/// the rate, which suits bounds that span orders of magnitude.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateBound {
+    pub name: String,
+    pub range: [f64; 2],
+    #[serde(default)]
+    pub log: bool,
+}
+
+impl OptimizeSpec {
+    pub fn from_toml(text: &str) -> Result<OptimizeSpec, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    pub fn from_json(text: &str) -> Result<OptimizeSpec, String> {
+        serde_json::from_str(text).map_err(|err| err.to_string())
+    }
+}
+
+impl RateBound {
+    /// The rate at a position between 0 (the low bound) and 1 (the high one).
+    fn rate_at(&self, position: f64) -> f64 {
+        let [low, high] = self.range;
+        match self.log {
+            true => (low.ln() + position * (high.ln() - low.ln())).exp(),
+            false => low + position * (high - low),
+        }
+    }
+
+    fn position_of(&self, rate: f64) -> f64 {
+        let [low, high] = self.range;
+        let position = match self.log {
+            true => (rate.ln() - low.ln()) / (high.ln() - low.ln()),
+            false => (rate - low) / (high - low),
+        };
+        match position.is_finite() {
+            true => position.clamp(0.0, 1.0),
+            false => 0.5,
+        }
+    }
+}
+
+/// What a run is scored on.
+#[derive(Debug, Clone, PartialEq)]
+enum Objective {
+    Probability,   // 1 if the `until` condition held by the end of the run, 0 if not.
+    Time,          // When the `until` condition first held, or the time bound if it never did.
+    Count(String), // How many cells ended up in a state.
+}
+
+impl Objective {
+    fn parse(text: &str, until: Option<&str>) -> Result<Objective, String> {
+        let objective = match text.trim() {
+            "probability" => Objective::Probability,
+            "time" => Objective::Time,
+            other => match other.strip_prefix("count(").and_then(|rest| rest.strip_suffix(')')) {
+                Some(state) => Objective::Count(state.trim().to_string()),
+                None => return Err(format!("{text:?} isn't an objective; use probability, time, or count(state)")),
+            },
+        };
+        if until.is_none() && !matches!(objective, Objective::Count(_)) {
+            return Err(format!("the {text} objective needs an `until` condition"));
+        }
+        Ok(objective)
+    }
+}
+
+/// The best rates found, in the order the spec lists them, and how they
+/// scored. The search can end up favoring rates that happened to suit its
+/// seeds, so they're scored again on as many fresh seeds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Optimum {
+    pub rates: Vec<f64>,
+    pub score: Score,
+    pub fresh_score: Score,
+    pub n_evaluations: usize,
+}
+
+/// The mean of the objective over a candidate's runs, with its standard error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Score {
+    pub mean: f64,
+    pub std_error: f64,
+}
+
+/// Scores rate settings by running them. Every candidate gets the same seeds
+/// (common random numbers), so differences between candidates come from
+/// their rates rather than from luck.
+struct Evaluator<'a> {
+    manifest: ParsedManifest,
+    targets: Vec<Target>,
+    spec: &'a OptimizeSpec,
+    objective: Objective,
+    n_evaluations: usize,
+}
+
+impl Evaluator<'_> {
+    fn score(&mut self, rates: &[f64], first_seed: u64) -> Result<Score, String> {
+        self.n_evaluations += 1;
+        let values: Vec<SettingValue> = rates.iter().map(|rate| SettingValue::Float(*rate)).collect();
+        let point = apply_point(&self.manifest, &self.targets, &values)?;
+        let (components, settings) = build_simulation(&point)?;
+        let condition = self.spec.until
+            .as_deref()
+            .map(|until| Condition::parse(until, &components, &settings))
+            .transpose()?;
+        let counted_state = match &self.objective {
+            Objective::Count(name) => Some(
+                components.state_names
+                    .iter()
+                    .find(|(_, state_name)| *state_name == name)
+                    .map(|(state, _)| *state)
+                    .ok_or_else(|| format!("there's no state named {name:?}"))?
+            ),
+            _ => None,
+        };
+        let time_bound = self.spec.time.unwrap_or(settings.max_duration);
+
+        let outcomes: Vec<f64> = (0..self.spec.replicates as u64)
+            .map(|replicate| {
+                let outcome = run_replicate(&components, &settings, first_seed.wrapping_add(replicate), time_bound, |_, board, counts| {
+                    condition.as_ref().is_some_and(|condition| condition.holds(board, counts))
+                });
+                match self.objective {
+                    Objective::Probability => outcome.reached.map_or(0.0, |_| 1.0),
+                    Objective::Time => outcome.reached.unwrap_or(time_bound) as f64,
+                    Objective::Count(_) => counted_state.and_then(|state| outcome.counts.get(state)).copied().unwrap_or(0) as f64,
+                }
+            })
+            .collect();
+        let n = outcomes.len() as f64;
+        let mean = outcomes.iter().sum::<f64>() / n;
+        let variance = outcomes.iter().map(|outcome| (outcome - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0);
+        Ok(Score { mean, std_error: (variance / n).sqrt() })
+    }
+}
+
+/// Searches for the rates that score best, with the Nelder-Mead simplex
+/// method. It needs no derivatives, and with every candidate run on the same
+/// seeds the noisy objective is smooth enough for it to follow. Each rate is
+/// searched as a position between its bounds, and any step that would leave
+/// them is cut off at the bound. The search starts from the manifest's own
+/// rates (moved inside their bounds if need be), and `progress` hears about
+/// the best candidate after every iteration.
+pub fn optimize(
+    manifest: &ParsedManifest,
+    spec: &OptimizeSpec,
+    seed: u64,
+    mut progress: impl FnMut(usize, &[f64], Score)
+) -> Result<Optimum, String> {
+    let manifest = with_image_read(manifest)?;
+    let (objective, maximize) = match (&spec.maximize, &spec.minimize) {
+        (Some(text), None) => (Objective::parse(text, spec.until.as_deref())?, true),
+        (None, Some(text)) => (Objective::parse(text, spec.until.as_deref())?, false),
+        _ => return Err("give either `maximize` or `minimize`".to_string()),
+    };
+    if spec.parameters.is_empty() {
+        return Err("there are no rates to adjust".to_string());
+    }
+    if spec.replicates == 0 {
+        return Err("replicates should be at least 1".to_string());
+    }
+    let mut targets = Vec::new();
+    let mut start = Vec::new();
+    for bound in spec.parameters.iter() {
+        let [low, high] = bound.range;
+        if !(0.0 <= low && low < high) || (bound.log && low == 0.0) {
+            return Err(format!("{:?} needs a range [low, high] with 0 <= low < high (and 0 < low for log = true)", bound.name));
+        }
+        match parameter_target(&bound.name, &manifest)? {
+            Target::Rate(rule_idx) => {
+                start.push(bound.position_of(manifest.transition_rules[rule_idx].rate as f64));
+                targets.push(Target::Rate(rule_idx));
+            },
+            _ => return Err(format!("{:?} isn't a rate; name rates like rate(A + B -> B + B)", bound.name)),
+        }
+    }
+
+    let mut evaluator = Evaluator { manifest, targets, spec, objective, n_evaluations: 0 };
+    let rates_at = |position: &[f64]| -> Vec<f64> {
+        spec.parameters.iter().zip(position).map(|(bound, position)| bound.rate_at(*position)).collect()
+    };
+    // Nelder-Mead minimizes, so a maximized objective is negated.
+    let mut cost = |position: &[f64]| -> Result<(f64, Score), String> {
+        let score = evaluator.score(&rates_at(position), seed)?;
+        Ok((if maximize { -score.mean } else { score.mean }, score))
+    };
+    let clamp = |position: Vec<f64>| -> Vec<f64> { position.into_iter().map(|x| x.clamp(0.0, 1.0)).collect() };
+    // Moves `from` by `factor` times the way from it to `to`.
+    let towards = |from: &[f64], to: &[f64], factor: f64| -> Vec<f64> {
+        clamp(from.iter().zip(to).map(|(a, b)| a + factor * (b - a)).collect())
+    };
+
+    let n_dims = start.len();
+    let mut simplex = vec![start.clone()];
+    for dim in 0..n_dims {
+        let mut vertex = start.clone();
+        vertex[dim] += if start[dim] <= 0.75 { 0.25 } else { -0.25 };
+        simplex.push(vertex);
+    }
+    let mut costs = simplex.iter().map(|vertex| cost(vertex)).collect::<Result<Vec<_>, String>>()?;
+
+    for iteration in 0..spec.iterations {
+        let mut order: Vec<usize> = (0..simplex.len()).collect();
+        order.sort_by(|a, b| costs[*a].0.total_cmp(&costs[*b].0));
+        simplex = order.iter().map(|idx| simplex[*idx].clone()).collect();
+        costs = order.iter().map(|idx| costs[*idx]).collect();
+        progress(iteration, &rates_at(&simplex[0]), costs[0].1);
+        let size = simplex[1..].iter().flat_map(|vertex| vertex.iter().zip(&simplex[0]).map(|(a, b)| (a - b).abs())).fold(0.0, f64::max);
+        if size < 1e-3 {
+            break;
+        }
+
+        let worst = simplex[n_dims].clone();
+        let centroid: Vec<f64> = (0..n_dims)
+            .map(|dim| simplex[..n_dims].iter().map(|vertex| vertex[dim]).sum::<f64>() / n_dims as f64)
+            .collect();
+        let reflected = towards(&centroid, &worst, -1.0);
+        let reflected_cost = cost(&reflected)?;
+        let (vertex, vertex_cost) = if reflected_cost.0 < costs[0].0 {
+            let expanded = towards(&centroid, &worst, -2.0);
+            let expanded_cost = cost(&expanded)?;
+            match expanded_cost.0 < reflected_cost.0 {
+                true => (expanded, expanded_cost),
+                false => (reflected, reflected_cost),
+            }
+        } else if reflected_cost.0 < costs[n_dims - 1].0 {
+            (reflected, reflected_cost)
+        } else {
+            // Contract toward the better of the reflected and worst points.
+            let (outside, toward) = match reflected_cost.0 < costs[n_dims].0 {
+                true => (true, &reflected),
+                false => (false, &worst),
+            };
+            let contracted = towards(&centroid, toward, 0.5);
+            let contracted_cost = cost(&contracted)?;
+            let limit = if outside { reflected_cost.0 } else { costs[n_dims].0 };
+            if contracted_cost.0 <= limit {
+                (contracted, contracted_cost)
+            } else {
+                // Nothing along that line helps: shrink toward the best point.
+                for idx in 1..simplex.len() {
+                    simplex[idx] = towards(&simplex[0], &simplex[idx], 0.5);
+                    costs[idx] = cost(&simplex[idx])?;
+                }
+                continue;
+            }
+        };
+        simplex[n_dims] = vertex;
+        costs[n_dims] = vertex_cost;
+    }
+
+    let best = (0..simplex.len()).min_by(|a, b| costs[*a].0.total_cmp(&costs[*b].0)).unwrap();
+    let rates = rates_at(&simplex[best]);
+    let fresh_score = evaluator.score(&rates, seed.wrapping_add(spec.replicates as u64))?;
+    Ok(Optimum { rates, score: costs[best].1, fresh_score, n_evaluations: evaluator.n_evaluations })
+}
+
+/// Runs `chitin optimize`: tunes a manifest's rates to an optimization spec,
+/// printing the best candidate as the search goes, and writes the manifest
+/// with the best rates to `output_file` (in the format its extension names),
+/// if given.
+pub fn optimize_manifest_file(
+    input_file: PathBuf,
+    spec_file: PathBuf,
+    output_file: Option<PathBuf>,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let spec = fs::read_to_string(&spec_file)
+        .map_err(|err| err.to_string())
+        .and_then(|text| match ManifestFormat::from_path(&spec_file) {
+            ManifestFormat::Toml => OptimizeSpec::from_toml(&text),
+            ManifestFormat::Json => OptimizeSpec::from_json(&text),
+            ManifestFormat::Text => Err("optimization specs have to be .toml or .json files".to_string()),
+        });
+    let spec = match spec {
+        Ok(spec) => spec,
+        Err(err) => {
+            eprintln!("error: couldn't read {spec_file:?}: {err}");
+            return 2;
+        }
+    };
+    let manifest = match read_manifest_file(input_file.clone(), search_path).and_then(|manifest| manifest.with_scenario(scenario)) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let seed = spec.seed.unwrap_or_else(random);
+    let names: Vec<&str> = spec.parameters.iter().map(|bound| &bound.name[..]).collect();
+    let describe = |rates: &[f64]| -> String {
+        names.iter().zip(rates).map(|(name, rate)| format!("{name} = {rate:.4}")).collect::<Vec<String>>().join(", ")
+    };
+    println!("Running {} replicate(s) per candidate from seed {seed}.", spec.replicates);
+    let result = optimize(&manifest, &spec, seed, |iteration, rates, score| {
+        println!("    iteration {iteration}: {:.4} ± {:.4} at {}", score.mean, score.std_error, describe(rates));
+    });
+    let optimum = match result {
+        Ok(optimum) => optimum,
+        Err(err) => {
+            eprintln!("error: couldn't optimize {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    println!("Best after {} candidate run(s): {}", optimum.n_evaluations, describe(&optimum.rates));
+    println!("    scored {:.4} ± {:.4} on the search's seeds", optimum.score.mean, optimum.score.std_error);
+    println!("    and {:.4} ± {:.4} on fresh ones", optimum.fresh_score.mean, optimum.fresh_score.std_error);
+
+    let Some(path) = output_file else {
+        return 0;
+    };
+    let targets: Vec<Target> = spec.parameters.iter().filter_map(|bound| parameter_target(&bound.name, &manifest).ok()).collect();
+    let values: Vec<SettingValue> = optimum.rates.iter().map(|rate| SettingValue::Float(*rate)).collect();
+    let written = apply_point(&manifest, &targets, &values)
+        .and_then(|tuned| format_manifest(&tuned, ManifestFormat::from_path(&path)))
+        .and_then(|text| fs::write(&path, text).map_err(|err| err.to_string()));
+    match written {
+        Ok(()) => {
+            println!("Wrote the tuned manifest to {path:?}.");
+            0
+        },
+        Err(err) => {
+            eprintln!("error: couldn't write {path:?}: {err}");
+            1
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+    use crate::manifest_lexer::normalize_manifest_text;
+
+    use super::{optimize, OptimizeSpec};
+
+    // B is made from A and decays, so there's a best rate for making it.
+    const MANIFEST: &str = "\
+!START_TRANSITION_RULES
+A -> B (1)
+B -> C (1)
+!END_TRANSITION_RULES
+!START_INIT_GENERATOR
+size 10 10
+fill A
+!END_INIT_GENERATOR";
+
+    #[test]
+    fn test_finds_best_yield() {
+        let manifest = settings_input::manifest(&normalize_manifest_text(MANIFEST).unwrap()).unwrap();
+        let spec = OptimizeSpec::from_toml(r#"
+maximize = "count(B)"
+time = 1
+replicates = 20
+
+[[parameter]]
+name = "rate(A -> B)"
+range = [0.1, 20]
+log = true
+"#).unwrap();
+        let mut n_reports = 0;
+        let optimum = optimize(&manifest, &spec, 3, |_, _, _| n_reports += 1).unwrap();
+        // At t=1 the expected fraction in B is k (e^-k - e^-1) / (1 - k),
+        // which peaks near k = 2.9 at about 0.48.
+        assert!((1.5..6.0).contains(&optimum.rates[0]), "{optimum:?}");
+        assert!(optimum.score.mean > 40.0 && optimum.fresh_score.mean > 40.0, "{optimum:?}");
+        assert!(n_reports > 0 && optimum.n_evaluations > n_reports);
+
+        // The same seed gives the same search.
+        assert_eq!(optimize(&manifest, &spec, 3, |_, _, _| {}).unwrap(), optimum);
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        let manifest = settings_input::manifest(&normalize_manifest_text(MANIFEST).unwrap()).unwrap();
+        let parameter = "[[parameter]]\nname = \"rate(A -> B)\"\nrange = [0.1, 2]";
+        for bad in [
+            parameter.to_string(),
+            format!("maximize = \"count(B)\"\nminimize = \"time\"\n{parameter}"),
+            format!("maximize = \"time\"\n{parameter}"),
+            format!("maximize = \"yield\"\n{parameter}"),
+            format!("maximize = \"count(D)\"\n{parameter}"),
+            "maximize = \"count(B)\"\n[[parameter]]\nname = \"wrap\"\nrange = [0, 1]".to_string(),
+            "maximize = \"count(B)\"\n[[parameter]]\nname = \"rate(A -> B)\"\nrange = [2, 1]".to_string(),
+            "maximize = \"count(B)\"\n[[parameter]]\nname = \"rate(A -> B)\"\nrange = [0, 1]\nlog = true".to_string(),
+        ] {
+            let spec = OptimizeSpec::from_toml(&bad).unwrap();
+            assert!(optimize(&manifest, &spec, 0, |_, _, _| {}).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::f64::consts::PI;
+use std::fs;
+use std::path::PathBuf;
+
+use rand::random;
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::simulator::{apply_reaction, extend_reaction_history, initialize_queue, square_neighbors};
+use crate::state::{Settings, SimulatorComponents, SimulatorState};
+
+/// What counts as "the same" when looking for clusters and interfaces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Grouping {
+    State, // Each state on its own.
+    Class, // States that share a color class together; a state without one is its own class.
+}
+
+/// The groups a board's states fall into: their names, and each state's
+/// group, by state id.
+pub struct Groups {
+    pub names: Vec<String>,
+    pub of_state: Vec<usize>,
+}
+
+impl Groups {
+    pub fn new(components: &SimulatorComponents, grouping: Grouping) -> Groups {
+        let n_states = components.state_names.keys().max().map_or(0, |id| id + 1);
+        match grouping {
+            // A state listed in the colormap can have more than one id, so
+            // states are grouped by name.
+            Grouping::State => {
+                let mut names: Vec<String> = components.state_names.values().cloned().collect();
+                names.sort();
+                names.dedup();
+                let of_state = (0..n_states).map(|state| names.binary_search(&components.state_names[&state]).unwrap()).collect();
+                Groups { names, of_state }
+            },
+            Grouping::Class => Groups {
+                names: components.colorclass_names.clone(),
+                of_state: (0..n_states).map(|state| components.state_colorclasses[&state]).collect(),
+            },
+        }
+    }
+}
+
+/// How one group's cells are arranged into clusters: sets of cells connected
+/// through neighbors in the same group.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClusterStats {
+    pub n_cells: usize,
+    pub n_clusters: usize,
+    pub largest: usize,
+}
+
+impl ClusterStats {
+    pub fn mean_size(&self) -> f64 {
+        match self.n_clusters {
+            0 => 0.0,
+            n => self.n_cells as f64 / n as f64,
+        }
+    }
+}
+
+/// Statistics of one board snapshot, per group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternStats {
+    pub clusters: Vec<ClusterStats>,
+    pub interfaces: Vec<Vec<usize>>, // Neighboring cell pairs between two groups, indexed [lower][higher].
+    pub spectra: Vec<Vec<f64>>,       // Radially averaged power spectrum, by wavenumber bin (see `radial_spectrum`).
+}
+
+/// Labels each cell with its cluster, numbered from 0 in the order they're
+/// found, and returns the labels and cluster sizes. Cells are connected the
+/// way the simulator connects them, through their four neighbors.
+pub fn label_clusters(groups: &[usize], settings: &Settings) -> (Vec<usize>, Vec<usize>) {
+    let mut labels = vec![usize::MAX; groups.len()];
+    let mut sizes = Vec::new();
+    for start in 0..groups.len() {
+        if labels[start] != usize::MAX {
+            continue;
+        }
+        let label = sizes.len();
+        labels[start] = label;
+        let mut size = 0;
+        let mut stack = vec![start];
+        while let Some(cell) = stack.pop() {
+            size += 1;
+            for neighbor in square_neighbors(cell, settings.n_cols, settings.n_rows, settings.wrap) {
+                if labels[neighbor] == usize::MAX && groups[neighbor] == groups[cell] {
+                    labels[neighbor] = label;
+                    stack.push(neighbor);
+                }
+            }
+        }
+        sizes.push(size);
+    }
+    (labels, sizes)
+}
+
+/// Counts the neighboring pairs of cells in each two groups, each pair once.
+/// With wrapping on a board one or two cells across, a cell can neighbor the
+/// same cell twice, across both edges, and that's two pairs.
+pub fn interface_lengths(groups: &[usize], n_groups: usize, settings: &Settings) -> Vec<Vec<usize>> {
+    let mut lengths = vec![vec![0; n_groups]; n_groups];
+    for cell in 0..groups.len() {
+        for neighbor in square_neighbors(cell, settings.n_cols, settings.n_rows, settings.wrap) {
+            if neighbor > cell && groups[neighbor] != groups[cell] {
+                let (low, high) = (groups[cell].min(groups[neighbor]), groups[cell].max(groups[neighbor]));
+                lengths[low][high] += 1;
+            }
+        }
+    }
+    lengths
+}
+
+/// The power spectrum of a field over the board, averaged over rings of
+/// equal wavenumber. Bin `k` (from 1) holds wavenumbers within half a bin of
+/// k / L cycles per cell, where L is the longer side of the board, so its
+/// wavelength is L / k cells. The field's mean is removed first, and the
+/// board is treated as periodic whether or not it wraps.
+pub fn radial_spectrum(field: &[f64], n_rows: usize, n_cols: usize) -> Vec<f64> {
+    let mean = field.iter().sum::<f64>() / field.len().max(1) as f64;
+    // A 2D discrete Fourier transform, done along rows and then columns.
+    let transform = |values: &[(f64, f64)], n: usize, stride: usize, offset: usize| -> Vec<(f64, f64)> {
+        let twiddles: Vec<(f64, f64)> = (0..n).map(|j| (2.0 * PI * j as f64 / n as f64).sin_cos()).collect();
+        (0..n)
+            .map(|k| {
+                (0..n).fold((0.0, 0.0), |(re, im), j| {
+                    let (x_re, x_im) = values[offset + j * stride];
+                    let (sin, cos) = twiddles[k * j % n];
+                    (re + x_re * cos + x_im * sin, im + x_im * cos - x_re * sin)
+                })
+            })
+            .collect()
+    };
+    let values: Vec<(f64, f64)> = field.iter().map(|value| (value - mean, 0.0)).collect();
+    let mut rows_done = vec![(0.0, 0.0); values.len()];
+    for row in 0..n_rows {
+        let transformed = transform(&values, n_cols, 1, row * n_cols);
+        rows_done[row * n_cols..(row + 1) * n_cols].copy_from_slice(&transformed);
+    }
+    let mut spectrum_2d = vec![(0.0, 0.0); values.len()];
+    for col in 0..n_cols {
+        for (row, value) in transform(&rows_done, n_rows, n_cols, col).into_iter().enumerate() {
+            spectrum_2d[row * n_cols + col] = value;
+        }
+    }
+
+    let longest = n_rows.max(n_cols);
+    let n_bins = (longest as f64 * 0.5f64.hypot(0.5)).round() as usize;
+    let mut power = vec![0.0; n_bins + 1];
+    let mut n_modes = vec![0usize; n_bins + 1];
+    for row in 0..n_rows {
+        for col in 0..n_cols {
+            let fy = row.min(n_rows - row) as f64 / n_rows as f64;
+            let fx = col.min(n_cols - col) as f64 / n_cols as f64;
+            let bin = (fx.hypot(fy) * longest as f64).round() as usize;
+            let (re, im) = spectrum_2d[row * n_cols + col];
+            power[bin] += (re * re + im * im) / field.len() as f64;
+            n_modes[bin] += 1;
+        }
+    }
+    (0..=n_bins).map(|bin| if n_modes[bin] > 0 { power[bin] / n_modes[bin] as f64 } else { 0.0 }).collect()
+}
+
+/// The wavelength, in cells, of the strongest nonzero wavenumber bin of a
+/// spectrum from `radial_spectrum`, or None for a featureless field.
+pub fn dominant_wavelength(spectrum: &[f64], n_rows: usize, n_cols: usize) -> Option<f64> {
+    let (bin, power) = spectrum.iter().enumerate().skip(1).max_by(|a, b| a.1.total_cmp(b.1))?;
+    (*power > 1e-12).then(|| n_rows.max(n_cols) as f64 / bin as f64)
+}
+
+/// Measures clusters, interfaces, and spectra for one board.
+pub fn pattern_stats(board: &[usize], groups: &Groups, settings: &Settings) -> PatternStats {
+    let n_groups = groups.names.len();
+    let grouped: Vec<usize> = board.iter().map(|state| groups.of_state[*state]).collect();
+    let (labels, sizes) = label_clusters(&grouped, settings);
+    let mut cluster_groups = vec![0; sizes.len()];
+    for (cell, label) in labels.iter().enumerate() {
+        cluster_groups[*label] = grouped[cell];
+    }
+    let mut clusters = vec![ClusterStats { n_cells: 0, n_clusters: 0, largest: 0 }; n_groups];
+    for (size, group) in sizes.iter().zip(cluster_groups) {
+        let stats = &mut clusters[group];
+        stats.n_cells += size;
+        stats.n_clusters += 1;
+        stats.largest = stats.largest.max(*size);
+    }
+    let spectra = (0..n_groups)
+        .map(|group| {
+            let field: Vec<f64> = grouped.iter().map(|cell_group| if *cell_group == group { 1.0 } else { 0.0 }).collect();
+            radial_spectrum(&field, settings.n_rows, settings.n_cols)
+        })
+        .collect();
+    PatternStats { clusters, interfaces: interface_lengths(&grouped, n_groups, settings), spectra }
+}
+
+/// Runs one seeded simulation of the board on screen until `sample_times`
+/// end, handing `visit` the displayed board (`current_states`) as it stood at
+/// each sample time.
+pub fn sample_boards(
+    components: &SimulatorComponents,
+    settings: &Settings,
+    seed: u64,
+    sample_times: &[f64],
+    mut visit: impl FnMut(usize, &[usize])
+) {
+    let mut components = components.clone();
+    let mut global_state = SimulatorState::new(Some(seed), 0);
+    initialize_queue(&components, &mut global_state, settings);
+    let mut sample_idx = 0;
+    while sample_idx < sample_times.len() {
+        let n_old = components.reaction_history.len();
+        extend_reaction_history(&mut components, &mut global_state, settings);
+        let Some(event) = components.reaction_history.last().copied().filter(|_| components.reaction_history.len() > n_old) else {
+            break;
+        };
+        while sample_idx < sample_times.len() && sample_times[sample_idx] < event.t as f64 {
+            visit(sample_idx, &components.current_states);
+            sample_idx += 1;
+        }
+        apply_reaction(&event, &global_state, &mut components, settings, true);
+        // Only the latest event is needed to time the next ones.
+        components.reaction_history.drain(..n_old);
+    }
+    for idx in sample_idx..sample_times.len() {
+        visit(idx, &components.current_states);
+    }
+}
+
+/// A few lines on a board's clusters and interfaces, for printing.
+pub fn describe_patterns(stats: &PatternStats, groups: &Groups, settings: &Settings) -> String {
+    let mut text = String::new();
+    for (group, name) in groups.names.iter().enumerate() {
+        let clusters = stats.clusters[group];
+        if clusters.n_cells == 0 {
+            continue;
+        }
+        let wavelength = dominant_wavelength(&stats.spectra[group], settings.n_rows, settings.n_cols)
+            .map_or(String::from("none"), |wavelength| format!("{wavelength:.1}"));
+        text.push_str(&format!(
+            "    {name}: {} cell(s) in {} cluster(s), largest {}, mean {:.1}; dominant wavelength {wavelength}\n",
+            clusters.n_cells, clusters.n_clusters, clusters.largest, clusters.mean_size()
+        ));
+    }
+    for (low, lengths) in stats.interfaces.iter().enumerate() {
+        for (high, length) in lengths.iter().enumerate().filter(|(_, length)| **length > 0) {
+            text.push_str(&format!("    {}|{}: interface length {length}\n", groups.names[low], groups.names[high]));
+        }
+    }
+    text
+}
+
+/// The settings for `chitin patterns`, as given on the command line.
+pub struct PatternOptions<'a> {
+    pub by: Option<&'a str>,
+    pub time: Option<&'a str>,
+    pub samples: Option<&'a str>,
+    pub seed: Option<&'a str>,
+    pub spectrum: Option<&'a str>,
+}
+
+/// Runs `chitin patterns`: samples the board over one seeded run and writes
+/// cluster, interface, and dominant wavelength statistics per group as a time
+/// series to a CSV file (or stdout), and optionally the last sample's
+/// radially averaged power spectra to another CSV file. Returns the process
+/// exit code.
+pub fn patterns_manifest_file(
+    input_file: PathBuf,
+    output_file: Option<PathBuf>,
+    options: PatternOptions,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings, _) = match load_from_manifest(&manifest, scenario) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let parsed = (|| -> Result<(Grouping, f64, usize, u64), String> {
+        let grouping = match options.by {
+            None | Some("class") => Grouping::Class,
+            Some("state") => Grouping::State,
+            Some(other) => return Err(format!("--by should be state or class, not {other:?}")),
+        };
+        let parse = |value: Option<&str>, flag: &str| value.map(|value| value.parse::<f64>().map_err(|_| format!("{flag} can't be {value:?}")));
+        let t_end = parse(options.time, "--time").transpose()?.unwrap_or(settings.max_duration as f64);
+        let n_samples = parse(options.samples, "--samples").transpose()?.unwrap_or(100.0) as usize;
+        let seed = match options.seed {
+            Some(seed) => seed.parse().map_err(|_| format!("--seed can't be {seed:?}"))?,
+            None => settings.rng_seed.map_or_else(random::<u32>, |seed| seed as u32) as u64,
+        };
+        if !(t_end > 0.0 && t_end.is_finite()) || n_samples == 0 {
+            return Err("--time and --samples should be positive".to_string());
+        }
+        Ok((grouping, t_end, n_samples, seed))
+    })();
+    let (grouping, t_end, n_samples, seed) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 2;
+        }
+    };
+
+    let groups = Groups::new(&components, grouping);
+    let n_groups = groups.names.len();
+    let sample_times: Vec<f64> = (0..=n_samples).map(|idx| t_end * idx as f64 / n_samples as f64).collect();
+    let mut samples: Vec<PatternStats> = Vec::new();
+    sample_boards(&components, &settings, seed, &sample_times, |_, board| samples.push(pattern_stats(board, &groups, &settings)));
+
+    let mut text = String::from("t");
+    for name in groups.names.iter() {
+        text.push_str(&format!(",cells({name}),clusters({name}),largest_cluster({name}),mean_cluster({name}),wavelength({name})"));
+    }
+    for low in 0..n_groups {
+        for high in low + 1..n_groups {
+            text.push_str(&format!(",interface({}|{})", groups.names[low], groups.names[high]));
+        }
+    }
+    text.push('\n');
+    for (t, stats) in sample_times.iter().zip(samples.iter()) {
+        text.push_str(&t.to_string());
+        for (clusters, spectrum) in stats.clusters.iter().zip(stats.spectra.iter()) {
+            let wavelength = dominant_wavelength(spectrum, settings.n_rows, settings.n_cols).map(|wavelength| wavelength.to_string());
+            text.push_str(&format!(
+                ",{},{},{},{},{}",
+                clusters.n_cells, clusters.n_clusters, clusters.largest, clusters.mean_size(), wavelength.unwrap_or_default()
+            ));
+        }
+        for low in 0..n_groups {
+            for high in low + 1..n_groups {
+                text.push_str(&format!(",{}", stats.interfaces[low][high]));
+            }
+        }
+        text.push('\n');
+    }
+
+    let last = samples.last().unwrap();
+    if let Some(path) = options.spectrum {
+        let longest = settings.n_rows.max(settings.n_cols);
+        let mut spectrum_text = String::from("wavenumber,wavelength");
+        for name in groups.names.iter() {
+            spectrum_text.push_str(&format!(",power({name})"));
+        }
+        spectrum_text.push('\n');
+        for bin in 1..last.spectra.first().map_or(0, |spectrum| spectrum.len()) {
+            spectrum_text.push_str(&format!("{},{}", bin as f64 / longest as f64, longest as f64 / bin as f64));
+            for spectrum in last.spectra.iter() {
+                spectrum_text.push_str(&format!(",{}", spectrum[bin]));
+            }
+            spectrum_text.push('\n');
+        }
+        if let Err(err) = fs::write(path, spectrum_text) {
+            eprintln!("Couldn't write {path:?}: {err}");
+            return 1;
+        }
+    }
+    match output_file {
+        Some(path) => match fs::write(&path, text) {
+            Ok(()) => {
+                println!("Wrote {} sample(s) up to t={t_end} (seed {seed}) to {path:?}. At the end:", sample_times.len());
+                print!("{}", describe_patterns(last, &groups, &settings));
+                0
+            },
+            Err(err) => {
+                eprintln!("Couldn't write {path:?}: {err}");
+                1
+            }
+        },
+        None => {
+            print!("{text}");
+            0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+
+    use super::{dominant_wavelength, pattern_stats, radial_spectrum, sample_boards, Grouping, Groups};
+
+    #[test]
+    fn test_clusters_and_interfaces() {
+        let manifest = "\
+!START_COLORMAP
+{Solid} A, B: (0, 0, 0)
+E: (255, 255, 255)
+!END_COLORMAP
+!START_TRANSITION_RULES
+A -> B (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A B E A
+E E E B
+A E E E
+!END_INIT_STATE";
+        let (components, mut settings) = settings_input::settings(manifest).unwrap();
+        let by_state = Groups::new(&components, Grouping::State);
+        let stats = pattern_stats(&components.current_states, &by_state, &settings);
+        let [a, b, e] = ["A", "B", "E"].map(|name| by_state.names.iter().position(|group| group == name).unwrap());
+        assert_eq!((stats.clusters[a].n_cells, stats.clusters[a].n_clusters, stats.clusters[a].largest), (3, 3, 1));
+        assert_eq!((stats.clusters[b].n_clusters, stats.clusters[e].n_clusters, stats.clusters[e].largest), (2, 1, 7));
+        let between = |x: usize, y: usize| stats.interfaces[x.min(y)][x.max(y)];
+        assert_eq!((between(a, b), between(a, e), between(b, e)), (2, 4, 4));
+
+        // Joined into one class, the corner A and B touch; wrapping also
+        // joins them to the A in the opposite corner.
+        let by_class = Groups::new(&components, Grouping::Class);
+        let solid = by_class.names.iter().position(|group| group == "Solid").unwrap();
+        let stats = pattern_stats(&components.current_states, &by_class, &settings);
+        assert_eq!((stats.clusters[solid].n_cells, stats.clusters[solid].n_clusters, stats.clusters[solid].largest), (5, 3, 2));
+        settings.wrap = true;
+        let stats = pattern_stats(&components.current_states, &by_class, &settings);
+        assert_eq!((stats.clusters[solid].n_clusters, stats.clusters[solid].largest), (1, 5));
+        assert_eq!(stats.interfaces.iter().flatten().sum::<usize>(), 12);
+    }
+
+    #[test]
+    fn test_stripe_wavelength() {
+        // Vertical stripes two cells wide repeat every 4 cells.
+        let (n_rows, n_cols) = (8, 16);
+        let field: Vec<f64> = (0..n_rows * n_cols).map(|cell| if cell % n_cols % 4 < 2 { 1.0 } else { 0.0 }).collect();
+        let spectrum = radial_spectrum(&field, n_rows, n_cols);
+        assert_eq!(dominant_wavelength(&spectrum, n_rows, n_cols), Some(4.0));
+        assert_eq!(dominant_wavelength(&radial_spectrum(&vec![1.0; n_rows * n_cols], n_rows, n_cols), n_rows, n_cols), None);
+    }
+
+    #[test]
+    fn test_sample_boards() {
+        let manifest = "\
+!START_TRANSITION_RULES
+A -> B (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A A A
+A A A
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        let a = components.state_ids["A"];
+        let mut counts = Vec::new();
+        sample_boards(&components, &settings, 1, &[0.0, 0.5, 1.0, 100.0], |idx, board| {
+            counts.push((idx, board.iter().filter(|state| **state == a).count()));
+        });
+        assert_eq!(counts.iter().map(|(idx, _)| *idx).collect::<Vec<usize>>(), [0, 1, 2, 3]);
+        assert_eq!((counts[0].1, counts[3].1), (6, 0));
+        assert!(counts.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+}
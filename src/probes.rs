@@ -0,0 +1,428 @@
+use std::fs;
+use std::path::PathBuf;
+
+use rand::random;
+
+use crate::input::read_manifest_file;
+use crate::input_parsers::{build_simulation, ParsedManifest};
+use crate::rate_inference::simulate_history;
+use crate::reactions::ReactionEvent;
+use crate::state::{Settings, SimulatorComponents};
+use crate::trace::parse_trace;
+
+/// A probe's logical value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Low,
+    High,
+    Unknown, // Any state that isn't mapped to low or high.
+}
+
+impl Level {
+    /// How the level is written in a VCD file.
+    pub fn vcd_char(&self) -> char {
+        match self {
+            Level::Low => '0',
+            Level::High => '1',
+            Level::Unknown => 'x',
+        }
+    }
+}
+
+/// A state, or every state in a colormap class, as in `!PROBE_HIGH 1, {1 signal}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateOrClass {
+    State(String),
+    Class(String),
+}
+
+/// A probe cell named in a manifest, as in `!PROBE carry 27,5`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeSpec {
+    pub name: String,
+    pub row: usize,
+    pub col: usize,
+}
+
+/// One watched cell and every change of its level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Probe {
+    pub name: String,
+    pub loc: usize,
+    pub changes: Vec<(usize, f32, Level)>, // (n_events, t, level), starting with the level at t=0.
+}
+
+impl Probe {
+    /// The probe's level once `n_events` events had happened.
+    pub fn level_at(&self, n_events: usize) -> Level {
+        let n_changes = self.changes.partition_point(|(change_events, _, _)| *change_events <= n_events);
+        self.changes[n_changes.saturating_sub(1)].2
+    }
+}
+
+/// Records the levels of probe cells through a reaction history. States map
+/// to levels the way the manifest's `!PROBE_HIGH` and `!PROBE_LOW` lines say,
+/// or, without them, a state named `1` is high and one named `0` is low.
+pub struct ProbeRecorder {
+    pub probes: Vec<Probe>,
+    level_of_state: Vec<Level>, // By state id.
+    n_events: usize,            // How much of the history has been followed.
+    n_cols: usize,
+}
+
+impl ProbeRecorder {
+    /// Sets up recording for a manifest (with its scenario already applied)
+    /// and the probes it names, before any events have been followed.
+    pub fn new(manifest: &ParsedManifest, components: &SimulatorComponents, settings: &Settings) -> Result<ProbeRecorder, String> {
+        let n_states = components.state_names.keys().max().map_or(0, |id| id + 1);
+        let mut level_of_state = vec![Level::Unknown; n_states];
+        let defaults = [(Level::High, StateOrClass::State("1".to_string())), (Level::Low, StateOrClass::State("0".to_string()))];
+        let levels = match manifest.probe_levels.is_empty() {
+            true => &defaults[..],
+            false => &manifest.probe_levels[..],
+        };
+        for (level, states) in levels.iter() {
+            let ids: Vec<usize> = match states {
+                StateOrClass::State(name) => components.state_names.iter().filter(|(_, state_name)| *state_name == name).map(|(id, _)| *id).collect(),
+                StateOrClass::Class(name) => match components.colorclass_names.iter().position(|class| class == name) {
+                    Some(class) => components.state_colorclasses.iter().filter(|(_, state_class)| **state_class == class).map(|(id, _)| *id).collect(),
+                    None => return Err(format!("there's no color class named {name:?} to probe for")),
+                },
+            };
+            if ids.is_empty() && !manifest.probe_levels.is_empty() {
+                return Err(format!("there's no state {states:?} to probe for"));
+            }
+            for id in ids {
+                if level_of_state[id] != Level::Unknown && level_of_state[id] != *level {
+                    return Err(format!("{} is given as both high and low", components.state_names[&id]));
+                }
+                level_of_state[id] = *level;
+            }
+        }
+
+        let mut recorder = ProbeRecorder { probes: Vec::new(), level_of_state, n_events: 0, n_cols: settings.n_cols };
+        for spec in manifest.probes.iter() {
+            if spec.row >= settings.n_rows || spec.col >= settings.n_cols {
+                return Err(format!("probe {} at {},{} is off the {}x{} board", spec.name, spec.row, spec.col, settings.n_rows, settings.n_cols));
+            }
+            if recorder.probes.iter().any(|probe| probe.name == spec.name) {
+                return Err(format!("there's more than one probe named {}", spec.name));
+            }
+            recorder.add_probe(spec.name.clone(), spec.row * settings.n_cols + spec.col, components);
+        }
+        Ok(recorder)
+    }
+
+    /// Starts watching a cell, reading its levels so far back out of the
+    /// history. A cell's state at t=0 is the reactant of the first event in
+    /// the whole history that changed it, or, if none did, the latest one.
+    pub fn add_probe(&mut self, name: String, loc: usize, components: &SimulatorComponents) {
+        let touching = |event: &ReactionEvent| event.r1_loc == loc || event.r2_loc == Some(loc);
+        let history = &components.reaction_history[..self.n_events];
+        let initial = match components.reaction_history.iter().find(|event| touching(event)) {
+            Some(event) => {
+                let rxn = &components.all_reactions[event.rxn_idx];
+                if event.r1_loc == loc { rxn.r1_num } else { rxn.r2_num.unwrap() }
+            },
+            None => components.latest_states[loc],
+        };
+        self.probes.push(Probe { name, loc, changes: vec![(0, 0.0, self.level_of_state[initial])] });
+        let probe_idx = self.probes.len() - 1;
+        for (event_idx, event) in history.iter().enumerate().filter(|(_, event)| touching(event)) {
+            self.record(probe_idx, event, event_idx + 1, components);
+        }
+    }
+
+    /// Stops watching a cell, if a probe was on it, or starts, naming the
+    /// probe after its cell. Returns whether the cell is watched now.
+    pub fn toggle_probe(&mut self, loc: usize, components: &SimulatorComponents) -> bool {
+        match self.probes.iter().position(|probe| probe.loc == loc) {
+            Some(probe_idx) => {
+                self.probes.remove(probe_idx);
+                false
+            },
+            None => {
+                self.add_probe(format!("r{}c{}", loc / self.n_cols, loc % self.n_cols), loc, components);
+                true
+            }
+        }
+    }
+
+    fn record(&mut self, probe_idx: usize, event: &ReactionEvent, n_events: usize, components: &SimulatorComponents) {
+        let rxn = &components.all_reactions[event.rxn_idx];
+        let probe = &mut self.probes[probe_idx];
+        let state = if event.r1_loc == probe.loc { rxn.p1_num } else { rxn.p2_num.unwrap() };
+        let level = self.level_of_state[state];
+        if probe.changes.last().unwrap().2 != level {
+            probe.changes.push((n_events, event.t, level));
+        }
+    }
+
+    /// Records the next event of the history.
+    pub fn apply(&mut self, event: &ReactionEvent, components: &SimulatorComponents) {
+        self.n_events += 1;
+        for probe_idx in 0..self.probes.len() {
+            let loc = self.probes[probe_idx].loc;
+            if event.r1_loc == loc || event.r2_loc == Some(loc) {
+                self.record(probe_idx, event, self.n_events, components);
+            }
+        }
+    }
+
+    /// Follows whatever the history has gained since the last call.
+    pub fn catch_up(&mut self, components: &SimulatorComponents) {
+        while self.n_events < components.reaction_history.len() {
+            let event = components.reaction_history[self.n_events];
+            self.apply(&event, components);
+        }
+    }
+}
+
+/// Writes probes' changes as a Value Change Dump, for waveform viewers. VCD
+/// times are whole numbers, so each unit of simulated time is `resolution`
+/// ticks of 1 ns; changes that land on the same tick keep only the last.
+pub fn write_vcd(probes: &[Probe], resolution: f64) -> String {
+    // Identifiers are short strings of printable characters, from "!" up.
+    let identifier = |mut idx: usize| {
+        let mut code = String::new();
+        loop {
+            code.push((b'!' + (idx % 94) as u8) as char);
+            idx /= 94;
+            if idx == 0 {
+                return code;
+            }
+            idx -= 1;
+        }
+    };
+    let mut text = String::from("$version chitin $end\n");
+    text.push_str(&format!("$comment one unit of simulated time is {resolution} ticks $end\n$timescale 1 ns $end\n"));
+    text.push_str("$scope module probes $end\n");
+    for (probe_idx, probe) in probes.iter().enumerate() {
+        text.push_str(&format!("$var wire 1 {} {} $end\n", identifier(probe_idx), probe.name));
+    }
+    text.push_str("$upscope $end\n$enddefinitions $end\n#0\n$dumpvars\n");
+    for (probe_idx, probe) in probes.iter().enumerate() {
+        text.push_str(&format!("{}{}\n", probe.changes[0].2.vcd_char(), identifier(probe_idx)));
+    }
+    text.push_str("$end\n");
+
+    let mut changes: Vec<(u64, usize, usize, Level)> = probes
+        .iter()
+        .enumerate()
+        .flat_map(|(probe_idx, probe)| {
+            probe.changes[1..].iter().map(move |(n_events, t, level)| ((*t as f64 * resolution).round() as u64, *n_events, probe_idx, *level))
+        })
+        .collect();
+    changes.sort_by_key(|(tick, n_events, probe_idx, _)| (*tick, *n_events, *probe_idx));
+    let mut written: Vec<Level> = probes.iter().map(|probe| probe.changes[0].2).collect();
+    let mut idx = 0;
+    while idx < changes.len() {
+        let tick = changes[idx].0;
+        let mut at_tick = Vec::new();
+        while idx < changes.len() && changes[idx].0 == tick {
+            let (_, _, probe_idx, level) = changes[idx];
+            at_tick.retain(|(other, _)| *other != probe_idx);
+            at_tick.push((probe_idx, level));
+            idx += 1;
+        }
+        at_tick.retain(|(probe_idx, level)| written[*probe_idx] != *level);
+        if at_tick.is_empty() {
+            continue;
+        }
+        text.push_str(&format!("#{tick}\n"));
+        for (probe_idx, level) in at_tick {
+            text.push_str(&format!("{}{}\n", level.vcd_char(), identifier(probe_idx)));
+            written[probe_idx] = level;
+        }
+    }
+    text
+}
+
+/// The settings for `chitin probes`, as given on the command line.
+pub struct ProbeOptions<'a> {
+    pub trace: Option<PathBuf>,
+    pub time: Option<&'a str>,
+    pub seed: Option<&'a str>,
+    pub resolution: Option<&'a str>,
+}
+
+/// Runs `chitin probes`: records the manifest's probe cells through a trace
+/// file or a seeded run, writes them to a VCD file, and prints how long each
+/// spent at each level. Returns the process exit code.
+pub fn probes_manifest_file(
+    input_file: PathBuf,
+    output_file: PathBuf,
+    options: ProbeOptions,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path).and_then(|manifest| manifest.with_scenario(scenario)) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings) = match build_simulation(&manifest) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let mut recorder = match ProbeRecorder::new(&manifest, &components, &settings) {
+        Ok(recorder) if recorder.probes.is_empty() => {
+            eprintln!("error: {input_file:?} doesn't name any probe cells; add a line like !PROBE out 3,4");
+            return 1;
+        },
+        Ok(recorder) => recorder,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return 1;
+        }
+    };
+    let resolution = match options.resolution.map(|value| value.parse::<f64>()) {
+        None => 1000.0,
+        Some(Ok(resolution)) if resolution > 0.0 && resolution.is_finite() => resolution,
+        _ => {
+            eprintln!("error: --resolution should be a positive number of ticks per unit time");
+            return 2;
+        }
+    };
+    let (events, t_end) = match options.trace {
+        Some(path) => match fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| parse_trace(&text, &components, &settings))
+        {
+            Ok(events) => {
+                let t_end = events.last().map_or(0.0, |event| event.t);
+                (events, t_end)
+            },
+            Err(err) => {
+                eprintln!("error: couldn't read {path:?}: {err}");
+                return 1;
+            }
+        },
+        None => {
+            let t_end = match options.time.map(|time| time.parse::<f32>()) {
+                None => settings.max_duration,
+                Some(Ok(t_end)) if t_end > 0.0 => t_end,
+                _ => {
+                    eprintln!("error: --time should be a positive time");
+                    return 2;
+                }
+            };
+            let seed = match options.seed.map(|seed| seed.parse::<u64>()) {
+                None => settings.rng_seed.map_or_else(random::<u32>, |seed| seed as u32) as u64,
+                Some(Ok(seed)) => seed,
+                Some(Err(_)) => {
+                    eprintln!("error: --seed should be a whole number");
+                    return 2;
+                }
+            };
+            println!("Simulating up to t={t_end} (seed {seed}).");
+            (simulate_history(&components, &settings, seed, t_end), t_end)
+        }
+    };
+    for event in events.iter() {
+        recorder.apply(event, &components);
+    }
+
+    if let Err(err) = fs::write(&output_file, write_vcd(&recorder.probes, resolution)) {
+        eprintln!("Couldn't write {output_file:?}: {err}");
+        return 1;
+    }
+    println!("Wrote {} probe(s) over {} event(s), up to t={t_end}, to {output_file:?}.", recorder.probes.len(), events.len());
+    for probe in recorder.probes.iter() {
+        let mut time_at = [0.0f32; 3];
+        for (idx, (_, t, level)) in probe.changes.iter().enumerate() {
+            let until = probe.changes.get(idx + 1).map_or(t_end, |(_, next_t, _)| *next_t);
+            time_at[*level as usize] += (until - t).max(0.0);
+        }
+        let share = |time: f32| if t_end > 0.0 { 100.0 * time / t_end } else { 0.0 };
+        println!(
+            "    {}: {} change(s); low {:.1}%, high {:.1}%, X {:.1}% of the time, ending {}",
+            probe.name, probe.changes.len() - 1, share(time_at[0]), share(time_at[1]), share(time_at[2]),
+            probe.changes.last().unwrap().2.vcd_char()
+        );
+    }
+    0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::{build_simulation, settings_input};
+    use crate::trace::parse_trace;
+
+    use super::{write_vcd, Level, ProbeRecorder};
+
+    const MANIFEST: &str = "\
+!START_COLORMAP
+{Signal} 1, 1x: (0, 0, 0)
+0: (255, 0, 0)
+B: (200, 200, 200)
+!END_COLORMAP
+!START_TRANSITION_RULES
+1 + B -> B + 1 (1)
+0 + B -> B + 0 (1)
+1 -> 1x (1)
+!END_TRANSITION_RULES
+!PROBE end 0,2
+!START_INIT_STATE
+1 B B
+0 B B
+!END_INIT_STATE";
+
+    #[test]
+    fn test_levels() {
+        let manifest = settings_input::manifest(MANIFEST).unwrap().with_scenario(None).unwrap();
+        let (mut components, settings) = build_simulation(&manifest).unwrap();
+        let mut recorder = ProbeRecorder::new(&manifest, &components, &settings).unwrap();
+        let events = parse_trace("1 0 0,0 0,1\n2 0 0,1 0,2\n4 2 0,2\n", &components, &settings).unwrap();
+        for event in events.iter() {
+            recorder.apply(event, &components);
+        }
+        // Without level lines, 1 is high, 0 low, and 1x unknown.
+        let changes: Vec<(usize, Level)> = recorder.probes[0].changes.iter().map(|(n, _, level)| (*n, *level)).collect();
+        assert_eq!(changes, [(0, Level::Unknown), (2, Level::High), (3, Level::Unknown)]);
+        assert_eq!((recorder.probes[0].level_at(1), recorder.probes[0].level_at(2)), (Level::Unknown, Level::High));
+
+        // A probe added later reads the history so far, and a class maps
+        // all its states.
+        let text = format!("{MANIFEST}\n!PROBE_HIGH {{Signal}}\n!PROBE_LOW 0");
+        let manifest = settings_input::manifest(&text).unwrap().with_scenario(None).unwrap();
+        let mut recorder = ProbeRecorder::new(&manifest, &components, &settings).unwrap();
+        components.reaction_history = events;
+        recorder.catch_up(&components);
+        assert!(recorder.toggle_probe(1, &components));
+        assert_eq!(recorder.probes[1].name, "r0c1");
+        let levels: Vec<Level> = recorder.probes.iter().flat_map(|probe| probe.changes.iter().map(|(_, _, level)| *level)).collect();
+        assert_eq!(levels, [Level::Unknown, Level::High, Level::Unknown, Level::High, Level::Unknown]);
+        assert!(!recorder.toggle_probe(1, &components));
+        assert_eq!(recorder.probes.len(), 1);
+    }
+
+    #[test]
+    fn test_bad_probes() {
+        for extra in ["!PROBE far 5,0", "!PROBE end 1,1", "!PROBE_HIGH {Nothing}", "!PROBE_HIGH 1\n!PROBE_LOW {Signal}"] {
+            let manifest = settings_input::manifest(&format!("{MANIFEST}\n{extra}")).unwrap().with_scenario(None).unwrap();
+            let (components, settings) = build_simulation(&manifest).unwrap();
+            assert!(ProbeRecorder::new(&manifest, &components, &settings).is_err(), "{extra}");
+        }
+    }
+
+    #[test]
+    fn test_vcd() {
+        let manifest = settings_input::manifest(&format!("{MANIFEST}\n!PROBE start 0,0")).unwrap().with_scenario(None).unwrap();
+        let (components, settings) = build_simulation(&manifest).unwrap();
+        let mut recorder = ProbeRecorder::new(&manifest, &components, &settings).unwrap();
+        let events = parse_trace("1 0 0,0 0,1\n1.0001 0 0,1 0,2\n", &components, &settings).unwrap();
+        for event in events.iter() {
+            recorder.apply(event, &components);
+        }
+        let vcd = write_vcd(&recorder.probes, 10.0);
+        assert!(vcd.contains("$var wire 1 ! end $end\n$var wire 1 \" start $end\n"));
+        assert!(vcd.ends_with("#0\n$dumpvars\nx!\n1\"\n$end\n#10\nx\"\n1!\n"), "{vcd}");
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use rand::random;
+
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::monte_carlo::normal_quantile;
+use crate::reactions::ReactionEvent;
+use crate::simulator::{extend_reaction_history, initialize_queue, square_neighbors};
+use crate::state::{Settings, SimulatorComponents, SimulatorState};
+use crate::trace::{describe_reaction, parse_trace};
+
+/// A maximum-likelihood estimate of one rule's rate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateEstimate {
+    pub n_events: usize,       // How many times the rule fired.
+    pub exposure: f64,         // How long each place it could fire was open to it, summed.
+    pub rate: f64,             // n_events / exposure.
+    pub interval: (f64, f64),  // Confidence interval for the rate.
+}
+
+/// Counts the places each rule could fire, the way the simulator schedules
+/// them: every cell in a one-cell rule's reactant state, and every (cell,
+/// neighbor) pair in a two-cell rule's reactant states, in that order. A pair
+/// of cells in the same state is one place for a rule like `A + A -> ...`,
+/// not two.
+struct InstanceCounter {
+    neighbors: Vec<Vec<usize>>,
+    unimolecular: HashMap<usize, Vec<usize>>,         // State -> rules it can fire alone.
+    bimolecular: HashMap<(usize, usize), Vec<usize>>, // (r1, r2) -> rules.
+}
+
+impl InstanceCounter {
+    fn new(components: &SimulatorComponents, settings: &Settings) -> Self {
+        let mut unimolecular: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut bimolecular: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for (rxn_idx, rxn) in components.all_reactions.iter().enumerate() {
+            match rxn.r2_num {
+                Some(r2) => bimolecular.entry((rxn.r1_num, r2)).or_default().push(rxn_idx),
+                None => unimolecular.entry(rxn.r1_num).or_default().push(rxn_idx),
+            }
+        }
+        let neighbors = (0..components.current_states.len())
+            .map(|loc| square_neighbors(loc, settings.n_cols, settings.n_rows, settings.wrap))
+            .collect();
+        InstanceCounter { neighbors, unimolecular, bimolecular }
+    }
+
+    /// Adds `sign` times the number of instances of each rule that involve
+    /// any of `cells` (counting each instance once) to `counts`.
+    fn add_instances_at(&self, board: &[usize], cells: &[usize], sign: i64, counts: &mut [i64]) {
+        let mut add_pair = |first: usize, second: usize| {
+            if board[first] == board[second] && first > second {
+                return;
+            }
+            if let Some(rules) = self.bimolecular.get(&(board[first], board[second])) {
+                rules.iter().for_each(|rxn_idx| counts[*rxn_idx] += sign);
+            }
+        };
+        for cell in cells {
+            for neighbor in self.neighbors[*cell].iter() {
+                add_pair(*cell, *neighbor);
+                if !cells.contains(neighbor) {
+                    add_pair(*neighbor, *cell);
+                }
+            }
+        }
+        for cell in cells {
+            if let Some(rules) = self.unimolecular.get(&board[*cell]) {
+                rules.iter().for_each(|rxn_idx| counts[*rxn_idx] += sign);
+            }
+        }
+    }
+}
+
+/// Estimates every rule's rate from a complete event history, starting from
+/// the board on screen and observed until `t_end`. Each instance of a rule
+/// fires after an exponential wait, so the likelihood depends only on how
+/// many times each rule fired and its total exposure, the time integral of
+/// how many places it could have fired; the estimate is their ratio. The
+/// interval is the Wald interval on the log scale, or, for a rule that never
+/// fired, the one-sided upper bound.
+pub fn estimate_rates(
+    components: &SimulatorComponents,
+    settings: &Settings,
+    events: &[ReactionEvent],
+    t_end: f64,
+    confidence: f64
+) -> Vec<RateEstimate> {
+    let n_rules = components.all_reactions.len();
+    let counter = InstanceCounter::new(components, settings);
+    let mut board = components.current_states.clone();
+    let mut counts = vec![0i64; n_rules];
+    let all_cells: Vec<usize> = (0..board.len()).collect();
+    // Every cell is "changed" here, so each instance is counted once.
+    counter.add_instances_at(&board, &all_cells, 1, &mut counts);
+
+    let mut n_events = vec![0; n_rules];
+    let mut exposure = vec![0.0; n_rules];
+    let mut t = 0.0;
+    for event in events {
+        for (rxn_idx, count) in counts.iter().enumerate() {
+            exposure[rxn_idx] += *count as f64 * (event.t as f64 - t);
+        }
+        t = event.t as f64;
+        n_events[event.rxn_idx] += 1;
+
+        let cells: Vec<usize> = std::iter::once(event.r1_loc).chain(event.r2_loc).collect();
+        counter.add_instances_at(&board, &cells, -1, &mut counts);
+        let rxn = &components.all_reactions[event.rxn_idx];
+        board[event.r1_loc] = rxn.p1_num;
+        if let (Some(r2_loc), Some(p2)) = (event.r2_loc, rxn.p2_num) {
+            board[r2_loc] = p2;
+        }
+        counter.add_instances_at(&board, &cells, 1, &mut counts);
+    }
+    for (rxn_idx, count) in counts.iter().enumerate() {
+        exposure[rxn_idx] += *count as f64 * (t_end - t).max(0.0);
+    }
+
+    let z = normal_quantile(confidence);
+    (0..n_rules)
+        .map(|rxn_idx| {
+            let (n, exposure) = (n_events[rxn_idx], exposure[rxn_idx]);
+            if exposure <= 0.0 {
+                return RateEstimate { n_events: n, exposure, rate: f64::NAN, interval: (0.0, f64::INFINITY) };
+            }
+            let rate = n as f64 / exposure;
+            let interval = match n {
+                0 => (0.0, -(1.0 - confidence).ln() / exposure),
+                _ => {
+                    let spread = (z / (n as f64).sqrt()).exp();
+                    (rate / spread, rate * spread)
+                }
+            };
+            RateEstimate { n_events: n, exposure, rate, interval }
+        })
+        .collect()
+}
+
+/// Simulates a manifest's own rules from the board on screen until `t_end`,
+/// keeping the whole history.
+pub fn simulate_history(components: &SimulatorComponents, settings: &Settings, seed: u64, t_end: f32) -> Vec<ReactionEvent> {
+    let mut components = components.clone();
+    let mut global_state = SimulatorState::new(Some(seed), 0);
+    initialize_queue(&components, &mut global_state, settings);
+    loop {
+        let n_events = components.reaction_history.len();
+        extend_reaction_history(&mut components, &mut global_state, settings);
+        match components.reaction_history.last() {
+            _ if components.reaction_history.len() == n_events => break,
+            Some(event) if event.t > t_end => {
+                components.reaction_history.pop();
+                break;
+            },
+            _ => {}
+        }
+    }
+    components.reaction_history
+}
+
+/// Runs `chitin infer`: estimates each rule's rate from a trace file (or,
+/// with `--simulate <time>`, from a seeded run of the manifest's own rates,
+/// as a check on the simulator) and prints them next to the manifest's
+/// rates. Returns the process exit code.
+pub fn infer_manifest_file(
+    input_file: PathBuf,
+    trace_file: Option<PathBuf>,
+    simulate: Option<&str>,
+    seed: Option<&str>,
+    confidence: Option<&str>,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (components, settings, _) = match load_from_manifest(&manifest, scenario) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("error: couldn't load {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let confidence = match confidence.map(|value| value.parse::<f64>()) {
+        None => 0.95,
+        Some(Ok(confidence)) if (0.5..1.0).contains(&confidence) => confidence,
+        _ => {
+            eprintln!("error: --confidence should be at least 0.5 and less than 1");
+            return 2;
+        }
+    };
+    let (events, t_end) = match (trace_file, simulate) {
+        (Some(path), None) => match fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| parse_trace(&text, &components, &settings))
+        {
+            Ok(events) => {
+                let t_end = events.last().map_or(0.0, |event| event.t as f64);
+                println!("Read {} event(s) from {path:?}, up to t={t_end}.", events.len());
+                (events, t_end)
+            },
+            Err(err) => {
+                eprintln!("error: couldn't read {path:?}: {err}");
+                return 1;
+            }
+        },
+        (None, Some(time)) => {
+            let seed = match seed.map(|seed| seed.parse::<u64>()) {
+                None => settings.rng_seed.map_or_else(random::<u32>, |seed| seed as u32) as u64,
+                Some(Ok(seed)) => seed,
+                Some(Err(_)) => {
+                    eprintln!("error: --seed should be a whole number");
+                    return 2;
+                }
+            };
+            let Some(t_end) = time.parse::<f32>().ok().filter(|t| *t > 0.0) else {
+                eprintln!("error: --simulate should be a positive time");
+                return 2;
+            };
+            let events = simulate_history(&components, &settings, seed, t_end);
+            println!("Simulated {} event(s) up to t={t_end} (seed {seed}).", events.len());
+            (events, t_end as f64)
+        },
+        _ => {
+            eprintln!("error: give either a trace file or --simulate <time>");
+            return 2;
+        }
+    };
+
+    let estimates = estimate_rates(&components, &settings, &events, t_end, confidence);
+    println!("{}% confidence intervals:", 100.0 * confidence);
+    for (rxn_idx, estimate) in estimates.iter().enumerate() {
+        let rule = describe_reaction(&components, rxn_idx);
+        let given = components.all_reactions[rxn_idx].rate;
+        if estimate.exposure <= 0.0 {
+            println!("    {rxn_idx}: {rule} (given {given}): never had a chance to fire");
+            continue;
+        }
+        println!(
+            "    {rxn_idx}: {rule} (given {given}): {:.4} [{:.4}, {:.4}] from {} event(s) over exposure {:.4}",
+            estimate.rate, estimate.interval.0, estimate.interval.1, estimate.n_events, estimate.exposure
+        );
+    }
+    0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+    use crate::trace::parse_trace;
+
+    use super::{estimate_rates, simulate_history};
+
+    #[test]
+    fn test_exposure() {
+        // One walker on a 1x2 strip can always move, so the rule is exposed
+        // the whole time.
+        let manifest = "\
+!START_TRANSITION_RULES
+A + E -> E + A (1)
+A -> X (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A E
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        let events = parse_trace("1 0 0,0 0,1\n3 0 0,1 0,0\n", &components, &settings).unwrap();
+        let estimates = estimate_rates(&components, &settings, &events, 4.0, 0.95);
+        assert_eq!(estimates[0].n_events, 2);
+        assert!((estimates[0].exposure - 4.0).abs() < 1e-9);
+        assert!((estimates[0].rate - 0.5).abs() < 1e-9);
+        assert!(estimates[0].interval.0 < 0.5 && 0.5 < estimates[0].interval.1);
+        // The decay never happened, but it could have all along.
+        assert_eq!(estimates[1].n_events, 0);
+        assert!((estimates[1].exposure - 4.0).abs() < 1e-9);
+        assert!((estimates[1].interval.1 - (0.05f64).ln().abs() / 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_recovers_simulated_rates() {
+        let manifest = "\
+wrap = true
+!START_TRANSITION_RULES
+A -> B (1)
+B -> A (2)
+A + B -> B + A (0.5)
+A + A -> C + C (0.2)
+C -> A (3)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A A A B B
+A A B B A
+A B B A A
+B B A A B
+B A A B A
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        let events = simulate_history(&components, &settings, 5, 100.0);
+        let estimates = estimate_rates(&components, &settings, &events, 100.0, 0.999);
+        for (rxn, estimate) in components.all_reactions.iter().zip(estimates.iter()) {
+            let rate = rxn.rate as f64;
+            assert!(estimate.interval.0 < rate && rate < estimate.interval.1, "{rate}: {estimate:?}");
+            assert!((estimate.rate - rate).abs() < 0.1 * rate, "{rate}: {estimate:?}");
+        }
+    }
+}
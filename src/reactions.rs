@@ -21,7 +21,7 @@ pub struct Reaction {
 }
 
 //Stores plaintext description of the reaction.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReactionDescription {
     pub r1: String,
     pub r2: Option<String>,
@@ -41,6 +41,12 @@ pub struct ReactionEvent {
 }
 
 impl ReactionDescription {
+    /// Whether two rules turn the same reactants into the same products,
+    /// whatever their rates.
+    pub fn same_reaction(&self, other: &ReactionDescription) -> bool {
+        self.r1 == other.r1 && self.r2 == other.r2 && self.p1 == other.p1 && self.p2 == other.p2
+    }
+
     pub fn all_states(&self) -> Vec<&str> {
         let mut states: Vec<&str> = Vec::new();
         states.push(&self.r1);
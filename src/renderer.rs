@@ -1,16 +1,20 @@
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::render::{WindowCanvas, TextureQuery, Texture};
-use sdl2::rect::Rect;
+use sdl2::rect::{Point, Rect};
 
-use itertools::izip;
+use itertools::{izip, Itertools};
 
 use sdl2::surface::Surface;
 use sdl2::ttf::Font;
 
+use crate::agents::AgentTracker;
+use crate::analysis::reachable_state_ids;
 use crate::button::ButtonID;
+use crate::kymograph::{state_color, Kymograph};
+use crate::probes::{Level, ProbeRecorder};
 use crate::state::{SimulatorComponents, SimulatorState, Settings};
 
 const MARGIN: u32 = 10;
@@ -22,6 +26,16 @@ pub const PLAYBAR_BUTTON_HEIGHT: u32 = 12;
 pub const PLAYBAR_HEIGHT: u32 = 2;
 
 pub const BACKGROUND_COLOR: Color = Color::RGB(200, 200, 220);
+const TRAIL_LENGTH: usize = 30; // How many of an agent's latest moves its trail shows.
+const TRAIL_COLORS: [Color; 4] = [Color::RGB(0, 0, 0), Color::RGB(200, 0, 120), Color::RGB(0, 120, 0), Color::RGB(230, 120, 0)];
+const KYMOGRAPH_MAX_WIDTH: u32 = 400;
+const KYMOGRAPH_HEIGHT: u32 = 200;
+const KYMOGRAPH_ROW_HEIGHT: u32 = 2; // Each sample's height in the kymograph panel; older ones scroll off the top.
+const WAVEFORM_ROW_HEIGHT: u32 = 20;
+const WAVEFORM_COLOR: Color = Color::RGB(0, 110, 0);
+const WAVEFORM_UNKNOWN_COLOR: Color = Color::RGB(160, 160, 170);
+const LEGEND_TEXT_COLOR: Color = Color::RGBA(0, 0, 0, 255);
+const UNREACHABLE_TEXT_COLOR: Color = Color::RGBA(150, 150, 150, 255);
 
 fn playbar_y(sim_components: &SimulatorComponents, settings: &Settings) -> i32 {
     ((settings.cell_size as i32 * settings.n_rows as i32 + sim_components.positions[0].y as i32) 
@@ -77,6 +91,14 @@ pub fn generate_legend_surface<'a>(
     }
     legend_height -= BUFFER;
 
+    // Classes that can never show up from the starting board are grayed out.
+    let reachable_states = reachable_state_ids(components);
+    let reachable_classes: HashSet<usize> = components.state_ids
+        .values()
+        .filter(|state| reachable_states.contains(state))
+        .map(|state| components.state_colorclasses[state])
+        .collect();
+
     let mut legend_surface = Surface::new(legend_width, legend_height, PixelFormatEnum::RGB24).unwrap();
     legend_surface.fill_rect(Rect::new(0, 0, legend_width, legend_height), Color::RGB(255, 255, 255)).ok();
     let x = BUFFER;
@@ -95,9 +117,10 @@ pub fn generate_legend_surface<'a>(
             Rect::new(x as i32, y as i32, font_height, font_height), 
             color
         ).unwrap();
+        let text_color = if reachable_classes.contains(&i) { LEGEND_TEXT_COLOR } else { UNREACHABLE_TEXT_COLOR };
         let font_surface = legend_font
             .render(&state_name)
-            .blended(Color::RGBA(0, 0, 0, 255))
+            .blended(text_color)
             .unwrap();
         font_surface.blit(
             None, 
@@ -120,7 +143,8 @@ pub fn calculate_window_size(
     sim_components: &mut SimulatorComponents, 
     settings: &Settings,
     state: &mut SimulatorState,
-    prerendered_surfaces: &HashMap<String, Surface>) 
+    prerendered_surfaces: &HashMap<String, Surface>,
+    kymograph: Option<&Kymograph>)
     -> (u32, u32) {
     let surface_width: u32 = settings.n_cols as u32 * settings.cell_size;
     let surface_height: u32 = settings.n_rows as u32 * settings.cell_size;
@@ -163,12 +187,25 @@ pub fn calculate_window_size(
 
     state.pressed_button_idx = sim_components.button_boxes.len();
 
+    // A kymograph panel goes under the legend.
+    let legend = &prerendered_surfaces["legend"];
+    let (side_width, side_height) = match kymograph.map(|kymograph| kymograph_panel_size(settings, kymograph)) {
+        Some((_, width, height)) => (max(legend.width(), width), legend.height() + settings.margin + height),
+        None => (legend.width(), legend.height())
+    };
     (
-        surface_width + 3 * settings.margin + prerendered_surfaces["legend"].width(),
-        max(surface_height, prerendered_surfaces["legend"].height()) + 3 * settings.margin + BUTTON_HEIGHT
+        surface_width + 3 * settings.margin + side_width,
+        max(surface_height, side_height) + 3 * settings.margin + BUTTON_HEIGHT
     )
 }
 
+/// How wide each cell is in the kymograph panel, and the panel's width and
+/// height.
+fn kymograph_panel_size(settings: &Settings, kymograph: &Kymograph) -> (u32, u32, u32) {
+    let cell_width = (KYMOGRAPH_MAX_WIDTH / kymograph.cells.len().max(1) as u32).clamp(1, settings.cell_size);
+    (cell_width, cell_width * kymograph.cells.len() as u32, KYMOGRAPH_HEIGHT)
+}
+
 pub fn render(
     canvas: &mut WindowCanvas,
     components: &SimulatorComponents,
@@ -272,6 +309,128 @@ pub fn render(
         let texture = state_textures.get(state).unwrap();
         canvas.copy(texture, None, Rect::new(position.x as i32, position.y as i32, size.width, size.height)).ok();
     }
+}
+
+/// Draws a line through the centers of the cells each agent on screen moved
+/// through lately, over a board drawn by `render`. Moves across a wrapped
+/// edge aren't drawn.
+pub fn draw_agent_trails(canvas: &mut WindowCanvas, components: &SimulatorComponents, state: &SimulatorState, tracker: &AgentTracker) {
+    let center = |loc: usize| {
+        let (position, size) = (&components.positions[loc], &components.sizes[loc]);
+        Point::new((position.x + size.width as f32 / 2.0) as i32, (position.y + size.height as f32 / 2.0) as i32)
+    };
+    for (agent_idx, agent) in tracker.agents.iter().enumerate() {
+        canvas.set_draw_color(TRAIL_COLORS[agent_idx % TRAIL_COLORS.len()]);
+        for pair in agent.trail(state.next_rxn_event, TRAIL_LENGTH).windows(2) {
+            let (from, to) = (center(pair[0].loc), center(pair[1].loc));
+            let across_edge = (from.x() - to.x()).abs() + (from.y() - to.y()).abs() > 2 * components.sizes[pair[0].loc].width as i32;
+            if !across_edge {
+                canvas.draw_line(from, to).ok();
+            }
+        }
+    }
+}
+
+/// Draws the latest samples of a kymograph in a panel under the legend, the
+/// newest at the bottom once the panel fills up.
+pub fn draw_kymograph(
+    canvas: &mut WindowCanvas,
+    components: &SimulatorComponents,
+    settings: &Settings,
+    prerendered_textures: &HashMap<String, Texture>,
+    kymograph: &Kymograph
+) {
+    let (cell_width, width, height) = kymograph_panel_size(settings, kymograph);
+    let x = (2 * BUFFER + settings.margin + settings.n_cols as u32 * settings.cell_size) as i32;
+    let y = (2 * settings.margin + prerendered_textures["legend"].query().height) as i32;
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.draw_rect(
+        Rect::new(x - BORDER_WIDTH as i32, y - BORDER_WIDTH as i32, width + 2 * BORDER_WIDTH, height + 2 * BORDER_WIDTH)
+    ).unwrap_or_else(|err| println!("Failed to draw kymograph: {err}"));
+
+    let n_shown = (height / KYMOGRAPH_ROW_HEIGHT) as usize;
+    let first = kymograph.samples.len().saturating_sub(n_shown);
+    for (row_idx, (_, row)) in kymograph.samples[first..].iter().enumerate() {
+        let mut cell_idx = 0;
+        for (run_length, state) in row.iter().dedup_with_count() {
+            canvas.set_draw_color(state_color(components, *state));
+            canvas.fill_rect(Rect::new(
+                x + (cell_idx as u32 * cell_width) as i32,
+                y + (row_idx as u32 * KYMOGRAPH_ROW_HEIGHT) as i32,
+                run_length as u32 * cell_width,
+                KYMOGRAPH_ROW_HEIGHT
+            )).ok();
+            cell_idx += run_length;
+        }
+    }
+}
+
+/// The board cell under a point in the window, if there is one.
+pub fn cell_at(components: &SimulatorComponents, settings: &Settings, x: i32, y: i32) -> Option<usize> {
+    let (col, row) = ((x as f32 - components.positions[0].x) / settings.cell_size as f32, (y as f32 - components.positions[0].y) / settings.cell_size as f32);
+    if col < 0.0 || row < 0.0 || col >= settings.n_cols as f32 || row >= settings.n_rows as f32 {
+        return None;
+    }
+    Some(row as usize * settings.n_cols + col as usize)
+}
+
+/// How much taller the window gets to show the waveforms of `n_probes` probes.
+pub fn waveform_panel_height(settings: &Settings, n_probes: usize) -> u32 {
+    match n_probes {
+        0 => 0,
+        n => n as u32 * (WAVEFORM_ROW_HEIGHT + BUFFER) + settings.margin
+    }
+}
 
-    canvas.present();
-}
\ No newline at end of file
+/// Draws each probe's levels over the whole simulated history, as wide as
+/// the playbar, in a panel starting `top` pixels down, with the probe's name
+/// and level on screen beside it and a line at the time on screen. High is
+/// drawn up, low down, and anything else as a gray band.
+pub fn draw_waveforms(
+    canvas: &mut WindowCanvas,
+    components: &SimulatorComponents,
+    state: &SimulatorState,
+    settings: &Settings,
+    font: &Font,
+    recorder: &ProbeRecorder,
+    top: u32
+) {
+    let texture_creator = canvas.texture_creator();
+    let (left, width) = (components.positions[0].x, (settings.cell_size * settings.n_cols as u32) as f32);
+    let t_max = components.reaction_history.last().map_or(0.0, |event| event.t).max(state.current_t).max(f32::EPSILON);
+    let x_of = |t: f32| (left + t / t_max * width) as i32;
+    for (probe_idx, probe) in recorder.probes.iter().enumerate() {
+        let row_top = (top + probe_idx as u32 * (WAVEFORM_ROW_HEIGHT + BUFFER)) as i32;
+        let (high_y, low_y) = (row_top + 2, row_top + WAVEFORM_ROW_HEIGHT as i32 - 3);
+        for (change_idx, (_, t, level)) in probe.changes.iter().enumerate() {
+            let (from, to) = (x_of(*t), x_of(probe.changes.get(change_idx + 1).map_or(t_max, |(_, next_t, _)| *next_t)));
+            match level {
+                Level::Unknown => {
+                    canvas.set_draw_color(WAVEFORM_UNKNOWN_COLOR);
+                    canvas.fill_rect(Rect::new(from, high_y, (to - from).max(1) as u32, (low_y - high_y) as u32)).ok();
+                },
+                Level::High | Level::Low => {
+                    let y = if *level == Level::High { high_y } else { low_y };
+                    canvas.set_draw_color(WAVEFORM_COLOR);
+                    canvas.draw_line(Point::new(from, y), Point::new(to, y)).ok();
+                }
+            }
+            if change_idx > 0 {
+                canvas.set_draw_color(WAVEFORM_COLOR);
+                canvas.draw_line(Point::new(from, high_y), Point::new(from, low_y)).ok();
+            }
+        }
+
+        let label = format!("{} = {}", probe.name, probe.level_at(state.next_rxn_event).vcd_char());
+        if let Ok(texture) = font.render(&label).blended(LEGEND_TEXT_COLOR).map_err(|err| err.to_string())
+            .and_then(|surface| texture_creator.create_texture_from_surface(&surface).map_err(|err| err.to_string()))
+        {
+            let TextureQuery { width: label_width, height: label_height, .. } = texture.query();
+            let label_x = (2 * BUFFER + settings.margin) as i32 + width as i32;
+            canvas.copy(&texture, None, Rect::new(label_x, row_top, label_width, label_height)).ok();
+        }
+    }
+    canvas.set_draw_color(Color::RGB(255, 0, 0));
+    let bottom = top as i32 + (recorder.probes.len() as u32 * (WAVEFORM_ROW_HEIGHT + BUFFER)) as i32;
+    canvas.draw_line(Point::new(x_of(state.current_t), top as i32), Point::new(x_of(state.current_t), bottom)).ok();
+}
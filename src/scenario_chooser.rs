@@ -0,0 +1,92 @@
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::ttf::Font;
+use sdl2::{Sdl, VideoSubsystem};
+
+use crate::renderer::BACKGROUND_COLOR;
+
+const MARGIN: u32 = 20;
+const BUFFER: u32 = 8;
+const MIN_WIDTH: u32 = 300;
+const HIGHLIGHT_COLOR: Color = Color::RGB(170, 170, 200);
+
+/// Opens a small window listing the manifest's scenarios and waits for the
+/// user to click one (or press its number). Returns None if the window is
+/// closed without choosing.
+pub fn choose_scenario(sdl_context: &Sdl, video_subsystem: &VideoSubsystem, font: &Font, names: &[&str]) -> Option<String> {
+    let title = "Choose a scenario:";
+    let row_height = font.height() as u32 + BUFFER;
+    let text_width = names
+        .iter()
+        .chain(std::iter::once(&title))
+        .map(|name| font.size_of(name).unwrap().0)
+        .max()
+        .unwrap_or(0);
+    let width = MIN_WIDTH.max(text_width + 2 * MARGIN);
+    let height = 2 * MARGIN + row_height * (names.len() as u32 + 1);
+
+    let window = video_subsystem.window("Chitin", width, height)
+        .position_centered()
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().build().expect("could not make a canvas");
+    let texture_creator = canvas.texture_creator();
+    let mut event_pump = sdl_context.event_pump().unwrap();
+
+    let row_at = |y: i32| -> Option<usize> {
+        let top = (MARGIN + row_height) as i32;
+        if y < top {
+            return None;
+        }
+        let row = ((y - top) as u32 / row_height) as usize;
+        (row < names.len()).then_some(row)
+    };
+    let mut hovered_row: Option<usize> = None;
+
+    loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit {..} | Event::KeyDown {keycode: Some(Keycode::Escape), ..} => {
+                    return None;
+                },
+                Event::MouseMotion {y, ..} => {
+                    hovered_row = row_at(y);
+                },
+                Event::MouseButtonUp {y, ..} => {
+                    if let Some(row) = row_at(y) {
+                        return Some(names[row].to_string());
+                    }
+                },
+                Event::KeyDown {keycode: Some(keycode), ..} => {
+                    let number = (keycode as i32) - (Keycode::Num1 as i32);
+                    if (0..names.len() as i32).contains(&number) {
+                        return Some(names[number as usize].to_string());
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        canvas.set_draw_color(BACKGROUND_COLOR);
+        canvas.clear();
+        let rows = std::iter::once(title.to_string())
+            .chain(names.iter().enumerate().map(|(i, name)| format!("{}. {name}", i + 1)));
+        for (i, text) in rows.enumerate() {
+            let y = (MARGIN + i as u32 * row_height) as i32;
+            if i > 0 && hovered_row == Some(i - 1) {
+                canvas.set_draw_color(HIGHLIGHT_COLOR);
+                canvas.fill_rect(Rect::new(0, y - (BUFFER / 2) as i32, width, row_height)).ok();
+            }
+            let surface = font
+                .render(&text)
+                .blended(Color::RGBA(0, 0, 0, 255))
+                .unwrap();
+            let texture = texture_creator.create_texture_from_surface(&surface).unwrap();
+            canvas.copy(&texture, None, Rect::new(MARGIN as i32, y, surface.width(), surface.height())).ok();
+        }
+        canvas.present();
+        std::thread::sleep(std::time::Duration::from_millis(16));
+    }
+}
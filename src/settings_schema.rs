@@ -0,0 +1,294 @@
+use std::str::FromStr;
+
+use crate::state::{Settings, SurfaceGeometry};
+
+/// One setting that can be given in a manifest as `name = value`.
+pub struct SettingSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub value_type: &'static str,
+    pub default: &'static str,
+    pub description: &'static str,
+    pub apply: fn(&mut Settings, &str) -> Result<(), String>,
+    pub format: fn(&Settings) -> String,
+}
+
+/// Every setting chitin understands. Aliases cover the names used by the
+/// original SurfaceCRN tooling, so its manifests load unchanged.
+pub const SETTINGS_SCHEMA: &[SettingSpec] = &[
+    SettingSpec {
+        name: "pixels_per_node",
+        aliases: &[],
+        value_type: "positive integer",
+        default: "5",
+        description: "Width and height of each cell on screen, in pixels.",
+        apply: |settings, value| {
+            settings.cell_size = parse_number(value)?;
+            if settings.cell_size == 0 {
+                return Err("must be at least 1".to_string());
+            }
+            Ok(())
+        },
+        format: |settings| settings.cell_size.to_string(),
+    },
+    SettingSpec {
+        name: "fps",
+        aliases: &[],
+        value_type: "number",
+        default: "60",
+        description: "Frames drawn per second of wall-clock time.",
+        apply: |settings, value| {
+            settings.fps = parse_number(value)?;
+            if settings.fps <= 0.0 {
+                return Err("must be greater than 0".to_string());
+            }
+            Ok(())
+        },
+        format: |settings| settings.fps.to_string(),
+    },
+    SettingSpec {
+        name: "speedup_factor",
+        aliases: &[],
+        value_type: "number",
+        default: "1",
+        description: "Simulated time that passes per second of wall-clock time.",
+        apply: |settings, value| {
+            settings.speedup_factor = parse_number(value)?;
+            Ok(())
+        },
+        format: |settings| settings.speedup_factor.to_string(),
+    },
+    SettingSpec {
+        name: "wrap",
+        aliases: &["wrap_grid"],
+        value_type: "boolean",
+        default: "false",
+        description: "Whether the edges of the surface wrap around to the opposite side.",
+        apply: |settings, value| {
+            settings.wrap = parse_bool(value)?;
+            Ok(())
+        },
+        format: |settings| settings.wrap.to_string(),
+    },
+    SettingSpec {
+        name: "debug",
+        aliases: &[],
+        value_type: "boolean",
+        default: "false",
+        description: "Turns on extra diagnostic output.",
+        apply: |settings, value| {
+            settings.debug = parse_bool(value)?;
+            Ok(())
+        },
+        format: |settings| settings.debug.to_string(),
+    },
+    SettingSpec {
+        name: "rng_seed",
+        aliases: &[],
+        value_type: "integer or \"none\"",
+        default: "none",
+        description: "Seed for the random number generator; \"none\" picks a fresh seed every run.",
+        apply: |settings, value| {
+            settings.rng_seed = match value.to_lowercase().as_str() {
+                "none" => None,
+                _ => Some(parse_number(value)?)
+            };
+            Ok(())
+        },
+        format: |settings| match settings.rng_seed {
+            Some(seed) => seed.to_string(),
+            None => "none".to_string()
+        },
+    },
+    SettingSpec {
+        name: "max_duration",
+        aliases: &[],
+        value_type: "number",
+        default: "1000000",
+        description: "Simulated time at which playback stops.",
+        apply: |settings, value| {
+            settings.max_duration = parse_number(value)?;
+            Ok(())
+        },
+        format: |settings| settings.max_duration.to_string(),
+    },
+    SettingSpec {
+        name: "node_display",
+        aliases: &["display_text", "node_text"],
+        value_type: "\"color\" or \"text\"",
+        default: "color",
+        description: "Whether cells are drawn as colored squares or labeled with their state names.",
+        apply: |settings, value| {
+            settings.display_text = match value.to_lowercase().as_str() {
+                "text" => true,
+                "color" => false,
+                _ => parse_bool(value).map_err(|_| "expected \"color\" or \"text\"".to_string())?
+            };
+            Ok(())
+        },
+        format: |settings| if settings.display_text { "text" } else { "color" }.to_string(),
+    },
+    SettingSpec {
+        name: "geometry",
+        aliases: &["surface_geometry"],
+        value_type: "\"square\" or \"hex\"",
+        default: "square",
+        description: "Shape of the lattice the surface is laid out on.",
+        apply: |settings, value| {
+            settings.surface_geometry = match value.to_lowercase().as_str() {
+                "hex" | "hexagonal" | "hexagons" | "honeycomb" => SurfaceGeometry::Hex,
+                "square" | "box" | "grid" => SurfaceGeometry::Square,
+                _ => return Err("expected \"square\" or \"hex\"".to_string())
+            };
+            Ok(())
+        },
+        format: |settings| match settings.surface_geometry {
+            SurfaceGeometry::Square => "square",
+            SurfaceGeometry::Hex => "hex"
+        }.to_string(),
+    },
+    SettingSpec {
+        name: "frame_capture_rate",
+        aliases: &[],
+        value_type: "number",
+        default: "0",
+        description: "Accepted for compatibility with SurfaceCRN manifests; chitin doesn't capture frames.",
+        apply: |settings, value| {
+            settings.frame_capture_rate = parse_number(value)?;
+            Ok(())
+        },
+        format: |settings| settings.frame_capture_rate.to_string(),
+    },
+];
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "on" | "yes" | "1" => Ok(true),
+        "false" | "off" | "no" | "0" => Ok(false),
+        _ => Err("expected true or false".to_string())
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| "expected a number".to_string())
+}
+
+/// Finds the setting a manifest key refers to, by name or by alias.
+pub fn find_setting(key: &str) -> Option<&'static SettingSpec> {
+    let key = key.to_lowercase();
+    SETTINGS_SCHEMA
+        .iter()
+        .find(|spec| spec.name == key || spec.aliases.contains(&key.as_str()))
+}
+
+/// Settings with every schema default applied and an empty surface.
+pub fn default_settings() -> Settings {
+    let mut settings = Settings {
+        n_rows: 0,
+        n_cols: 0,
+        cell_size: 0,
+        margin: 60,
+        fps: 0.0,
+        speedup_factor: 0.0,
+        wrap: false,
+        debug: false,
+        rng_seed: None,
+        max_duration: 0.0,
+        frame_capture_rate: 0.0,
+        display_text: false,
+        surface_geometry: SurfaceGeometry::Square
+    };
+    for spec in SETTINGS_SCHEMA {
+        (spec.apply)(&mut settings, spec.default).unwrap();
+    }
+    settings
+}
+
+/// Turns the `name = value` lines of a manifest (in the order they were
+/// written) into Settings. Returns the settings along with warnings about
+/// unknown or repeated keys, or an error if a value doesn't fit its setting.
+pub fn parse_settings(variables: &[(String, String)]) -> Result<(Settings, Vec<String>), String> {
+    let mut settings = default_settings();
+    let mut warnings = Vec::new();
+    let mut keys_seen: Vec<(&str, &str)> = Vec::new(); // (setting name, key as written)
+    for (key, value) in variables {
+        let Some(spec) = find_setting(key) else {
+            warnings.push(format!("unknown setting {key:?} will be ignored"));
+            continue;
+        };
+        if let Some((_, earlier_key)) = keys_seen.iter().find(|(name, _)| *name == spec.name) {
+            warnings.push(format!("setting {key:?} overrides the earlier {earlier_key:?}"));
+        }
+        keys_seen.push((spec.name, key));
+        (spec.apply)(&mut settings, value)
+            .map_err(|err| format!("invalid value {value:?} for setting {key:?}: {err}"))?;
+    }
+    Ok((settings, warnings))
+}
+
+/// A human-readable table of every setting, for `chitin settings`.
+pub fn settings_documentation() -> String {
+    let mut doc = String::from("Settings are written in a manifest as `name = value`, one per line.\n");
+    for spec in SETTINGS_SCHEMA {
+        doc.push('\n');
+        doc.push_str(spec.name);
+        if !spec.aliases.is_empty() {
+            doc.push_str(&format!(" (also: {})", spec.aliases.join(", ")));
+        }
+        doc.push_str(&format!("\n    {}\n    type: {}, default: {}\n", spec.description, spec.value_type, spec.default));
+    }
+    doc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::matches;
+
+    use crate::state::SurfaceGeometry;
+
+    use super::{parse_settings, SETTINGS_SCHEMA};
+
+    fn variables(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_surfacecrn_aliases() {
+        let (settings, warnings) = parse_settings(&variables(&[
+            ("geometry", "hex"),
+            ("wrap", "True"),
+            ("node_display", "Text"),
+            ("debug", "False"),
+            ("frame_capture_rate", "2"),
+            ("speedup_factor", "0.5"),
+        ])).unwrap();
+        assert!(warnings.is_empty());
+        assert!(matches!(settings.surface_geometry, SurfaceGeometry::Hex));
+        assert!(settings.wrap);
+        assert!(settings.display_text);
+        assert!(!settings.debug);
+        assert_eq!(settings.speedup_factor, 0.5);
+    }
+
+    #[test]
+    fn test_setting_warnings_and_errors() {
+        let (settings, warnings) = parse_settings(&variables(&[
+            ("wrap", "true"),
+            ("wrap_grid", "false"),
+            ("colour", "red"),
+        ])).unwrap();
+        assert!(!settings.wrap);
+        assert_eq!(warnings.len(), 2);
+
+        assert!(parse_settings(&variables(&[("pixels_per_node", "big")])).is_err());
+        assert!(parse_settings(&variables(&[("geometry", "triangle")])).is_err());
+    }
+
+    #[test]
+    fn test_schema_defaults_parse() {
+        for spec in SETTINGS_SCHEMA {
+            assert!(parse_settings(&variables(&[(spec.name, spec.default)])).is_ok(), "{}", spec.name);
+        }
+    }
+}
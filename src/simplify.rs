@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::analysis::reachable_states;
+use crate::input::read_manifest_file;
+use crate::input_parsers::ParsedManifest;
+use crate::manifest_lexer::quote_name;
+use crate::reactions::ReactionDescription;
+
+/// A rule's reactants and products, written the way round that sorts first:
+/// `A + B -> C + D` and `B + A -> D + C` fire on the same pairs of cells, so
+/// they're the same rule.
+type RuleKey = (String, Option<String>, String, Option<String>);
+
+fn rule_key(r1: &str, r2: Option<&str>, p1: &str, p2: Option<&str>) -> RuleKey {
+    match (r2, p2) {
+        (Some(r2), Some(p2)) if (r2, p2) < (r1, p1) => (r2.to_string(), Some(r1.to_string()), p2.to_string(), Some(p1.to_string())),
+        _ => (r1.to_string(), r2.map(str::to_string), p1.to_string(), p2.map(str::to_string)),
+    }
+}
+
+/// One way a state can change, from its own side: (the other reactant, if
+/// any, the group this state becomes, the group the other reactant becomes).
+/// The other reactant is named exactly: merged states have to react the same
+/// way with each state, not just with each group, or a pair of cells could
+/// react before merging and not after.
+type Transition<'a> = (Option<&'a str>, usize, Option<usize>);
+
+/// Collects rules into one per key, summing the rates of duplicates and
+/// keeping the order each key first shows up in.
+fn merge_duplicates(rules: impl IntoIterator<Item = (RuleKey, f64)>) -> Vec<(RuleKey, f64)> {
+    let mut merged: Vec<(RuleKey, f64)> = Vec::new();
+    let mut positions: HashMap<RuleKey, usize> = HashMap::new();
+    for (key, rate) in rules {
+        match positions.get(&key) {
+            Some(idx) => merged[*idx].1 += rate,
+            None => {
+                positions.insert(key.clone(), merged.len());
+                merged.push((key, rate));
+            }
+        }
+    }
+    merged
+}
+
+/// A smaller rule set that behaves the same as a manifest's.
+#[derive(Debug)]
+pub struct Simplification {
+    pub rules: Vec<ReactionDescription>,
+    pub renames: BTreeMap<String, String>, // Merged state -> the state it was merged into.
+    pub dead_rules: Vec<usize>,            // Rules that can never fire.
+    pub n_duplicates: usize,               // Rules folded into an identical or mirrored one.
+}
+
+/// Simplifies a manifest's rules (with its scenario already applied) in three
+/// steps. Rules that can never fire, because a reactant never appears or the
+/// rate is zero, are dropped. Duplicate and mirrored rules are folded into
+/// one, with their rates summed. Then states that behave the same way are
+/// merged: two states in the same color class are merged if every rule for
+/// one has a matching rule for the other, with the same other reactant, the
+/// same rate, and products that are merged too. The merged rules are written
+/// with each group's alphabetically first state.
+pub fn simplify_rules(manifest: &ParsedManifest) -> Result<Simplification, String> {
+    let mut initial: Vec<String> = Vec::new();
+    for source in manifest.init_states.iter() {
+        initial.extend(source.expand(manifest)?.states);
+    }
+    let rules = &manifest.transition_rules;
+    let reachable = reachable_states(initial.iter().map(|s| &s[..]), rules);
+    let (live, dead): (Vec<_>, Vec<_>) = rules
+        .iter()
+        .enumerate()
+        .partition(|(_, rule)| rule.rate != 0.0 && rule.all_states().iter().all(|state| reachable.contains(state)));
+
+    let unique_rules = merge_duplicates(live.iter().map(|(_, rule)| {
+        (rule_key(&rule.r1, rule.r2.as_deref(), &rule.p1, rule.p2.as_deref()), rule.rate as f64)
+    }));
+    let n_duplicates = live.len() - unique_rules.len();
+
+    // Start with one group per color class (states outside the colormap are
+    // all shown in random colors, so they start in one group), then keep
+    // splitting groups until every state in a group has the same rules.
+    let state_class: HashMap<&str, &str> = manifest.color_classes
+        .iter()
+        .flat_map(|(class_name, _, states)| states.iter().map(move |state| (&state[..], &class_name[..])))
+        .collect();
+    let mut states: Vec<&str> = reachable.iter().copied().chain(initial.iter().map(|s| &s[..])).collect();
+    states.sort();
+    states.dedup();
+    let mut group = number_groups(&states, |state| state_class.get(state).copied());
+    loop {
+        let mut signatures: HashMap<&str, BTreeMap<Transition, f64>> = HashMap::new();
+        for ((r1, r2, p1, p2), rate) in unique_rules.iter() {
+            match (r2, p2) {
+                (Some(r2), Some(p2)) => {
+                    *signatures.entry(&r1[..]).or_default().entry((Some(&r2[..]), group[&p1[..]], Some(group[&p2[..]]))).or_default() += rate;
+                    *signatures.entry(&r2[..]).or_default().entry((Some(&r1[..]), group[&p2[..]], Some(group[&p1[..]]))).or_default() += rate;
+                },
+                _ => *signatures.entry(&r1[..]).or_default().entry((None, group[&p1[..]], None)).or_default() += rate,
+            }
+        }
+        let refined = number_groups(&states, |state| {
+            // Rates are compared to six decimal places, so summing them in a
+            // different order doesn't split a group.
+            let signature: Vec<_> = signatures
+                .get(state)
+                .map(|signature| signature.iter().map(|(entry, rate)| (*entry, (rate * 1e6).round() as i64)).collect())
+                .unwrap_or_default();
+            (group[state], signature)
+        });
+        let n_groups = |groups: &HashMap<&str, usize>| groups.values().collect::<HashSet<_>>().len();
+        let done = n_groups(&refined) == n_groups(&group);
+        group = refined;
+        if done {
+            break;
+        }
+    }
+
+    let mut representative: HashMap<usize, &str> = HashMap::new();
+    for state in states.iter() {
+        representative.entry(group[state]).or_insert(state); // States are sorted, so this is the first.
+    }
+    let rename = |state: &str| representative[&group[state]].to_string();
+    let renames: BTreeMap<String, String> = states
+        .iter()
+        .filter(|state| rename(state) != **state)
+        .map(|state| (state.to_string(), rename(state)))
+        .collect();
+
+    // Every member of a group has the same rules, so the representatives'
+    // rules stand for all of them.
+    let merged_rules = merge_duplicates(
+        unique_rules
+            .iter()
+            .filter(|((r1, r2, _, _), _)| !renames.contains_key(r1) && r2.as_ref().is_none_or(|r2| !renames.contains_key(r2)))
+            .map(|((r1, r2, p1, p2), rate)| {
+                let r2 = r2.as_deref().map(rename);
+                let p2 = p2.as_deref().map(rename);
+                (rule_key(&rename(r1), r2.as_deref(), &rename(p1), p2.as_deref()), *rate)
+            })
+    );
+    let rules = merged_rules
+        .into_iter()
+        .map(|((r1, r2, p1, p2), rate)| ReactionDescription { r1, r2, p1, p2, rate: rate as f32 })
+        .collect();
+
+    Ok(Simplification {
+        rules,
+        renames,
+        dead_rules: dead.iter().map(|(rule_idx, _)| *rule_idx).collect(),
+        n_duplicates,
+    })
+}
+
+/// Numbers states by a key, giving states with equal keys the same number.
+fn number_groups<'a, K: Ord>(states: &[&'a str], key: impl Fn(&'a str) -> K) -> HashMap<&'a str, usize> {
+    let keys: BTreeMap<K, Vec<&str>> = states.iter().fold(BTreeMap::new(), |mut keys, state| {
+        keys.entry(key(state)).or_insert_with(Vec::new).push(*state);
+        keys
+    });
+    keys.into_values()
+        .enumerate()
+        .flat_map(|(group_id, members)| members.into_iter().map(move |state| (state, group_id)))
+        .collect()
+}
+
+/// Writes a simplified rule block, with the state renaming as comments above
+/// it, ready to `!INCLUDE` in place of the original rules.
+pub fn format_simplification(simplification: &Simplification) -> String {
+    let mut text = String::new();
+    if !simplification.renames.is_empty() {
+        text.push_str("# Merged states (rename these in the colormap and initial state):\n");
+        for (state, merged_into) in simplification.renames.iter() {
+            text.push_str(&format!("#     {} -> {}\n", quote_name(state), quote_name(merged_into)));
+        }
+    }
+    text.push_str("!START_TRANSITION_RULES\n");
+    for rule in simplification.rules.iter() {
+        match (&rule.r2, &rule.p2) {
+            (Some(r2), Some(p2)) => text.push_str(&format!(
+                "{} + {} -> {} + {} ({})\n", quote_name(&rule.r1), quote_name(r2), quote_name(&rule.p1), quote_name(p2), rule.rate
+            )),
+            _ => text.push_str(&format!("{} -> {} ({})\n", quote_name(&rule.r1), quote_name(&rule.p1), rule.rate)),
+        }
+    }
+    text.push_str("!END_TRANSITION_RULES\n");
+    text
+}
+
+/// Runs `chitin simplify`: writes a manifest's simplified rules to a file or
+/// to stdout, with a summary of what changed.
+pub fn simplify_manifest_file(
+    input_file: PathBuf,
+    output_file: Option<PathBuf>,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let simplification = match read_manifest_file(input_file.clone(), search_path)
+        .and_then(|manifest| manifest.with_scenario(scenario))
+        .and_then(|manifest| Ok((simplify_rules(&manifest)?, manifest.transition_rules.len())))
+    {
+        Ok(simplification) => simplification,
+        Err(err) => {
+            eprintln!("error: couldn't simplify {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let (simplification, n_rules) = simplification;
+    let summary = format!(
+        "{n_rules} rules -> {} rules: dropped {} dead rule(s), folded {} duplicate(s), merged {} state(s).",
+        simplification.rules.len(),
+        simplification.dead_rules.len(),
+        simplification.n_duplicates,
+        simplification.renames.len()
+    );
+    let text = format_simplification(&simplification);
+    match output_file {
+        Some(path) => match fs::write(&path, text) {
+            Ok(()) => {
+                println!("{summary}");
+                0
+            },
+            Err(err) => {
+                eprintln!("Couldn't write {path:?}: {err}");
+                1
+            }
+        },
+        None => {
+            // Keep stdout usable as a rule file.
+            eprintln!("{summary}");
+            print!("{text}");
+            0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+    use crate::manifest_lexer::normalize_manifest_text;
+
+    use super::{format_simplification, simplify_rules};
+
+    const MANIFEST: &str = "\
+!START_COLORMAP
+{Walkers} A, A2: (255,0,0)
+B: (0,0,255)
+E: (0,0,0)
+!END_COLORMAP
+!START_TRANSITION_RULES
+A + E -> E + A (1)
+E + A -> A + E (1) # the same rule, mirrored
+A2 + E -> E + A2 (2)
+B + E -> E + B (2)
+Z -> A (1) # Z never shows up
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A E A2 E B
+!END_INIT_STATE";
+
+    #[test]
+    fn test_simplify() {
+        let manifest = settings_input::manifest(&normalize_manifest_text(MANIFEST).unwrap()).unwrap();
+        let simplification = simplify_rules(&manifest).unwrap();
+        assert_eq!(simplification.dead_rules, vec![4]);
+        assert_eq!(simplification.n_duplicates, 1);
+        // B moves the same way, but it's shown in a different color.
+        assert_eq!(simplification.renames.len(), 1);
+        assert_eq!(simplification.renames["A2"], "A");
+
+        let text = format_simplification(&simplification);
+        assert!(text.contains("#     A2 -> A\n"), "{text}");
+        assert!(text.contains("A + E -> E + A (2)\nB + E -> E + B (2)\n!END"), "{text}");
+    }
+}
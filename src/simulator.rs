@@ -1,4 +1,5 @@
-use rand::random;
+use rand::Rng;
+use rand::rngs::StdRng;
 
 use crate::{state::{SimulatorState, SimulatorComponents, Settings}, reactions::{ReactionEvent, Reaction}};
 
@@ -6,13 +7,14 @@ use crate::{state::{SimulatorState, SimulatorComponents, Settings}, reactions::{
 /// its clock should start at the last-simulated event in the reaction history.
 pub fn compute_next_t(
     components: &SimulatorComponents,
+    rng: &mut StdRng,
     rxn_idx: usize
 ) -> f32 {
     let t = match components.reaction_history.last() {
         Some(event) => event.t,
         None => 0.0
     };
-    t + (1.0f32 / random::<f32>()).ln() / components.all_rxn_rates[rxn_idx]
+    t + (1.0f32 / rng.gen::<f32>()).ln() / components.all_rxn_rates[rxn_idx]
 }
 
 /// Iterates through the indexes of the (square) neighbors of one position idx.
@@ -129,9 +131,10 @@ pub fn extend_reaction_history(components: &mut SimulatorComponents, global_stat
     
     // Check if (either of) the changed state(s) can react, and if so add those reactions
     // to the queue.
-    check_for_new_reactions_at(next_event.r1_loc, components, global_state, settings, true);
+    check_for_new_reactions_at(next_event.r1_loc, components, global_state, settings, true, None);
     if next_event.r2_loc.is_some() {
-        check_for_new_reactions_at(next_event.r2_loc.unwrap(), components, global_state, settings, true);
+        // Pairs of the two changed positions were just scheduled from the first.
+        check_for_new_reactions_at(next_event.r2_loc.unwrap(), components, global_state, settings, true, Some(next_event.r1_loc));
     }
 }
 
@@ -146,12 +149,14 @@ pub fn extend_reaction_history_threaded() {
 /// symmetric=true (what we usually want) means that the position can be either 
 /// reactant; otherwise, only the first state can react (useful for setting up the 
 /// initial reaction queue at the beginning of the simulation).
+/// Pairs with the `skip` position, if any, are left out.
 fn check_for_new_reactions_at(
     idx: usize,
     components: &SimulatorComponents,
     global_state: &mut SimulatorState,
     settings: &Settings,
-    symmetric: bool
+    symmetric: bool,
+    skip: Option<usize>
 ) {
     for rxn_idx in 0..components.all_reactions.len() {
         let rxn = &(components.all_reactions[rxn_idx]);
@@ -166,11 +171,25 @@ fn check_for_new_reactions_at(
                     );
                     for neighbor_idx in neighbors {
                         let neighbor_state = components.latest_states[neighbor_idx];
+                        // Two cells in the same state would each schedule the
+                        // pair when setting up the queue, so only the first
+                        // one does. Later on, only the cell that changed
+                        // schedules the pair. Either way, which cell is the
+                        // first reactant (and gets the first product) is
+                        // picked at random.
+                        let same_states = rxn.r1_num == r2;
+                        if Some(neighbor_idx) == skip || (!symmetric && same_states && neighbor_idx < idx) {
+                            continue;
+                        }
                         if neighbor_state == r2 {
-                            let next_t = compute_next_t(components, rxn_idx);
+                            let (r1_loc, r2_loc) = match same_states && global_state.rng.gen::<bool>() {
+                                true => (neighbor_idx, idx),
+                                false => (idx, neighbor_idx)
+                            };
+                            let next_t = compute_next_t(components, &mut global_state.rng, rxn_idx);
                             let new_event = ReactionEvent{
-                                r1_loc: idx,
-                                r2_loc: Some(neighbor_idx),
+                                r1_loc,
+                                r2_loc: Some(r2_loc),
                                 rxn_idx: rxn_idx,
                                 t: next_t,
                                 t_issued: match components.reaction_history.last() {
@@ -184,7 +203,7 @@ fn check_for_new_reactions_at(
                     }
                 },
                 None => {
-                    let next_t = compute_next_t(components, rxn_idx);
+                    let next_t = compute_next_t(components, &mut global_state.rng, rxn_idx);
                     let new_event = ReactionEvent{
                         r1_loc: idx,
                         r2_loc: None,
@@ -209,8 +228,11 @@ fn check_for_new_reactions_at(
             );
             for neighbor_idx in neighbors {
                 let neighbor_state = components.latest_states[neighbor_idx];
+                if Some(neighbor_idx) == skip {
+                    continue;
+                }
                 if neighbor_state == rxn.r1_num {
-                    let next_t = compute_next_t(components, rxn_idx);
+                    let next_t = compute_next_t(components, &mut global_state.rng, rxn_idx);
                     let new_event = ReactionEvent{
                         r1_loc: neighbor_idx,
                         r2_loc: Some(idx),
@@ -235,7 +257,7 @@ pub fn initialize_queue(
     settings: &Settings
 ) {
     for idx in 0..components.current_states.len() {
-        check_for_new_reactions_at(idx, components, global_state, settings, false);
+        check_for_new_reactions_at(idx, components, global_state, settings, false, None);
     }
 }
 
@@ -267,7 +289,7 @@ pub fn initialize_queue(
 //                             if (rxn.r1_idx == r2) && (neighbor_idx < next_event.r1_idx) {
 //                                 continue;
 //                             }
-//                             let next_t = compute_next_t(components, rxn_idx);
+//                             let next_t = compute_next_t(components, &mut global_state.rng, rxn_idx);
 //                             let new_event = ReactionEvent{
 //                                 r1_idx: self_state,
 //                                 r2_idx: Some(neighbor_state),
@@ -283,7 +305,7 @@ pub fn initialize_queue(
 //                     }
 //                 },
 //                 None => {
-//                     let next_t = compute_next_t(components, rxn_idx);
+//                     let next_t = compute_next_t(components, &mut global_state.rng, rxn_idx);
 //                     let new_event = ReactionEvent{
 //                         r1_idx: self_state,
 //                         r2_idx: None,
@@ -313,9 +335,15 @@ pub fn tick(global_state: &mut SimulatorState, components: &mut SimulatorCompone
             global_state.current_t = settings.max_duration;
             global_state.is_playing = false;
         }
-        while global_state.next_rxn_event >= components.reaction_history.len() {
+        if global_state.next_rxn_event >= components.reaction_history.len() {
             extend_reaction_history(components, global_state, settings);
         }
+        // Nothing more can happen: the board is stuck, or a loaded trace is over.
+        if global_state.next_rxn_event >= components.reaction_history.len() {
+            global_state.is_playing = false;
+            global_state.tick = false;
+            return;
+        }
         let mut next_event = components.reaction_history[global_state.next_rxn_event];
         while next_event.t <= global_state.current_t && next_event.t <= settings.max_duration {
             apply_reaction(&next_event, global_state, components, settings, true);
@@ -324,6 +352,9 @@ pub fn tick(global_state: &mut SimulatorState, components: &mut SimulatorCompone
             if global_state.next_rxn_event == components.reaction_history.len() {
                 extend_reaction_history(components, global_state, settings);
             }
+            if global_state.next_rxn_event == components.reaction_history.len() {
+                break;
+            }
             next_event = components.reaction_history[global_state.next_rxn_event];
         }
     } else {
@@ -356,7 +387,10 @@ pub fn tick(global_state: &mut SimulatorState, components: &mut SimulatorCompone
 
 #[cfg(test)]
 mod tests {
-    use super::square_neighbors;
+    use crate::input_parsers::settings_input;
+    use crate::state::SimulatorState;
+
+    use super::{extend_reaction_history, initialize_queue, square_neighbors};
 
     fn coords_to_idx(x: usize, y: usize, width: usize) -> usize {
         x + y * width
@@ -422,4 +456,95 @@ mod tests {
         assert_vecs_equal!(square_neighbors(coords_to_idx(1, 9, 3), 3, 10, false),
                             [(0, 9), (2, 9), (1, 8)].map(|(x, y)| coords_to_idx(x, y, 3)));
     }
-}
\ No newline at end of file
+
+    /// The unordered pairs of cells the queue holds events for, for one
+    /// rule, and which cell of each is the first reactant.
+    fn scheduled_pairs(global_state: &mut SimulatorState, rxn_idx: usize) -> Vec<((usize, usize), usize)> {
+        let mut pairs: Vec<((usize, usize), usize)> = global_state.rxn_queue
+            .into_sorted_vec()
+            .into_iter()
+            .filter(|(_, event)| event.rxn_idx == rxn_idx)
+            .map(|(_, event)| {
+                let (r1_loc, r2_loc) = (event.r1_loc, event.r2_loc.unwrap());
+                ((r1_loc.min(r2_loc), r1_loc.max(r2_loc)), r1_loc)
+            })
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn test_same_state_pairs_scheduled_once() {
+        let manifest = "\
+!START_TRANSITION_RULES
+A + A -> B + C (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A A A
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        let mut first_reactants = Vec::new();
+        for seed in 0..20 {
+            let mut global_state = SimulatorState::new(Some(seed), 0);
+            initialize_queue(&components, &mut global_state, &settings);
+            let pairs = scheduled_pairs(&mut global_state, 0);
+            assert_eq!(pairs.iter().map(|(pair, _)| *pair).collect::<Vec<_>>(), [(0, 1), (1, 2)]);
+            first_reactants.push(pairs[0].1);
+        }
+        // Neither cell of a pair is always the first reactant.
+        assert!(first_reactants.contains(&0) && first_reactants.contains(&1));
+    }
+
+    #[test]
+    fn test_changed_pair_scheduled_once() {
+        // Once X + X fires, the two cells it changed are a new A + A pair,
+        // which only one of them schedules.
+        let manifest = "\
+!START_TRANSITION_RULES
+X + X -> A + A (1)
+A + A -> B + C (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+X X A
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        for seed in 0..10 {
+            let mut global_state = SimulatorState::new(Some(seed), 0);
+            let mut components = components.clone();
+            initialize_queue(&components, &mut global_state, &settings);
+            extend_reaction_history(&mut components, &mut global_state, &settings);
+            assert_eq!(components.reaction_history.len(), 1);
+            let pairs = scheduled_pairs(&mut global_state, 1);
+            assert_eq!(pairs.iter().map(|(pair, _)| *pair).collect::<Vec<_>>(), [(0, 1), (1, 2)]);
+        }
+    }
+
+    #[test]
+    fn test_changed_cell_product_placement() {
+        // The X turns into an A, which schedules the new A + A pair; it
+        // shouldn't always be the one that gets the B.
+        let manifest = "\
+!START_TRANSITION_RULES
+X -> A (1)
+A + A -> B + C (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+X A
+!END_INIT_STATE";
+        let (components, settings) = settings_input::settings(manifest).unwrap();
+        let b = components.state_ids["B"];
+        let mut n_b_in_changed_cell = 0;
+        for seed in 0..40 {
+            let mut global_state = SimulatorState::new(Some(seed), 0);
+            let mut components = components.clone();
+            initialize_queue(&components, &mut global_state, &settings);
+            extend_reaction_history(&mut components, &mut global_state, &settings);
+            extend_reaction_history(&mut components, &mut global_state, &settings);
+            assert_eq!(components.reaction_history.len(), 2);
+            if components.latest_states[0] == b {
+                n_b_in_changed_cell += 1;
+            }
+        }
+        assert!(n_b_in_changed_cell > 5 && n_b_in_changed_cell < 35, "{n_b_in_changed_cell}");
+    }
+}
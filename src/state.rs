@@ -1,4 +1,6 @@
 use rand::random;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 // use sdl2::video::WindowContext;
 use sdl2::{pixels::Color, rect::Rect};
 use priq::PriorityQueue;
@@ -9,7 +11,7 @@ use crate::reactions::{Reaction, ReactionDescription, ReactionEvent};
 use crate::button::ButtonID;
 // use crate::textures::TextureAtlas;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SimulatorComponents {
     pub sizes: Vec<Size>, // For graphics.
     pub positions: Vec<Position>, // For graphics.
@@ -23,6 +25,7 @@ pub struct SimulatorComponents {
     pub state_colorclasses: HashMap<usize, usize>,
     pub colorclass_names: Vec<String>,
     pub colorclass_colors: Vec<Color>,
+    pub implicit_colorclasses: HashSet<usize>, // Classes made up (with a random color) for states the colormap doesn't list.
     pub state_ids: HashMap<String, usize>,
     pub button_boxes: Vec<Rect>,
     pub button_ids: Vec<ButtonID>,
@@ -45,6 +48,7 @@ impl SimulatorComponents {
             state_colorclasses: HashMap::new(),
             colorclass_names: Vec::new(),
             colorclass_colors: Vec::new(),
+            implicit_colorclasses: HashSet::new(),
             state_ids: HashMap::new(),
             button_boxes: Vec::new(),
             button_ids: Vec::new(),
@@ -64,6 +68,7 @@ impl SimulatorComponents {
                 self.colorclass_names.push(name.to_string());
                 let new_color = self.new_random_color();
                 self.colorclass_colors.push(new_color);
+                self.implicit_colorclasses.insert(self.n_colorclasses);
                 self.n_colorclasses += 1;
                 self.n_colorclasses - 1
             }
@@ -139,8 +144,10 @@ impl SimulatorComponents {
                 row += 1;
             }
         }
-        println!("Board state after setting: {:?}", self.current_states);
-        println!("Dimensions (rows x cols) : ({:?}, {:?})", settings.n_rows, settings.n_cols);
+        if settings.debug {
+            println!("Board state after setting: {:?}", self.current_states);
+            println!("Dimensions (rows x cols) : ({:?}, {:?})", settings.n_rows, settings.n_cols);
+        }
     }
 
 }
@@ -155,7 +162,30 @@ pub struct SimulatorState {
     pub pressed_button_idx: usize,
     pub is_playing: bool,
     pub run_direction_forward: bool,
-    pub tick: bool
+    pub tick: bool,
+    pub rng: StdRng, // Draws every reaction time, so a seed replays a whole run.
+}
+
+impl SimulatorState {
+    /// A simulation that hasn't started yet, drawing its reaction times from a
+    /// generator seeded with `seed` (or a fresh seed, without one).
+    pub fn new(seed: Option<u64>, pressed_button_idx: usize) -> Self {
+        Self {
+            last_states: Vec::new(),
+            rxn_queue: PriorityQueue::new(),
+            speedup: 1.0,
+            current_t: 0.0,
+            next_rxn_event: 0,
+            pressed_button_idx,
+            is_playing: false,
+            run_direction_forward: true,
+            tick: false,
+            rng: match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy()
+            }
+        }
+    }
 }
 #[derive(Debug)]
 
@@ -176,17 +206,18 @@ pub struct Settings {
     pub debug: bool,
     pub rng_seed: Option<i32>,
     pub max_duration: f32,
+    pub frame_capture_rate: f32,
     pub display_text: bool,
     pub surface_geometry: SurfaceGeometry
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Position {
     pub x: f32,
     pub y: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Size {
     pub width: u32,
     pub height: u32,
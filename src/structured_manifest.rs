@@ -0,0 +1,507 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use sdl2::pixels::Color;
+use serde::{Deserialize, Serialize};
+
+use crate::agents::AgentRule;
+use crate::analysis::Invariant;
+use crate::init_states::{InitGrid, InitStateSource};
+use crate::input::{load_from_manifest, read_manifest_file};
+use crate::input_parsers::ParsedManifest;
+use crate::manifest_writer::{write_manifest, BoardSnapshot};
+use crate::probes::{Level, ProbeSpec, StateOrClass};
+use crate::reactions::ReactionDescription;
+
+/// A manifest written as TOML or JSON instead of in the line-based format.
+/// It covers the same ground as a single-scenario text manifest: settings, a
+/// colormap, transition rules, declarations like invariants, agents, and
+/// probes, and a spelled-out initial state.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StructuredManifest {
+    #[serde(default)]
+    pub settings: BTreeMap<String, SettingValue>,
+    #[serde(default)]
+    pub colormap: Vec<ColorClassEntry>,
+    #[serde(default)]
+    pub rules: Vec<RuleEntry>,
+    pub init_state: Vec<Vec<String>>, // One list of state names per row.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invariants: Vec<BTreeMap<String, i64>>, // Each one's weight for each state, as in { Ant = 1, Carrying = 2 }.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agents: Vec<String>, // States whose cells are tracked as agents.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub agent_rules: Vec<AgentRuleEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probes: Vec<ProbeEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probe_high: Vec<String>, // States, or whole colormap classes written as "{Class}".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probe_low: Vec<String>,
+}
+
+/// A setting's value. TOML and JSON have their own booleans and numbers, so
+/// settings don't all have to be written as strings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SettingValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColorClassEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>, // May be left out for a class with a single state.
+    pub states: Vec<String>,
+    pub color: [u8; 3],
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuleEntry {
+    pub reactants: Vec<String>,
+    pub products: Vec<String>,
+    pub rate: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AgentRuleEntry {
+    pub reactants: Vec<String>,
+    pub products: Vec<String>,
+    #[serde(default)]
+    pub carries: Vec<[usize; 2]>, // (reactant, product) pairs, numbered from 1 as in a text manifest.
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProbeEntry {
+    pub name: String,
+    pub row: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManifestFormat {
+    Text,
+    Toml,
+    Json,
+}
+
+impl ManifestFormat {
+    /// Picks a format from a file's extension; anything unrecognized is text.
+    pub fn from_path(path: &Path) -> ManifestFormat {
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) {
+            Some(ext) if ext == "toml" => ManifestFormat::Toml,
+            Some(ext) if ext == "json" => ManifestFormat::Json,
+            _ => ManifestFormat::Text,
+        }
+    }
+}
+
+impl fmt::Display for SettingValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingValue::Bool(value) => write!(f, "{value}"),
+            SettingValue::Integer(value) => write!(f, "{value}"),
+            SettingValue::Float(value) => write!(f, "{value}"),
+            SettingValue::Text(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+impl SettingValue {
+    /// Reads a value written in a text manifest, keeping numbers and booleans
+    /// typed.
+    fn from_text(value: &str) -> SettingValue {
+        if let Ok(value) = value.parse::<bool>() {
+            SettingValue::Bool(value)
+        } else if let Ok(value) = value.parse::<i64>() {
+            SettingValue::Integer(value)
+        } else if let Ok(value) = value.parse::<f64>() {
+            SettingValue::Float(value)
+        } else {
+            SettingValue::Text(value.to_string())
+        }
+    }
+}
+
+impl StructuredManifest {
+    pub fn from_toml(text: &str) -> Result<StructuredManifest, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    pub fn from_json(text: &str) -> Result<StructuredManifest, String> {
+        serde_json::from_str(text).map_err(|err| err.to_string())
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Turns this into the same ParsedManifest a text manifest would produce,
+    /// so it's built into a simulation the usual way.
+    pub fn to_parsed(&self) -> Result<ParsedManifest, String> {
+        let mut manifest = ParsedManifest {
+            variables: self.settings.iter().map(|(key, value)| (key.clone(), value.to_string())).collect(),
+            ..Default::default()
+        };
+
+        for entry in self.colormap.iter() {
+            let class_name = match (&entry.class, &entry.states[..]) {
+                (Some(class_name), _) => class_name.clone(),
+                (None, [state]) => state.clone(),
+                (None, _) => return Err(format!("colormap entry for {:?} needs a class name", entry.states)),
+            };
+            let [r, g, b] = entry.color;
+            let states: HashSet<String> = entry.states.iter().cloned().collect();
+            manifest.color_classes.push((class_name, Color::RGB(r, g, b), states));
+        }
+
+        for (rule_idx, rule) in self.rules.iter().enumerate() {
+            let reaction = match (&rule.reactants[..], &rule.products[..]) {
+                ([r1], [p1]) => ReactionDescription {
+                    r1: r1.clone(), r2: None, p1: p1.clone(), p2: None, rate: rule.rate as f32
+                },
+                ([r1, r2], [p1, p2]) => ReactionDescription {
+                    r1: r1.clone(), r2: Some(r2.clone()), p1: p1.clone(), p2: Some(p2.clone()), rate: rule.rate as f32
+                },
+                _ => return Err(format!(
+                    "rule {rule_idx} needs one reactant and one product, or two of each"
+                )),
+            };
+            manifest.transition_rules.push(reaction);
+        }
+
+        let n_rows = self.init_state.len();
+        let n_cols = self.init_state.first().map_or(0, |row| row.len());
+        if n_rows == 0 || n_cols == 0 {
+            return Err("the initial state is empty".to_string());
+        }
+        if let Some(row_idx) = self.init_state.iter().position(|row| row.len() != n_cols) {
+            return Err(format!("row {row_idx} of the initial state has a different length than row 0"));
+        }
+        manifest.init_states.push(InitStateSource::Grid(InitGrid {
+            states: self.init_state.concat(),
+            n_rows,
+            n_cols,
+        }));
+
+        for (invariant_idx, weights) in self.invariants.iter().enumerate() {
+            if weights.is_empty() {
+                return Err(format!("invariant {invariant_idx} has no states"));
+            }
+            let terms = weights.iter().map(|(state, weight)| (state.clone(), *weight)).collect();
+            manifest.invariants.push(Invariant { terms });
+        }
+
+        manifest.agent_states = self.agents.clone();
+        for (rule_idx, entry) in self.agent_rules.iter().enumerate() {
+            let rule = match (&entry.reactants[..], &entry.products[..]) {
+                ([r1], [p1]) => ReactionDescription { r1: r1.clone(), r2: None, p1: p1.clone(), p2: None, rate: 0.0 },
+                ([r1, r2], [p1, p2]) => ReactionDescription {
+                    r1: r1.clone(), r2: Some(r2.clone()), p1: p1.clone(), p2: Some(p2.clone()), rate: 0.0
+                },
+                _ => return Err(format!(
+                    "agent rule {rule_idx} needs one reactant and one product, or two of each"
+                )),
+            };
+            let carries = entry.carries.iter().map(|[from, to]| (*from, *to)).collect();
+            let agent_rule = AgentRule::new(rule, carries).map_err(|err| format!("agent rule {rule_idx}: expected {err}"))?;
+            manifest.agent_rules.push(agent_rule);
+        }
+
+        for probe in self.probes.iter() {
+            manifest.probes.push(ProbeSpec { name: probe.name.clone(), row: probe.row, col: probe.col });
+        }
+        for (level, states) in [(Level::High, &self.probe_high), (Level::Low, &self.probe_low)] {
+            for state in states.iter() {
+                let states = match state.strip_prefix('{').and_then(|class| class.strip_suffix('}')) {
+                    Some(class) => StateOrClass::Class(class.to_string()),
+                    None => StateOrClass::State(state.clone()),
+                };
+                manifest.probe_levels.push((level, states));
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Describes a parsed manifest (with its scenarios already applied) in
+    /// structured form. Generated and image initial states are expanded into
+    /// grids.
+    pub fn from_parsed(manifest: &ParsedManifest) -> Result<StructuredManifest, String> {
+        // Later settings lines override earlier ones.
+        let settings = manifest.variables
+            .iter()
+            .map(|(key, value)| (key.clone(), SettingValue::from_text(value)))
+            .collect();
+
+        let colormap = manifest.color_classes
+            .iter()
+            .map(|(class_name, color, states)| {
+                let mut states: Vec<String> = states.iter().cloned().collect();
+                states.sort();
+                let class = (states != [class_name.clone()]).then(|| class_name.clone());
+                ColorClassEntry { class, states, color: [color.r, color.g, color.b] }
+            })
+            .collect();
+
+        let rules = manifest.transition_rules
+            .iter()
+            .map(|rule| RuleEntry {
+                reactants: std::iter::once(&rule.r1).chain(rule.r2.iter()).cloned().collect(),
+                products: std::iter::once(&rule.p1).chain(rule.p2.iter()).cloned().collect(),
+                // Going through the decimal form keeps 0.1 from turning into 0.10000000149011612.
+                rate: rule.rate.to_string().parse().unwrap(),
+            })
+            .collect();
+
+        let grid = match &manifest.init_states[..] {
+            [source] => source.expand(manifest)?,
+            [] => return Err("the manifest has no initial state".to_string()),
+            _ => return Err("the manifest has more than one initial state; pick a scenario".to_string()),
+        };
+        let init_state = grid.states.chunks(grid.n_cols).map(|row| row.to_vec()).collect();
+
+        let invariants = manifest.invariants
+            .iter()
+            .map(|invariant| {
+                let mut weights = BTreeMap::new();
+                for (state, weight) in invariant.terms.iter() {
+                    *weights.entry(state.clone()).or_insert(0) += weight;
+                }
+                weights
+            })
+            .collect();
+
+        let agents = manifest.agent_states.clone();
+        let agent_rules = manifest.agent_rules
+            .iter()
+            .map(|agent_rule| AgentRuleEntry {
+                reactants: std::iter::once(&agent_rule.rule.r1).chain(agent_rule.rule.r2.iter()).cloned().collect(),
+                products: std::iter::once(&agent_rule.rule.p1).chain(agent_rule.rule.p2.iter()).cloned().collect(),
+                carries: agent_rule.carries.iter().map(|(from, to)| [from + 1, to + 1]).collect(),
+            })
+            .collect();
+
+        let probes = manifest.probes
+            .iter()
+            .map(|probe| ProbeEntry { name: probe.name.clone(), row: probe.row, col: probe.col })
+            .collect();
+        let probe_states = |level: Level| manifest.probe_levels
+            .iter()
+            .filter(|(state_level, _)| *state_level == level)
+            .map(|(_, states)| match states {
+                StateOrClass::State(name) => name.clone(),
+                StateOrClass::Class(name) => format!("{{{name}}}"),
+            })
+            .collect();
+        let probe_high = probe_states(Level::High);
+        let probe_low = probe_states(Level::Low);
+
+        Ok(StructuredManifest {
+            settings, colormap, rules, init_state, invariants, agents, agent_rules, probes, probe_high, probe_low
+        })
+    }
+}
+
+/// Writes a parsed manifest (with its scenarios already applied) out in the
+/// given format.
+pub fn format_manifest(manifest: &ParsedManifest, format: ManifestFormat) -> Result<String, String> {
+    match format {
+        ManifestFormat::Text => {
+            let (components, settings, _) = load_from_manifest(manifest, None)?;
+            Ok(write_manifest(&components, &settings, manifest, BoardSnapshot::Current))
+        },
+        ManifestFormat::Toml => StructuredManifest::from_parsed(manifest).map(|structured| structured.to_toml()),
+        ManifestFormat::Json => StructuredManifest::from_parsed(manifest).map(|structured| structured.to_json()),
+    }
+}
+
+/// Runs `chitin convert`: reads a manifest in any format and writes it in the
+/// format matching the output file's extension (.toml, .json, or text).
+pub fn convert_manifest_file(
+    input_file: PathBuf,
+    output_file: PathBuf,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let manifest = match read_manifest_file(input_file.clone(), search_path).and_then(|manifest| manifest.with_scenario(scenario)) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            eprintln!("error: couldn't read {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let result = format_manifest(&manifest, ManifestFormat::from_path(&output_file)).and_then(|text| fs::write(&output_file, text).map_err(|err| err.to_string()));
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("error: couldn't convert {input_file:?} to {output_file:?}: {err}");
+            1
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::input::{load_from_manifest, parse_manifest_file};
+    use crate::input_parsers::settings_input;
+
+    use super::{format_manifest, ManifestFormat, RuleEntry, SettingValue, StructuredManifest};
+
+    const TOML_MANIFEST: &str = r#"
+init_state = [["A", "B"], ["B", "A"]]
+
+[settings]
+speedup_factor = 0.5
+wrap = true
+geometry = "hex"
+
+[[colormap]]
+states = ["A"]
+color = [255, 0, 0]
+
+[[colormap]]
+class = "Others"
+states = ["B", "C"]
+color = [0, 0, 255]
+
+[[rules]]
+reactants = ["A", "B"]
+products = ["B", "C"]
+rate = 2.0
+
+[[rules]]
+reactants = ["C"]
+products = ["A"]
+rate = 0.1
+"#;
+
+    #[test]
+    fn test_toml_manifest() {
+        let structured = StructuredManifest::from_toml(TOML_MANIFEST).unwrap();
+        assert_eq!(structured.settings["wrap"], SettingValue::Bool(true));
+        let (components, settings, _) = load_from_manifest(&structured.to_parsed().unwrap(), None).unwrap();
+        assert!(settings.wrap);
+        assert_eq!(settings.speedup_factor, 0.5);
+        assert_eq!((settings.n_rows, settings.n_cols), (2, 2));
+        assert_eq!(components.all_rxn_rates, vec![2.0, 0.1]);
+        assert_eq!(components.colorclass_names.len(), 2);
+
+        // Same manifest, through JSON.
+        let reparsed = StructuredManifest::from_json(&structured.to_json()).unwrap();
+        assert_eq!(reparsed, structured);
+
+        assert!(StructuredManifest::from_toml("init_state = [[\"A\"], [\"A\", \"B\"]]").unwrap().to_parsed().is_err());
+        assert!(StructuredManifest::from_toml("init_state = [[\"A\"]]\ncolour = 3").is_err());
+    }
+
+    #[test]
+    fn test_text_to_structured() {
+        let manifest = parse_manifest_file(PathBuf::from("test_resources/manifests/scenario_manifest.txt"), &[]);
+        let fast = manifest.with_scenario(Some("fast")).unwrap();
+        let structured = StructuredManifest::from_parsed(&fast).unwrap();
+        let reparsed = StructuredManifest::from_toml(&structured.to_toml()).unwrap();
+        assert_eq!(reparsed, structured);
+
+        let (text_components, text_settings, _) = load_from_manifest(&fast, None).unwrap();
+        let (components, settings, _) = load_from_manifest(&reparsed.to_parsed().unwrap(), None).unwrap();
+        assert_eq!(settings.speedup_factor, text_settings.speedup_factor);
+        assert_eq!(components.all_rxn_rates, text_components.all_rxn_rates);
+        assert_eq!(components.current_states.len(), text_components.current_states.len());
+    }
+
+    #[test]
+    fn test_toml_through_text() {
+        // D isn't in the colormap, so it gets a random color when loaded.
+        let mut structured = StructuredManifest::from_toml(TOML_MANIFEST).unwrap();
+        structured.rules.push(RuleEntry { reactants: vec!["C".to_string()], products: vec!["D".to_string()], rate: 1.0 });
+        let text = format_manifest(&structured.to_parsed().unwrap(), ManifestFormat::Text).unwrap();
+        assert_eq!(format_manifest(&structured.to_parsed().unwrap(), ManifestFormat::Text).unwrap(), text);
+        let back = format_manifest(&settings_input::manifest(&text).unwrap(), ManifestFormat::Toml).unwrap();
+        assert_eq!(StructuredManifest::from_toml(&back).unwrap(), structured);
+    }
+
+    #[test]
+    fn test_declarations_round_trip() {
+        let manifest = settings_input::manifest("\
+!INVARIANT Ant + 2 Carrying
+!START_TRANSITION_RULES
+Ant + Food -> Carrying + Empty (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+Ant Food
+!END_INIT_STATE").unwrap();
+        let structured = StructuredManifest::from_parsed(&manifest).unwrap();
+        let toml = structured.to_toml();
+        let reparsed = StructuredManifest::from_toml(&toml).unwrap();
+        assert_eq!(reparsed, structured);
+        assert_eq!(StructuredManifest::from_json(&structured.to_json()).unwrap(), structured);
+        assert_eq!(reparsed.to_parsed().unwrap().invariants, manifest.invariants);
+    }
+
+    #[test]
+    fn test_agents_round_trip() {
+        let manifest = settings_input::manifest("\
+!AGENTS A, B
+!AGENT_RULE A + B -> B + A: 1->2, 2->1
+!START_TRANSITION_RULES
+A + B -> B + A (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+A B
+!END_INIT_STATE").unwrap();
+        let structured = StructuredManifest::from_parsed(&manifest).unwrap();
+        assert_eq!(structured.agent_rules[0].carries, [[1, 2], [2, 1]]);
+        let reparsed = StructuredManifest::from_toml(&structured.to_toml()).unwrap();
+        assert_eq!(reparsed, structured);
+        assert_eq!(StructuredManifest::from_json(&structured.to_json()).unwrap(), structured);
+        let parsed = reparsed.to_parsed().unwrap();
+        assert_eq!(parsed.agent_states, manifest.agent_states);
+        assert_eq!(parsed.agent_rules[0].carries, manifest.agent_rules[0].carries);
+
+        let mut bad = structured;
+        bad.agent_rules[0].carries = vec![[1, 3]];
+        assert!(bad.to_parsed().is_err());
+    }
+
+    #[test]
+    fn test_probes_round_trip() {
+        let manifest = settings_input::manifest("\
+!PROBE out 0,1
+!PROBE_HIGH On, {Lit}
+!PROBE_LOW Off
+!START_COLORMAP
+{Lit} Glow: (255, 255, 0)
+!END_COLORMAP
+!START_TRANSITION_RULES
+On + Off -> Off + On (1)
+!END_TRANSITION_RULES
+!START_INIT_STATE
+On Off Glow
+!END_INIT_STATE").unwrap();
+        let structured = StructuredManifest::from_parsed(&manifest).unwrap();
+        assert_eq!(structured.probe_high, ["On", "{Lit}"]);
+        let reparsed = StructuredManifest::from_toml(&structured.to_toml()).unwrap();
+        assert_eq!(reparsed, structured);
+        assert_eq!(StructuredManifest::from_json(&structured.to_json()).unwrap(), structured);
+        let parsed = reparsed.to_parsed().unwrap();
+        assert_eq!(parsed.probes, manifest.probes);
+        assert_eq!(parsed.probe_levels, manifest.probe_levels);
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+
+use rand::rngs::StdRng;
+use rand::{random, Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::init_states::{InitCommand, InitStateSource};
+use crate::input::read_manifest_file;
+use crate::input_parsers::{build_simulation, ParsedManifest};
+use crate::monte_carlo::{run_replicate, Condition};
+use crate::settings_schema::{find_setting, parse_settings};
+use crate::structured_manifest::{ManifestFormat, SettingValue};
+
+/// A parameter sweep, written as TOML or JSON: which parameters to vary, over
+/// what values, and how many seeded replicates to run at each point.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SweepSpec {
+    #[serde(default)]
+    pub design: Design,
+    #[serde(default)]
+    pub samples: Option<usize>, // How many points a random design draws.
+    #[serde(default = "one")]
+    pub replicates: usize,
+    #[serde(default)]
+    pub seed: Option<u64>, // Seeds the random design, and replicate seeds count up from it.
+    #[serde(default)]
+    pub time: Option<f32>, // How long each replicate runs; max_duration by default.
+    #[serde(default)]
+    pub until: Option<String>, // Stops a replicate early, as in `chitin estimate --until`.
+    #[serde(rename = "parameter")]
+    pub parameters: Vec<ParameterEntry>,
+}
+
+fn one() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Design {
+    #[default]
+    Grid,   // Every combination of the listed values.
+    Random, // Independent draws from each parameter's values or range.
+}
+
+/// One parameter to vary. `name` is a setting (like `wrap`), a rule's rate
+/// (like `rate(A + B -> B + B)`), or `size`, the rows x columns of a
+/// generated initial state (with values like `"40x60"`).
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParameterEntry {
+    pub name: String,
+    #[serde(default)]
+    pub values: Vec<SettingValue>,
+    #[serde(default)]
+    pub range: Option<[f64; 2]>, // For random designs: draw uniformly between these.
+}
+
+impl SweepSpec {
+    pub fn from_toml(text: &str) -> Result<SweepSpec, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    pub fn from_json(text: &str) -> Result<SweepSpec, String> {
+        serde_json::from_str(text).map_err(|err| err.to_string())
+    }
+
+    /// The parameter values at each point of the design.
+    fn points(&self) -> Result<Vec<Vec<SettingValue>>, String> {
+        for parameter in self.parameters.iter() {
+            if parameter.values.is_empty() && (self.design == Design::Grid || parameter.range.is_none()) {
+                return Err(format!("parameter {:?} needs a list of values", parameter.name));
+            }
+        }
+        match self.design {
+            Design::Grid => Ok(self.parameters.iter().fold(vec![Vec::new()], |points, parameter| {
+                points
+                    .iter()
+                    .flat_map(|point| parameter.values.iter().map(move |value| [&point[..], std::slice::from_ref(value)].concat()))
+                    .collect()
+            })),
+            Design::Random => {
+                let n_samples = self.samples.ok_or("a random design needs a number of samples")?;
+                let mut rng = StdRng::seed_from_u64(self.seed.unwrap_or_else(random));
+                Ok((0..n_samples)
+                    .map(|_| {
+                        self.parameters
+                            .iter()
+                            .map(|parameter| match parameter.range {
+                                Some([low, high]) => SettingValue::Float(rng.gen_range(low..=high)),
+                                None => parameter.values[rng.gen_range(0..parameter.values.len())].clone(),
+                            })
+                            .collect()
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+/// What a sweep (or optimizer) parameter changes in the manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Setting(&'static str),
+    Rate(usize), // Index into the manifest's rules.
+    Size,
+}
+
+/// Reads a rule written like `A + B -> C + D` or `A -> B`.
+fn parse_rule(text: &str) -> Result<(String, Option<String>, String, Option<String>), String> {
+    let (reactants, products) = text.split_once("->").ok_or_else(|| format!("{text:?} isn't a rule"))?;
+    let sides = [reactants, products].map(|side| {
+        let mut states = side.split('+').map(|state| state.trim().trim_matches('"').to_string());
+        (states.next().unwrap_or_default(), states.next(), states.next())
+    });
+    match sides {
+        [(r1, r2, None), (p1, p2, None)] if !r1.is_empty() && !p1.is_empty() && r2.is_some() == p2.is_some() => Ok((r1, r2, p1, p2)),
+        _ => Err(format!("{text:?} isn't a rule")),
+    }
+}
+
+pub fn parameter_target(name: &str, manifest: &ParsedManifest) -> Result<Target, String> {
+    if name == "size" {
+        return match &manifest.init_states[..] {
+            [InitStateSource::Generated(_)] => Ok(Target::Size),
+            _ => Err("only a generated initial state (!START_INIT_GENERATOR or !INIT_RANDOM) can change size".to_string()),
+        };
+    }
+    if let Some(rule) = name.strip_prefix("rate(").and_then(|rest| rest.strip_suffix(')')) {
+        let (r1, r2, p1, p2) = parse_rule(rule)?;
+        return manifest.transition_rules
+            .iter()
+            .position(|existing| existing.r1 == r1 && existing.r2 == r2 && existing.p1 == p1 && existing.p2 == p2)
+            .map(Target::Rate)
+            .ok_or_else(|| format!("there's no rule {rule:?}"));
+    }
+    find_setting(name).map(|spec| Target::Setting(spec.name)).ok_or_else(|| format!("there's no setting named {name:?}"))
+}
+
+/// The manifest with one point's parameter values filled in.
+pub fn apply_point(manifest: &ParsedManifest, targets: &[Target], values: &[SettingValue]) -> Result<ParsedManifest, String> {
+    let mut manifest = manifest.clone();
+    for (target, value) in targets.iter().zip(values) {
+        match target {
+            Target::Setting(name) => {
+                manifest.variables.retain(|(key, _)| find_setting(key).is_none_or(|spec| spec.name != *name));
+                manifest.variables.push((name.to_string(), value.to_string()));
+            },
+            Target::Rate(rule_idx) => {
+                manifest.transition_rules[*rule_idx].rate = match value {
+                    SettingValue::Integer(rate) => *rate as f32,
+                    SettingValue::Float(rate) => *rate as f32,
+                    _ => return Err(format!("{value} isn't a rate")),
+                };
+            },
+            Target::Size => {
+                let size = value.to_string();
+                let (n_rows, n_cols) = size
+                    .split_once('x')
+                    .and_then(|(rows, cols)| Some((rows.trim().parse().ok()?, cols.trim().parse().ok()?)))
+                    .ok_or_else(|| format!("size {size:?} should look like 40x60"))?;
+                if let [InitStateSource::Generated(commands)] = &mut manifest.init_states[..] {
+                    if let Some(InitCommand::Size { .. }) = commands.first() {
+                        commands[0] = InitCommand::Size { n_rows, n_cols };
+                    }
+                }
+            }
+        }
+    }
+    parse_settings(&manifest.variables)?;
+    Ok(manifest)
+}
+
+/// A copy of the manifest with an initial state image read into a grid, so
+/// that it's read once rather than at every point.
+pub fn with_image_read(manifest: &ParsedManifest) -> Result<ParsedManifest, String> {
+    let mut manifest = manifest.clone();
+    if let [source @ InitStateSource::Image(_)] = &manifest.init_states[..] {
+        manifest.init_states = vec![InitStateSource::Grid(source.expand(&manifest)?)];
+    }
+    Ok(manifest)
+}
+
+/// The results of a sweep: one row per replicate, with the parameter values
+/// and summary metrics.
+#[derive(Debug)]
+pub struct SweepTable {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Runs every point of a sweep on a manifest (with its scenario already
+/// applied). The manifest is parsed once; each point works on a copy of the
+/// parsed manifest and builds its simulation from that.
+pub fn run_sweep(manifest: &ParsedManifest, spec: &SweepSpec) -> Result<SweepTable, String> {
+    let manifest = with_image_read(manifest)?;
+    let targets = spec.parameters
+        .iter()
+        .map(|parameter| parameter_target(&parameter.name, &manifest))
+        .collect::<Result<Vec<Target>, String>>()?;
+    let base_seed = spec.seed.unwrap_or_else(random);
+
+    let mut runs = Vec::new();
+    let mut state_names: BTreeSet<String> = BTreeSet::new();
+    let points = spec.points()?;
+    for (point_idx, values) in points.iter().enumerate() {
+        let point = apply_point(&manifest, &targets, values)?;
+        let (components, settings) = build_simulation(&point)?;
+        let condition = spec.until
+            .as_deref()
+            .map(|until| Condition::parse(until, &components, &settings))
+            .transpose()?;
+        let time_bound = spec.time.unwrap_or(settings.max_duration);
+        for replicate in 0..spec.replicates {
+            let seed = base_seed.wrapping_add((point_idx * spec.replicates + replicate) as u64);
+            let outcome = run_replicate(&components, &settings, seed, time_bound, |_, board, counts| {
+                condition.as_ref().is_some_and(|condition| condition.holds(board, counts))
+            });
+            let counts: Vec<(String, usize)> = components.state_names
+                .iter()
+                .map(|(state, name)| (name.clone(), outcome.counts[*state]))
+                .collect();
+            state_names.extend(counts.iter().map(|(name, _)| name.clone()));
+            runs.push((point_idx, replicate, seed, values, outcome, counts));
+        }
+    }
+
+    let mut header: Vec<String> = ["point", "replicate", "seed"].map(String::from).to_vec();
+    header.extend(spec.parameters.iter().map(|parameter| parameter.name.clone()));
+    header.extend(["t_end", "events"].map(String::from));
+    if spec.until.is_some() {
+        header.extend(["reached", "first_passage"].map(String::from));
+    }
+    header.extend(state_names.iter().map(|name| format!("count({name})")));
+    let rows = runs
+        .into_iter()
+        .map(|(point_idx, replicate, seed, values, outcome, counts)| {
+            let mut row = vec![point_idx.to_string(), replicate.to_string(), seed.to_string()];
+            row.extend(values.iter().map(|value| value.to_string()));
+            row.extend([outcome.t_end.to_string(), outcome.n_events.to_string()]);
+            if spec.until.is_some() {
+                row.push(outcome.reached.is_some().to_string());
+                row.push(outcome.reached.map(|t| t.to_string()).unwrap_or_default());
+            }
+            row.extend(state_names.iter().map(|name| {
+                counts.iter().find(|(state, _)| state == name).map_or(0, |(_, count)| *count).to_string()
+            }));
+            row
+        })
+        .collect();
+    Ok(SweepTable { header, rows })
+}
+
+/// Writes the table as CSV, quoting any field with a comma or quote in it.
+pub fn format_csv(table: &SweepTable) -> String {
+    let field = |value: &String| match value.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.clone(),
+    };
+    std::iter::once(&table.header)
+        .chain(table.rows.iter())
+        .map(|row| row.iter().map(field).collect::<Vec<String>>().join(",") + "\n")
+        .collect()
+}
+
+/// Runs `chitin sweep`: runs a manifest over every point of a sweep and
+/// writes one row per replicate to a CSV file (or stdout).
+pub fn sweep_manifest_file(
+    input_file: PathBuf,
+    sweep_file: PathBuf,
+    output_file: Option<PathBuf>,
+    scenario: Option<&str>,
+    search_path: &[PathBuf]
+) -> i32 {
+    let spec = fs::read_to_string(&sweep_file)
+        .map_err(|err| err.to_string())
+        .and_then(|text| match ManifestFormat::from_path(&sweep_file) {
+            ManifestFormat::Toml => SweepSpec::from_toml(&text),
+            ManifestFormat::Json => SweepSpec::from_json(&text),
+            ManifestFormat::Text => Err("sweeps have to be .toml or .json files".to_string()),
+        });
+    let spec = match spec {
+        Ok(spec) => spec,
+        Err(err) => {
+            eprintln!("error: couldn't read {sweep_file:?}: {err}");
+            return 2;
+        }
+    };
+    let table = read_manifest_file(input_file.clone(), search_path)
+        .and_then(|manifest| manifest.with_scenario(scenario))
+        .and_then(|manifest| run_sweep(&manifest, &spec));
+    let table = match table {
+        Ok(table) => table,
+        Err(err) => {
+            eprintln!("error: couldn't sweep {input_file:?}: {err}");
+            return 1;
+        }
+    };
+    let text = format_csv(&table);
+    match output_file {
+        Some(path) => match fs::write(&path, text) {
+            Ok(()) => {
+                println!("Wrote {} run(s) to {path:?}.", table.rows.len());
+                0
+            },
+            Err(err) => {
+                eprintln!("Couldn't write {path:?}: {err}");
+                1
+            }
+        },
+        None => {
+            print!("{text}");
+            0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::input_parsers::settings_input;
+    use crate::manifest_lexer::normalize_manifest_text;
+
+    use super::{format_csv, run_sweep, SweepSpec};
+
+    // Walkers that decay, on a generated board.
+    const MANIFEST: &str = "\
+max_duration = 5
+!START_TRANSITION_RULES
+A + E -> E + A (1)
+A -> E (0.5)
+!END_TRANSITION_RULES
+!START_INIT_GENERATOR
+size 2 3
+fill E
+rect A 0 0 1 1
+!END_INIT_GENERATOR";
+
+    #[test]
+    fn test_grid_sweep() {
+        let manifest = settings_input::manifest(&normalize_manifest_text(MANIFEST).unwrap()).unwrap();
+        let spec = SweepSpec::from_toml(r#"
+replicates = 2
+seed = 10
+until = "count(A)==0"
+
+[[parameter]]
+name = "rate(A -> E)"
+values = [0, 100]
+
+[[parameter]]
+name = "wrap"
+values = [true, false]
+
+[[parameter]]
+name = "size"
+values = ["2x3", "4x4"]
+"#).unwrap();
+        let table = run_sweep(&manifest, &spec).unwrap();
+        assert_eq!(table.rows.len(), 2 * 2 * 2 * 2);
+        assert_eq!(
+            table.header,
+            ["point", "replicate", "seed", "rate(A -> E)", "wrap", "size", "t_end", "events", "reached", "first_passage", "count(A)", "count(E)"]
+        );
+        assert_eq!(table.rows[0][..6], ["0", "0", "10", "0", "true", "2x3"]);
+        assert_eq!(table.rows[15][..6], ["7", "1", "25", "100", "false", "4x4"]);
+        for row in table.rows.iter() {
+            // Without decay the walker never disappears; quick decay almost
+            // certainly happens before t=5.
+            let reached = &row[8];
+            assert_eq!(reached == "true", row[3] == "100", "{row:?}");
+            let n_cells: usize = row[10..].iter().map(|count| count.parse::<usize>().unwrap()).sum();
+            assert_eq!(n_cells, if row[5] == "2x3" { 6 } else { 16 });
+        }
+        assert!(format_csv(&table).starts_with("point,replicate,seed,rate(A -> E),wrap,size,"));
+    }
+
+    #[test]
+    fn test_random_sweep() {
+        let manifest = settings_input::manifest(&normalize_manifest_text(MANIFEST).unwrap()).unwrap();
+        let spec = SweepSpec::from_toml(r#"
+design = "random"
+samples = 5
+seed = 3
+
+[[parameter]]
+name = "rate(A + E -> E + A)"
+range = [0.5, 2.0]
+"#).unwrap();
+        let table = run_sweep(&manifest, &spec).unwrap();
+        assert_eq!(table.rows.len(), 5);
+        for row in table.rows.iter() {
+            let rate: f64 = row[3].parse().unwrap();
+            assert!((0.5..=2.0).contains(&rate));
+        }
+
+        for bad in ["name = \"rate(A -> B)\"\nvalues = [1]", "name = \"colour\"\nvalues = [1]", "name = \"wrap\"\nvalues = [\"sideways\"]"] {
+            let spec = SweepSpec::from_toml(&format!("[[parameter]]\n{bad}")).unwrap();
+            assert!(run_sweep(&manifest, &spec).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs;
+use std::path::Path;
+
+use crate::reactions::ReactionEvent;
+use crate::simulator::square_neighbors;
+use crate::state::{Settings, SimulatorComponents};
+
+/// Describes one of a simulation's rules by state name, like `A + B -> C + D`.
+pub fn describe_reaction(components: &SimulatorComponents, rxn_idx: usize) -> String {
+    let rxn = &components.all_reactions[rxn_idx];
+    let name = |state: usize| &components.state_names[&state];
+    match (rxn.r2_num, rxn.p2_num) {
+        (Some(r2), Some(p2)) => format!("{} + {} -> {} + {}", name(rxn.r1_num), name(r2), name(rxn.p1_num), name(p2)),
+        _ => format!("{} -> {}", name(rxn.r1_num), name(rxn.p1_num)),
+    }
+}
+
+/// Writes events as a trace file, one event per line: its time, the index of
+/// the rule that fired, and the row,column of each cell it changed, with the
+/// rule spelled out in a comment. For example, `2 0 1,3 1,4 # A + B -> B + A`.
+pub fn format_trace(events: &[ReactionEvent], components: &SimulatorComponents, settings: &Settings) -> String {
+    let cell = |loc: usize| format!("{},{}", loc / settings.n_cols, loc % settings.n_cols);
+    let mut trace = String::new();
+    for event in events {
+        let mut line = format!("{} {} {}", event.t, event.rxn_idx, cell(event.r1_loc));
+        if let Some(r2_loc) = event.r2_loc {
+            line.push_str(&format!(" {}", cell(r2_loc)));
+        }
+        trace.push_str(&format!("{line} # {}\n", describe_reaction(components, event.rxn_idx)));
+    }
+    trace
+}
+
+/// Reads a trace written by format_trace, checking that each event can
+/// actually fire on the board left by the ones before it, starting from the
+/// board on screen. Blank lines and `#` comments are ignored.
+pub fn parse_trace(text: &str, components: &SimulatorComponents, settings: &Settings) -> Result<Vec<ReactionEvent>, String> {
+    let mut board = components.current_states.clone();
+    let mut events: Vec<ReactionEvent> = Vec::new();
+    for (line_idx, line) in text.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let fields: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let event = parse_event(&fields, events.last(), components, settings)
+            .map_err(|err| format!("line {line_number}: {err}"))?;
+        let rxn = &components.all_reactions[event.rxn_idx];
+        if board[event.r1_loc] != rxn.r1_num || event.r2_loc.map(|loc| board[loc]) != rxn.r2_num {
+            return Err(format!(
+                "line {line_number}: rule {} ({}) can't fire there",
+                event.rxn_idx, describe_reaction(components, event.rxn_idx)
+            ));
+        }
+        board[event.r1_loc] = rxn.p1_num;
+        if let (Some(r2_loc), Some(p2)) = (event.r2_loc, rxn.p2_num) {
+            board[r2_loc] = p2;
+        }
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn parse_event(
+    fields: &[&str],
+    previous: Option<&ReactionEvent>,
+    components: &SimulatorComponents,
+    settings: &Settings
+) -> Result<ReactionEvent, String> {
+    let (t, rxn_idx, cells) = match fields {
+        [t, rxn_idx, cells @ ..] if (1..=2).contains(&cells.len()) => (t, rxn_idx, cells),
+        _ => return Err("expected a time, a rule index, and one or two cells".to_string()),
+    };
+    let t: f32 = t.parse().map_err(|_| format!("{t:?} isn't a time"))?;
+    let t_issued = previous.map_or(0.0, |event| event.t);
+    if t < t_issued {
+        return Err(format!("time {t} is before the previous event's"));
+    }
+    let rxn_idx: usize = rxn_idx.parse().map_err(|_| format!("{rxn_idx:?} isn't a rule index"))?;
+    let rxn = components.all_reactions.get(rxn_idx).ok_or_else(|| format!("there's no rule {rxn_idx}"))?;
+    if cells.len() != 1 + rxn.r2_num.is_some() as usize {
+        return Err(format!("rule {rxn_idx} takes {} cell(s)", 1 + rxn.r2_num.is_some() as usize));
+    }
+    let mut locs = cells.iter().map(|cell| parse_cell(cell, settings));
+    let r1_loc = locs.next().unwrap()?;
+    let r2_loc = locs.next().transpose()?;
+    if let Some(r2_loc) = r2_loc {
+        if !square_neighbors(r1_loc, settings.n_cols, settings.n_rows, settings.wrap).contains(&r2_loc) {
+            return Err(format!("cells {} and {} aren't neighbors", cells[0], cells[1]));
+        }
+    }
+    Ok(ReactionEvent { r1_loc, r2_loc, rxn_idx, t, t_issued })
+}
+
+/// Reads a `row,column` cell position into a board index.
+pub fn parse_cell(cell: &str, settings: &Settings) -> Result<usize, String> {
+    let (row, col) = cell
+        .split_once(',')
+        .and_then(|(row, col)| Some((row.trim().parse::<usize>().ok()?, col.trim().parse::<usize>().ok()?)))
+        .ok_or_else(|| format!("{cell:?} isn't a row,column position"))?;
+    if row >= settings.n_rows || col >= settings.n_cols {
+        return Err(format!("{cell} is off the {}x{} board", settings.n_rows, settings.n_cols));
+    }
+    Ok(row * settings.n_cols + col)
+}
+
+/// Loads a trace file as the simulation's whole history, so the playbar steps
+/// through exactly those events. The board after the last event becomes the
+/// latest state; the caller shouldn't start the reaction queue, or random
+/// events would follow the trace.
+pub fn load_trace(path: &Path, components: &mut SimulatorComponents, settings: &Settings) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("couldn't open {}: {err}", path.display()))?;
+    let events = parse_trace(&text, components, settings).map_err(|err| format!("{}: {err}", path.display()))?;
+    for event in events.iter() {
+        let rxn = components.all_reactions[event.rxn_idx];
+        components.latest_states[event.r1_loc] = rxn.p1_num;
+        components.state_timestamps[event.r1_loc] = event.t;
+        if let (Some(r2_loc), Some(p2)) = (event.r2_loc, rxn.p2_num) {
+            components.latest_states[r2_loc] = p2;
+            components.state_timestamps[r2_loc] = event.t;
+        }
+    }
+    components.reaction_history = events;
+    Ok(())
+}